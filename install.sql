@@ -184,3 +184,13 @@ ALTER TABLE "public"."session" ADD CONSTRAINT "session_user_id_fkey" FOREIGN KEY
 ALTER TABLE "public"."user" ADD CONSTRAINT "user_lang_id_fkey" FOREIGN KEY ("lang_id") REFERENCES "public"."lang" ("lang_id") ON DELETE NO ACTION ON UPDATE NO ACTION;
 ALTER TABLE "public"."user" ADD CONSTRAINT "user_role_id_fkey" FOREIGN KEY ("role_id") REFERENCES "public"."role" ("role_id") ON DELETE NO ACTION ON UPDATE NO ACTION;
 
+
+CREATE FUNCTION "public"."tiny_reload"() RETURNS trigger LANGUAGE plpgsql AS $$
+BEGIN
+  PERFORM pg_notify('tiny_reload', TG_TABLE_NAME);
+  RETURN NULL;
+END;
+$$;
+CREATE TRIGGER "controller_tiny_reload" AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON "public"."controller" FOR EACH STATEMENT EXECUTE FUNCTION "public"."tiny_reload"();
+CREATE TRIGGER "redirect_tiny_reload" AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON "public"."redirect" FOR EACH STATEMENT EXECUTE FUNCTION "public"."tiny_reload"();
+CREATE TRIGGER "route_tiny_reload" AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON "public"."route" FOR EACH STATEMENT EXECUTE FUNCTION "public"."tiny_reload"();
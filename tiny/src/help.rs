@@ -5,11 +5,18 @@ impl Help {
         let desc = "Tiny is a high-speed FastCGI server for WEB applications.";
         let ver = format!("tiny version: {}", env!("CARGO_PKG_VERSION"));
         let help = "
-    Usage: tiny [start|stop|help] [-r <path to root path>]
+    Usage: tiny [start|stop|restart|status|reload|reload-routes|cache-clear|log-level <level>|workers <n>|help] [-r <path to root path>]
     
     Actions:
         start         : start server
         stop          : stop server
+        restart       : start the new server on the same sockets, the old one finishes the open requests
+        status        : show pid, uptime, busy and idle workers, queue length
+        reload        : load templates, translations, routes and redirects again
+        reload-routes : load routes and redirects from the database again
+        cache-clear   : clear the cache, routes and redirects are loaded again
+        log-level     : save messages from this level: info, warning or stop
+        workers       : change max_workers of the running server
         help          : show this help
         
     ";
@@ -9,12 +9,23 @@ pub mod work {
     pub mod worker;
     pub mod html;
     pub mod lang;
+    pub mod asset;
+    pub mod watch;
+    pub mod param;
 }
 pub mod sys {
     pub mod log; 
     pub mod init;
     pub mod go;
     pub mod fastcgi;
+    pub mod http;
+    pub mod socket;
+    pub mod accept;
+    pub mod restart;
+    pub mod rpc;
+    pub mod signal;
+    #[cfg(feature = "async")]
+    pub mod runtime;
     pub mod app;
 }
 pub mod help;
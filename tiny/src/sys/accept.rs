@@ -0,0 +1,182 @@
+use std::{net::IpAddr, str::FromStr};
+
+// One rule of the access list: "ip", "ip/prefix" or the deny rule "!ip/prefix"
+#[derive(Debug, Clone)]
+struct Rule {
+    deny: bool,                 // The rule refuses the matching address
+    ip: IpAddr,                 // Network address
+    prefix: u8,                 // Network prefix length
+    any: bool,                  // Unspecified address without prefix, matches everything
+    text: String,               // The rule as it is written in the config file
+}
+
+// List of the allowed client addresses and networks
+#[derive(Debug, Clone)]
+pub struct Accept {
+    rules: Vec<Rule>,
+}
+
+impl Accept {
+
+    pub fn new(list: &[&str]) -> Result<Accept, String> {
+        let mut rules = Vec::with_capacity(list.len());
+        for text in list {
+            rules.push(Accept::parse(text)?);
+        }
+        Ok(Accept { rules })
+    }
+
+    fn parse(text: &str) -> Result<Rule, String> {
+        let (deny, rule) = match text.trim().strip_prefix('!') {
+            Some(r) => (true, r.trim()),
+            None => (false, text.trim()),
+        };
+        let (ip, prefix) = match rule.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (rule, None),
+        };
+        let ip = match IpAddr::from_str(ip) {
+            Ok(ip) => ip,
+            Err(e) => return Err(format!("{}. Error: {}", text, e)),
+        };
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => match p.parse::<u8>() {
+                Ok(p) if p <= max => p,
+                _ => return Err(format!("{}. Error: wrong prefix length", text)),
+            },
+            None => max,
+        };
+        Ok(Rule {
+            deny,
+            ip,
+            prefix,
+            any: ip.is_unspecified() && prefix == max,
+            text: text.to_owned(),
+        })
+    }
+
+    // Check the client address. The deny rules are checked first, then the address must match any allow rule.
+    // Err contains the rule that refused the address.
+    pub fn check(&self, ip: IpAddr) -> Result<(), String> {
+        let ip = match ip {
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => ip,
+            },
+            ip => ip,
+        };
+        if let Some(rule) = self.rules.iter().find(|r| r.deny && Accept::matches(r, ip)) {
+            return Err(format!("refused by rule \"{}\"", rule.text));
+        }
+        let list: Vec<&Rule> = self.rules.iter().filter(|r| !r.deny).collect();
+        if list.is_empty() || list.iter().any(|r| Accept::matches(r, ip)) {
+            return Ok(());
+        }
+        let list: Vec<&str> = list.iter().map(|r| r.text.as_str()).collect();
+        Err(format!("not in the list \"{}\"", list.join(", ")))
+    }
+
+    // The list allows any address, so the check can be skipped
+    pub fn is_any(&self) -> bool {
+        self.rules.is_empty() || (self.rules.iter().all(|r| !r.deny) && self.rules.iter().any(|r| r.any))
+    }
+
+    fn matches(rule: &Rule, ip: IpAddr) -> bool {
+        if rule.any {
+            return true;
+        }
+        match (rule.ip, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = if rule.prefix == 0 { 0 } else { u32::MAX << (32 - rule.prefix) };
+                u32::from(net) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = if rule.prefix == 0 { 0 } else { u128::MAX << (128 - rule.prefix) };
+                u128::from(net) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, str::FromStr};
+
+    use super::Accept;
+
+    fn check(list: &[&str], ip: &str) -> bool {
+        Accept::new(list).unwrap().check(IpAddr::from_str(ip).unwrap()).is_ok()
+    }
+
+    #[test]
+    fn parse() {
+        assert!(Accept::new(&["127.0.0.1", "10.0.0.0/8", "!10.1.0.0/16", "::1", "fd00::/8", " 192.168.0.0/24 "]).is_ok());
+        assert!(Accept::new(&["10.0.0.0/33"]).is_err());
+        assert!(Accept::new(&["fd00::/129"]).is_err());
+        assert!(Accept::new(&["10.0.0.0/"]).is_err());
+        assert!(Accept::new(&["10.0.0.0/-1"]).is_err());
+        assert!(Accept::new(&["localhost"]).is_err());
+        assert!(Accept::new(&["10.0.0.256"]).is_err());
+    }
+
+    #[test]
+    fn cidr() {
+        let list = ["192.168.1.0/24"];
+        assert!(check(&list, "192.168.1.0"));
+        assert!(check(&list, "192.168.1.255"));
+        assert!(!check(&list, "192.168.2.0"));
+        assert!(!check(&list, "192.168.0.255"));
+        // The host bits of the rule are ignored
+        assert!(check(&["192.168.1.77/24"], "192.168.1.1"));
+        assert!(check(&["10.0.0.1/32"], "10.0.0.1"));
+        assert!(!check(&["10.0.0.1/32"], "10.0.0.2"));
+        assert!(check(&["10.0.0.1"], "10.0.0.1"));
+        assert!(!check(&["10.0.0.1"], "10.0.0.2"));
+        assert!(check(&["0.0.0.0/0"], "203.0.113.9"));
+        assert!(!check(&["0.0.0.0/0"], "2001:db8::1"));
+        assert!(check(&["128.0.0.0/1"], "255.255.255.255"));
+        assert!(!check(&["128.0.0.0/1"], "127.255.255.255"));
+        assert!(check(&["2001:db8::/32"], "2001:db8:ffff::1"));
+        assert!(!check(&["2001:db8::/32"], "2001:db9::1"));
+        assert!(check(&["::/0"], "::1"));
+        assert!(check(&["::1/128"], "::1"));
+    }
+
+    #[test]
+    fn mapped() {
+        // IPv4 client on the dual stack socket
+        assert!(check(&["127.0.0.1"], "::ffff:127.0.0.1"));
+        assert!(check(&["10.0.0.0/8"], "::ffff:10.2.3.4"));
+        assert!(!check(&["::1"], "::ffff:127.0.0.1"));
+    }
+
+    #[test]
+    fn deny() {
+        let list = ["10.0.0.0/8", "!10.1.0.0/16"];
+        assert!(check(&list, "10.2.0.1"));
+        assert!(!check(&list, "10.1.0.1"));
+        assert!(!check(&list, "11.0.0.1"));
+        // Only the deny rules, so the rest is allowed
+        assert!(check(&["!10.1.0.0/16"], "10.2.0.1"));
+        assert!(!check(&["!10.1.0.0/16"], "10.1.2.3"));
+        // The deny rule wins over the allow rule
+        assert!(!check(&["!0.0.0.0/0", "127.0.0.1"], "127.0.0.1"));
+        let err = Accept::new(&list).unwrap().check(IpAddr::from_str("10.1.0.1").unwrap()).unwrap_err();
+        assert_eq!(err, "refused by rule \"!10.1.0.0/16\"");
+        let err = Accept::new(&list).unwrap().check(IpAddr::from_str("11.0.0.1").unwrap()).unwrap_err();
+        assert_eq!(err, "not in the list \"10.0.0.0/8\"");
+    }
+
+    #[test]
+    fn any() {
+        assert!(Accept::new(&[]).unwrap().is_any());
+        assert!(Accept::new(&["0.0.0.0"]).unwrap().is_any());
+        assert!(Accept::new(&["::", "127.0.0.1"]).unwrap().is_any());
+        assert!(!Accept::new(&["0.0.0.0/0"]).unwrap().is_any());
+        assert!(!Accept::new(&["0.0.0.0", "!10.0.0.0/8"]).unwrap().is_any());
+        assert!(check(&["0.0.0.0"], "2001:db8::1"));
+        assert!(check(&[], "2001:db8::1"));
+    }
+}
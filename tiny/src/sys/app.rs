@@ -6,7 +6,7 @@ use std::time::Duration;
 
 use crate::help::Help;
 
-use super::{log::{Log}, init::{Init, Mode}, go::Go};
+use super::{log::{Log}, init::{Init, Mode}, go::Go, restart::RESTART_READY_TIMEOUT, rpc::Handshake};
 
 #[derive(Debug)]
 pub struct App {
@@ -34,48 +34,107 @@ impl App {
         match app.init.mode {
             Mode::Start => App::start(app),
             Mode::Stop => App::stop(app),
+            Mode::Restart => App::restart(app),
             Mode::Help => Help::show(),
             Mode::Go => Go::run(app),
+            Mode::Status | Mode::Reload | Mode::ReloadRoutes | Mode::CacheClear | Mode::LogLevel | Mode::Workers => App::command(app),
         };
     }
 
     fn stop(app: App) {
+        let answer = match App::rpc(&app, "stop") {
+            Some(a) => a,
+            None => return,
+        };
+        match answer.split_once(' ') {
+            Some(("stop", pid)) => Log::push_info(app.log, 215, Some(format!("Answer PID={}", pid))),
+            _ => Log::push_stop(app.log, 219, Some(answer)),
+        };
+    }
+
+    fn restart(app: App) {
+        let answer = match App::rpc(&app, "restart") {
+            Some(a) => a,
+            None => return,
+        };
+        match answer.split_once(' ') {
+            Some(("restart", pid)) => Log::push_info(app.log, 221, Some(format!("Answer PID={}", pid))),
+            Some(("error", e)) => Log::push_stop(app.log, 222, Some(e.to_owned())),
+            _ => Log::push_stop(app.log, 219, Some(answer)),
+        };
+    }
+
+    // Send the control command and print the answer of the server
+    fn command(app: App) {
+        let command = match &app.init.value {
+            Some(v) => format!("{} {}", app.init.mode.command(), v),
+            None => app.init.mode.command().to_owned(),
+        };
+        let answer = match App::rpc(&app, &command) {
+            Some(a) => a,
+            None => return,
+        };
+        match answer.strip_prefix("error ") {
+            Some(e) => {
+                eprintln!("{}", e);
+                Log::push_stop(app.log, 224, Some(e.to_owned()));
+            },
+            None => println!("{}", answer),
+        };
+    }
+
+    // Send the command to the running server and read its answer
+    fn rpc(app: &App, command: &str) -> Option<String> {
         let mut tcp = match TcpStream::connect_timeout(&SocketAddr::new(app.init.conf.rpc_ip, app.init.conf.rpc_port), Duration::from_secs(1)) {
             Ok(t) => t,
             Err(e) => {
-                Log::push_stop(app.log, 213, Some(e.to_string()));
-                return;
+                Log::push_stop(Arc::clone(&app.log), 213, Some(e.to_string()));
+                return None;
             },
         };
-        if let Err(e) = tcp.write_all(format!("stop {}", app.init.conf.salt).as_bytes()) {
-            Log::push_stop(app.log, 214, Some(e.to_string()));
-            return;
+        // The server sends the nonce first, the command is signed with it, so the salt isn't sent
+        if let Err(e) = tcp.set_read_timeout(Some(Duration::from_secs(3))) {
+            Log::push_stop(Arc::clone(&app.log), 217, Some(e.to_string()));
+            return None;
         };
-        if let Err(e) = tcp.set_read_timeout(Some(Duration::from_secs(30))) {
-            Log::push_stop(app.log, 217, Some(e.to_string()));
-            return;
+        let nonce = match Handshake::read_line(&tcp).ok().and_then(|n| String::from_utf8(n).ok()) {
+            Some(n) => n,
+            None => {
+                Log::push_stop(Arc::clone(&app.log), 230, None);
+                return None;
+            },
         };
-
-        let mut buf: [u8; 1024] = [0; 1024];
-        let s = match tcp.read(&mut buf) {
-            Ok(s) => s,
-            Err(e) => {
-                Log::push_stop(app.log, 218, Some(e.to_string()));
-                return;
+        let signature = match Handshake::sign(&app.init.conf.salt, &nonce, command) {
+            Some(s) => s,
+            None => {
+                Log::push_stop(Arc::clone(&app.log), 214, None);
+                return None;
             },
         };
-        if s < 6 {
-            Log::push_stop(app.log, 219, Some(format!("{:?}", &buf[..s])));
-            return;
-        }
-        let pid = match String::from_utf8((&buf[5..s]).to_vec()) {
-            Ok(i) => i,
+        if let Err(e) = tcp.write_all(format!("{} {}\n", command, signature).as_bytes()) {
+            Log::push_stop(Arc::clone(&app.log), 214, Some(e.to_string()));
+            return None;
+        };
+        // The server answers after the open connections are drained or the new process is ready
+        let timeout = app.init.conf.drain_timeout.max(RESTART_READY_TIMEOUT.as_secs()) + 30;
+        if let Err(e) = tcp.set_read_timeout(Some(Duration::from_secs(timeout))) {
+            Log::push_stop(Arc::clone(&app.log), 217, Some(e.to_string()));
+            return None;
+        };
+
+        // The server closes the connection after the answer
+        let mut buf = Vec::with_capacity(1024);
+        if let Err(e) = tcp.read_to_end(&mut buf) {
+            Log::push_stop(Arc::clone(&app.log), 218, Some(e.to_string()));
+            return None;
+        };
+        match String::from_utf8(buf) {
+            Ok(i) => Some(i),
             Err(e) => {
-                Log::push_stop(app.log, 220, Some(format!("{:?} {}", &buf[5..s], e.to_string())));
-                return;
+                Log::push_stop(Arc::clone(&app.log), 220, Some(format!("{:?} {}", e.as_bytes(), e)));
+                None
             },
-        };
-        Log::push_info(app.log, 215, Some(format!("Answer PID={}", pid)));
+        }
     }
 
     #[cfg(target_family="windows")]
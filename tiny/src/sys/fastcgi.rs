@@ -1,18 +1,28 @@
-use std::{net::TcpStream, time::Duration, io::{ErrorKind, Read, Write}, collections::HashMap, rc::Rc, sync::{Arc, Mutex}};
+use std::{net::Shutdown, time::Duration, io::{ErrorKind, Read, Write}, collections::{HashMap, HashSet}, sync::{Arc, Mutex, mpsc::{self, Sender, RecvTimeoutError}, atomic::{AtomicBool, Ordering}}, thread::{self, JoinHandle}};
 
-use crate::{sys::log::Log, work::action::DataRun};
+use crate::{sys::{log::Log, socket::Socket, go::{Go, DRAIN_CHECK_INTERVAL}}, work::{action::{DataRun, Body, Action, Handler}, watch::Output}};
 
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum Status {
   None,               // Nothing or Init
   Begin,              // Receive a "Begin" request
   Param,              // Receive a "Param" request
   ParamEnd,           // Receive a empty "Param" request
   Stdin,              // Receive a "Stdin" request
+  Rejected,           // The answer has been sent before the end of "Stdin", the rest of "Stdin" is skipped
 }
 
-// FastCGI header
+// State of the one request on the multiplexed connection
 #[derive(Debug)]
+pub struct RequestState {
+    pub begin: Record,                      // "Begin" record
+    pub param: HashMap<String, String>,     // Params
+    pub stdin: Body,                        // Stdin
+    pub status: Status,                     // Status of the request
+}
+
+// FastCGI header
+#[derive(Debug, Clone)]
 pub struct Header
 {
     pub version: u8,
@@ -24,7 +34,7 @@ pub struct Header
 }
 
 // FastCGI header type
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum HeaderType {
     BeginRequest,
     AbortRequest,
@@ -68,7 +78,7 @@ pub enum ContentData {
     ErrorStream,
     Break,
     Unknown(Unknown),
-    Raw(Rc<Vec<u8>>, usize, u16),
+    Raw(Arc<Vec<u8>>, usize, u16),
     End(End),
 }
 
@@ -139,95 +149,437 @@ pub const FASTCGI_RESPONDER: u16 = 1;
 pub const FASTCGI_AUTHORIZER: u16 = 2;
 pub const FASTCGI_FILTER: u16 = 3;
 
+pub const FASTCGI_KEEP_CONN: u8 = 1;
+
 pub const FASTCGI_REQUEST_COMPLETE: u8 = 0;
+pub const FASTCGI_OVERLOADED: u8 = 2;
+// Requests, which one connection may have at once. FCGI_GET_VALUES reports it as FCGI_MAX_REQS.
+pub const FASTCGI_MAX_REQS: usize = 64;
+// appStatus of the request, which has been aborted before its answer was complete
+pub const FASTCGI_ABORT_STATUS: u32 = 1;
+
+// What to do after the record is handled
+pub enum Next {
+    Continue,                   // Wait for the next record
+    Run(RequestState),          // The request is received, so the controller is run
+    Close,                      // Close the connection
+}
+
+// Writer of the FCGI_STDOUT records of the one request
+pub struct Stream<'a> {
+    pub tcp: &'a mut dyn Write,
+    pub request_id: u16,
+}
+
+impl<'a> Write for Stream<'a> {
+    // Send data as one FCGI_STDOUT record
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // An empty record closes the stream, so it is never sent here
+        let len = buf.len().min(FASTCGI_MAX_CONTENT_LEN as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        let record = Record {
+            header: Header {
+                version: FASTCGI_VERSION,
+                header_type: HeaderType::Stdout,
+                request_id: self.request_id,
+                content_length: len as u16,
+                padding_length: 0,
+                reserved: 0,
+            },
+            data: ContentData::None,
+        };
+        let mut data = FastCGI::record_array(record);
+        data.extend_from_slice(&buf[..len]);
+        self.tcp.write_all(&data[..])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.tcp.flush()
+    }
+}
+
+// The request, which the controller runs now, and the aborted requests, which wait for the controller.
+// The reader thread gets FCGI_ABORT_REQUEST, while the request may be still in the queue of the connection.
+#[derive(Default)]
+pub struct Active {
+    running: Option<(u16, Arc<AtomicBool>)>,    // request_id and the abort flag of the controller
+    aborted: HashSet<u16>,                      // FCGI_ABORT_REQUEST has come before the controller started
+}
+
+impl Active {
+
+    // The controller starts the request. Returns false, when the request has already been aborted.
+    pub fn start(active: &Mutex<Active>, request_id: u16, abort: Arc<AtomicBool>, log: Arc<Mutex<Log>>) -> bool {
+        match Mutex::lock(active) {
+            Ok(mut a) => {
+                if a.aborted.remove(&request_id) {
+                    return false;
+                }
+                a.running = Some((request_id, abort));
+                true
+            },
+            Err(e) => Log::error(log, e.to_string()),
+        }
+    }
+
+    pub fn finish(active: &Mutex<Active>, log: Arc<Mutex<Log>>) {
+        match Mutex::lock(active) {
+            Ok(mut a) => a.running = None,
+            Err(e) => Log::error(log, e.to_string()),
+        };
+    }
+
+    // The new request reuses the request_id, so the old abort doesn't concern it
+    pub fn begin(active: &Mutex<Active>, request_id: u16, log: Arc<Mutex<Log>>) {
+        match Mutex::lock(active) {
+            Ok(mut a) => {
+                a.aborted.remove(&request_id);
+            },
+            Err(e) => Log::error(log, e.to_string()),
+        };
+    }
+
+    // FCGI_ABORT_REQUEST stops the running controller or is kept until the request starts
+    pub fn abort(active: &Mutex<Active>, request_id: u16, log: Arc<Mutex<Log>>) {
+        match Mutex::lock(active) {
+            Ok(mut a) => match &a.running {
+                Some((id, abort)) if *id == request_id => abort.store(true, Ordering::Release),
+                _ => {
+                    a.aborted.insert(request_id);
+                },
+            },
+            Err(e) => Log::error(log, e.to_string()),
+        };
+    }
+
+    // The web server has closed the connection
+    pub fn close(active: &Mutex<Active>, log: Arc<Mutex<Log>>) {
+        match Mutex::lock(active) {
+            Ok(a) => if let Some((_, abort)) = &a.running {
+                abort.store(true, Ordering::Release);
+            },
+            Err(e) => Log::error(log, e.to_string()),
+        };
+    }
+
+    // Track the records, which the reader thread has got
+    pub fn record(active: &Mutex<Active>, header: &Header, log: Arc<Mutex<Log>>) {
+        match header.header_type {
+            HeaderType::BeginRequest => Active::begin(active, header.request_id, log),
+            HeaderType::AbortRequest => Active::abort(active, header.request_id, log),
+            _ => {},
+        }
+    }
+}
 
 // Wrapper of FastCGI server
 pub struct FastCGI { }
 
 impl FastCGI {
 
-    pub fn run(f: &dyn Fn(HashMap<String, String>, Option<Vec<u8>>, DataRun, Arc<Mutex<Log>>) -> Vec<u8>, mut tcp: TcpStream, data: DataRun, log: Arc<Mutex<Log>>) {
-        let mut begin_record: Option<Record> = None;
-        let mut param_record: HashMap<String, String> = HashMap::with_capacity(128);
-        let mut stdin_record: Option<Vec<u8>> = None;
-
-        let mut buffer: [u8; FASTCGI_MAX_REQUEST_LEN] = [0; FASTCGI_MAX_REQUEST_LEN];
-        let mut seek: usize = 0;
-        let mut size: usize = 0;
-        let mut need_read = true;
+    pub fn run(f: &Handler, tcp: Socket, mut data: DataRun, log: Arc<Mutex<Log>>) {
+        let mut requests: HashMap<u16, RequestState> = HashMap::new();
+        let mut close = false;
+        let active = Arc::new(Mutex::new(Active::default()));
 
-        let mut status = Status::None;
+        // The controller, the reader thread and the watchdog write to the same connection
+        let mut output = match tcp.try_clone() {
+            Ok(stream) => Output::new(stream),
+            Err(e) => {
+                Log::push_warning(log, 513, Some(e.to_string()));
+                return;
+            },
+        };
+        let (sender, receiver) = mpsc::channel();
+        let reader = match tcp.try_clone() {
+            Ok(stream) => FastCGI::reader(stream, output.clone(), sender, Arc::clone(&active), Arc::clone(&log)),
+            Err(e) => {
+                Log::push_warning(log, 513, Some(e.to_string()));
+                return;
+            },
+        };
 
         loop {
-            let record = match FastCGI::read_record(&mut seek, &mut size, &mut need_read, &mut buffer[..], &mut tcp) {
-                RecordType::None => continue,
-                RecordType::Some(record) => record,
-                RecordType::ErrorStream | RecordType::StreamClosed => break,
+            let received = if close && requests.is_empty() {
+                // No active requests are left, only the records, which have come already, may begin the new ones
+                receiver.try_recv().map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                receiver.recv_timeout(DRAIN_CHECK_INTERVAL)
             };
-            match record.header.header_type {
-                HeaderType::BeginRequest => {
-                    begin_record = Some(record);
-                    if Status::None != status {
-                        break;
-                    }
-                    status = Status::Begin;
-                },
-                HeaderType::AbortRequest => {
-                    if let Some(record) = begin_record {
-                        FastCGI::write_abort(&record.header, &mut tcp);
-                    }
+            // On stop the idle connection is closed, the web server opens the new one to the new process
+            let record = match received {
+                Ok(r) => r,
+                Err(RecvTimeoutError::Timeout) => if Go::is_draining() && requests.is_empty() {
                     break;
+                } else {
+                    continue;
                 },
-                HeaderType::Params => {
-                    match status {
-                        Status::Begin | Status::Param => {},
-                        _ => break,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let request = match FastCGI::next(record, &mut requests, &mut close, data.max_body, data.spool_body, &mut output, Arc::clone(&log)) {
+                Next::Continue => continue,
+                Next::Close => break,
+                Next::Run(request) => request,
+            };
+            let request_id = request.begin.header.request_id;
+            if !Active::start(&active, request_id, Arc::clone(&data.abort), Arc::clone(&log)) {
+                // The web server has aborted the request before the controller started
+                FastCGI::write_abort(&request.begin.header, &mut output);
+                FastCGI::done(&request.begin, &mut close);
+                continue;
+            }
+            let (number, mut controller) = output.start();
+            if data.watch.is_enabled() {
+                // The watchdog ends only this request with 504, when the deadline has passed
+                let watchdog = output.clone();
+                let header = request.begin.header.clone();
+                data.watch.start(Box::new(move || {
+                    watchdog.expire(number, |socket, written| {
+                        // The started answer can't be replaced, it is just ended
+                        if written {
+                            FastCGI::write_abort(&header, socket);
+                        } else {
+                            FastCGI::write_status(&header, 504, socket);
+                        }
+                    });
+                }), data.db.cancel_token(), Arc::clone(&data.abort));
+            }
+            let mut stream = Stream { tcp: &mut controller, request_id };
+            let answer = f(request.param, request.stdin, &mut data, &mut stream, Arc::clone(&log));
+            Active::finish(&active, Arc::clone(&log));
+            if data.watch.finish() {
+                // 504 has been sent instead of the answer. The replaced worker doesn't take the next requests.
+                data.abort.store(false, Ordering::Release);
+                FastCGI::done(&request.begin, &mut close);
+                if data.watch.is_abandoned() {
+                    break;
+                }
+            } else if !FastCGI::finish(&request.begin, answer, data.abort.swap(false, Ordering::AcqRel), &mut close, &mut output) {
+                break;
+            }
+            if Go::is_draining() && requests.is_empty() {
+                break;
+            }
+        }
+        // Stop the reader thread
+        drop(receiver);
+        let _ = tcp.shutdown(Shutdown::Both);
+        if reader.join().is_err() {
+            Log::push_warning(log, 514, None);
+        }
+    }
+
+    // Handle one record of the connection
+    pub fn next(record: Record, requests: &mut HashMap<u16, RequestState>, close: &mut bool, max_body: usize, spool_body: usize, tcp: &mut dyn Write, log: Arc<Mutex<Log>>) -> Next {
+        let request_id = record.header.request_id;
+        match record.header.header_type {
+            HeaderType::BeginRequest => {
+                if requests.contains_key(&request_id) {
+                    return Next::Close;
+                }
+                // The records of the refused request are ignored, as its request_id is inactive
+                if requests.len() >= FASTCGI_MAX_REQS {
+                    if tcp.write_all(&FastCGI::end_array(&record.header, 0, FASTCGI_OVERLOADED)[..]).is_err() {
+                        return Next::Close;
                     }
-                    match record.data {
-                        ContentData::Param(data) => {
-                            if param_record.len() == 0 {
-                                param_record = data;
-                            } else {
-                                for (key, value) in data {
-                                    param_record.insert(key, value);
+                    return Next::Continue;
+                }
+                requests.insert(request_id, RequestState {
+                    begin: record,
+                    param: HashMap::with_capacity(128),
+                    stdin: Body::None,
+                    status: Status::Begin,
+                });
+            },
+            HeaderType::AbortRequest => {
+                // Records with an inactive request_id are ignored
+                if let Some(request) = requests.remove(&request_id) {
+                    FastCGI::write_abort(&request.begin.header, tcp);
+                    FastCGI::done(&request.begin, close);
+                }
+            },
+            HeaderType::Params => {
+                let request = match requests.get_mut(&request_id) {
+                    Some(r) => r,
+                    None => return Next::Continue,
+                };
+                match request.status {
+                    Status::Begin | Status::Param => {},
+                    _ => return Next::Close,
+                }
+                match record.data {
+                    ContentData::Param(data) => {
+                        if request.param.is_empty() {
+                            request.param = data;
+                        } else {
+                            for (key, value) in data {
+                                request.param.insert(key, value);
+                            }
+                        } 
+                        request.status = Status::Param
+                    },
+                    ContentData::None => {
+                        request.status = Status::ParamEnd;
+                        // Reject the oversized body before it is received
+                        if max_body > 0 {
+                            if let Some(len) = request.param.get("CONTENT_LENGTH") {
+                                if let Ok(len) = len.parse::<usize>() {
+                                    if len > max_body && !FastCGI::reject(request, 413, tcp) {
+                                        return Next::Close;
+                                    }
                                 }
-                            } 
-                            status = Status::Param
-                        },
-                        ContentData::None => status = Status::ParamEnd,
-                        _ => break,
-                    }
-                },
-                HeaderType::Stdin => {
-                    match status {
-                        Status::Begin | Status::ParamEnd | Status::Stdin => {},
-                        _ => break,
-                    }
-                    match record.data {
-                        ContentData::Stream(data) => {
-                            match stdin_record.as_mut() {
-                                Some(param) => param.extend_from_slice(&data[..]),
-                                None => stdin_record = Some(data),
-                            };
-                        },
-                        ContentData::None => {
-                            let answer = f(param_record, stdin_record, data, Arc::clone(&log));
-
-                            if let Some(record) = begin_record {
-                                FastCGI::write_response(&record.header, answer, &mut tcp);
                             }
-                            break;
-                        },
-                        _ => break,
-                    }
+                        }
+                    },
+                    _ => return Next::Close,
+                }
+            },
+            HeaderType::Stdin => {
+                let request = match requests.get_mut(&request_id) {
+                    Some(r) => r,
+                    None => return Next::Continue,
+                };
+                match request.status {
+                    Status::Begin | Status::ParamEnd | Status::Stdin | Status::Rejected => {},
+                    _ => return Next::Close,
+                }
+                match record.data {
+                    ContentData::Stream(stdin) => {
+                        if let Status::Rejected = request.status {
+                            return Next::Continue;
+                        }
+                        if max_body > 0 && request.stdin.len() + stdin.len() > max_body {
+                            if !FastCGI::reject(request, 413, tcp) {
+                                return Next::Close;
+                            }
+                            return Next::Continue;
+                        }
+                        if !request.stdin.append(&stdin, spool_body) {
+                            Log::push_warning(log, 515, None);
+                            if !FastCGI::reject(request, 500, tcp) {
+                                return Next::Close;
+                            }
+                            return Next::Continue;
+                        }
+                        request.status = Status::Stdin;
+                    },
+                    ContentData::None => {
+                        let request = match requests.remove(&request_id) {
+                            Some(r) => r,
+                            None => return Next::Close,
+                        };
+                        if let Status::Rejected = request.status {
+                            FastCGI::done(&request.begin, close);
+                            return Next::Continue;
+                        }
+                        return Next::Run(request);
+                    },
+                    _ => return Next::Close,
+                }
+            },
+            _ => {},
+        };
+        Next::Continue
+    }
+
+    // Send the answer of the controller. Returns false, when the connection must be closed.
+    pub fn finish(begin: &Record, answer: Vec<u8>, aborted: bool, close: &mut bool, tcp: &mut dyn Write) -> bool {
+        FastCGI::done(begin, close);
+        if aborted {
+            // The client has gone, so the answer is not needed
+            FastCGI::write_abort(&begin.header, tcp);
+            true
+        } else {
+            FastCGI::write_response(&begin.header, answer, tcp)
+        }
+    }
+
+    // Answer 503 to the first request of the connection, when there is no free worker for it
+    pub fn overload(mut tcp: Socket, retry: u64, log: Arc<Mutex<Log>>) {
+        if let Err(e) = tcp.set_read_timeout(Some(Duration::from_secs(1))) {
+            Log::push_warning(log, 505, Some(e.to_string()));
+            return;
+        }
+        let mut buffer: [u8; FASTCGI_MAX_REQUEST_LEN] = [0; FASTCGI_MAX_REQUEST_LEN];
+        let mut seek: usize = 0;
+        let mut size: usize = 0;
+        let mut need_read = true;
+        loop {
+            let mut answer = Vec::new();
+            let record = FastCGI::read_record(&mut seek, &mut size, &mut need_read, &mut buffer[..], &mut tcp, &mut answer);
+            if !answer.is_empty() && tcp.write_all(&answer).is_err() {
+                break;
+            }
+            match record {
+                RecordType::None => continue,
+                RecordType::Some(record) => if let HeaderType::BeginRequest = record.header.header_type {
+                    FastCGI::write_overload(&record.header, retry, &mut tcp);
+                    break;
                 },
-                _ => {},
+                RecordType::ErrorStream | RecordType::StreamClosed => break,
             };
         }
+        let _ = tcp.shutdown(Shutdown::Both);
+    }
+
+    // Read FastCGI records in the separate thread, so FCGI_ABORT_REQUEST is noticed while the controller is running.
+    // Answers of the low-level requests, like FCGI_GET_VALUES, go through the output of the connection.
+    fn reader(mut stream: Socket, mut output: Output<Socket>, sender: Sender<Record>, active: Arc<Mutex<Active>>, log: Arc<Mutex<Log>>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let mut buffer: [u8; FASTCGI_MAX_REQUEST_LEN] = [0; FASTCGI_MAX_REQUEST_LEN];
+            let mut seek: usize = 0;
+            let mut size: usize = 0;
+            let mut need_read = true;
+
+            loop {
+                let mut answer = Vec::new();
+                let record = FastCGI::read_record(&mut seek, &mut size, &mut need_read, &mut buffer[..], &mut stream, &mut answer);
+                if !answer.is_empty() && output.write_all(&answer).is_err() {
+                    break;
+                }
+                let record = match record {
+                    RecordType::None => continue,
+                    RecordType::Some(record) => record,
+                    RecordType::ErrorStream | RecordType::StreamClosed => break,
+                };
+                Active::record(&active, &record.header, Arc::clone(&log));
+                if sender.send(record).is_err() {
+                    return;
+                }
+            }
+            // The web server has closed the connection
+            Active::close(&active, log);
+        })
+    }
+
+    // Answer at once with the HTTP status only and skip the rest of the request
+    fn reject(request: &mut RequestState, code: u16, tcp: &mut dyn Write) -> bool {
+        request.status = Status::Rejected;
+        request.stdin = Body::None;
+        FastCGI::write_status(&request.begin.header, code, tcp)
+    }
+
+    // Check FCGI_KEEP_CONN flag in the "begin" record
+    pub fn keep_conn(record: &Record) -> bool {
+        match &record.data {
+            ContentData::BeginRequest(begin) => begin.flags & FASTCGI_KEEP_CONN == FASTCGI_KEEP_CONN,
+            _ => false,
+        }
+    }
+
+    // The finished request without FCGI_KEEP_CONN closes the connection,
+    // but only after the other active requests, so the connection is closed, when none are left.
+    pub fn done(begin: &Record, close: &mut bool) {
+        if !FastCGI::keep_conn(begin) {
+            *close = true;
+        }
     }
 
-    // Read FastCGI records
-    pub fn read_record(seek: &mut usize, size: &mut usize, need_read: &mut bool, buffer: &mut[u8], stream: &mut TcpStream) -> RecordType {
+    // Read FastCGI records. The answers of the low-level requests are added to answer.
+    pub fn read_record(seek: &mut usize, size: &mut usize, need_read: &mut bool, buffer: &mut[u8], stream: &mut Socket, answer: &mut Vec<u8>) -> RecordType {
         loop{
             if *need_read {
                 // Checks indicator to read from the stream buffer
@@ -251,7 +603,7 @@ impl FastCGI {
                         *size += n;
                         *need_read = false;
                         // Read one record
-                        match FastCGI::read(seek, size, buffer, stream) {
+                        match FastCGI::read(seek, size, buffer, answer) {
                             ReadStatus::Continue => {
                                 *need_read = true;
                                 continue;
@@ -273,7 +625,7 @@ impl FastCGI {
                     },
                 };
             } else {
-                match FastCGI::read(seek, size, buffer, stream) {
+                match FastCGI::read(seek, size, buffer, answer) {
                     ReadStatus::Continue => {
                         *need_read = true;
                         continue;
@@ -289,7 +641,7 @@ impl FastCGI {
     }
 
     // Decode one FastCGI record
-    fn read(seek: &mut usize, size: &mut usize, buffer: &mut[u8], stream: &mut TcpStream) -> ReadStatus {
+    pub fn read(seek: &mut usize, size: &mut usize, buffer: &mut[u8], stream: &mut dyn Write) -> ReadStatus {
         if *size - *seek < FASTCGI_HEADER_LEN {
             return ReadStatus::Continue;
         }
@@ -364,31 +716,33 @@ impl FastCGI {
     }
 
     // Answer GetValues low-level request
-    fn read_write_value(request_id: u16, stream: &mut TcpStream) -> ContentData {
+    fn read_write_value(request_id: u16, stream: &mut dyn Write) -> ContentData {
         if request_id > 0 {
             return ContentData::None;
         }
-        let mut params: HashMap<String, String> = HashMap::with_capacity(3);
-        params.insert("FCGI_MAX_CONNS".to_owned(), "1".to_owned());
-        params.insert("FASTCGI_MAX_REQS".to_owned(), "1".to_owned());
-        params.insert("FASTCGI_MAX_CONNS".to_owned(), "1".to_owned());
+        // The number of connections isn't limited by the application, so FCGI_MAX_CONNS isn't reported
+        let mut params: HashMap<String, String> = HashMap::with_capacity(2);
+        params.insert("FCGI_MAX_REQS".to_owned(), FASTCGI_MAX_REQS.to_string());
+        params.insert("FCGI_MPXS_CONNS".to_owned(), "1".to_owned());
 
-        let len = match u16::try_from(params.len()) {
-            Ok(u) => u,
-            Err(_) => return ContentData::ErrorStream,
-        };
         let record = Record {
             header: Header {
                 version: FASTCGI_VERSION,
                 header_type: HeaderType::GetValuesResult,
                 request_id,
-                content_length: len,
+                content_length: 0,
                 padding_length: 0,
                 reserved: 0,
             },
             data: ContentData::Param(params),
         };
-        let data = FastCGI::record_array(record);
+        let mut data = FastCGI::record_array(record);
+        // The length of the encoded name-value pairs is known only after encoding
+        let len = match u16::try_from(data.len() - FASTCGI_HEADER_LEN) {
+            Ok(u) => u,
+            Err(_) => return ContentData::ErrorStream,
+        };
+        data[4..6].copy_from_slice(&u16::to_be_bytes(len));
         if let Err(_) = stream.write_all(&data[..]) {
             return ContentData::ErrorStream;
         }
@@ -396,7 +750,7 @@ impl FastCGI {
     }
 
     // Answer unknown command
-    fn write_unknown(unknown: u8, request_id: u16, stream: &mut TcpStream) -> ContentData {
+    fn write_unknown(unknown: u8, request_id: u16, stream: &mut dyn Write) -> ContentData {
         let record = Record {
             header: Header {
                 version: FASTCGI_VERSION,
@@ -557,12 +911,12 @@ impl FastCGI {
     }
 
     // Answer to the WEB server
-    pub fn write_response(header: &Header, answer: Vec<u8>, stream: &mut TcpStream) {
+    pub fn write_response(header: &Header, answer: Vec<u8>, stream: &mut dyn Write) -> bool {
         let mut data: Vec<u8> = Vec::new();
         let len = answer.len();
         let mut size: u16;
         let mut seek: usize = 0;
-        let pack = Rc::new(answer);
+        let pack = Arc::new(answer);
         // Split data to parts
         while seek < len {
             if seek + (FASTCGI_MAX_CONTENT_LEN as usize) < len {
@@ -570,7 +924,7 @@ impl FastCGI {
             } else {
                 size = match u16::try_from(len - seek) {
                     Ok(u) => u,
-                    Err(_) => return,
+                    Err(_) => return false,
                 };
             };
             let record = Record {
@@ -582,7 +936,7 @@ impl FastCGI {
                     padding_length: 0,
                     reserved: 0,
                 },
-                data: ContentData::Raw(Rc::clone(&pack), seek, size),
+                data: ContentData::Raw(Arc::clone(&pack), seek, size),
             };
             data.extend_from_slice(&FastCGI::record_array(record)[..]);
             seek += size as usize;
@@ -615,13 +969,41 @@ impl FastCGI {
             }),
         };
         data.extend_from_slice(&FastCGI::record_array(record)[..]);
-        if let Err(_) = stream.write_all(&data[..]) {
-            return; 
-        }
+        stream.write_all(&data[..]).is_ok()
+    }
+
+    // Answer with the HTTP status only, without running the controller
+    pub fn write_status(header: &Header, code: u16, stream: &mut dyn Write) -> bool {
+        let answer = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", Action::http_code_get(code));
+        FastCGI::write_response(header, answer.into_bytes(), stream)
     }
 
-    // Write abore request
-    pub fn write_abort(header: &Header, stream: &mut TcpStream) {
+    // Answer 503 with Retry-After, when there is no free worker
+    pub fn write_overload(header: &Header, retry: u64, stream: &mut dyn Write) -> bool {
+        let answer = format!("HTTP/1.1 {}\r\nRetry-After: {}\r\nContent-Length: 0\r\n\r\n", Action::http_code_get(503), retry);
+        FastCGI::write_response(header, answer.into_bytes(), stream)
+    }
+
+    // End the aborted request. The empty FCGI_STDOUT closes the stream, which may be started already.
+    pub fn write_abort(header: &Header, stream: &mut dyn Write) {
+        let record = Record {
+            header: Header {
+                version: FASTCGI_VERSION,
+                header_type: HeaderType::Stdout,
+                request_id: header.request_id,
+                content_length: 0,
+                padding_length: 0,
+                reserved: 0,
+            },
+            data: ContentData::None,
+        };
+        let mut data = FastCGI::record_array(record);
+        data.extend_from_slice(&FastCGI::end_array(header, FASTCGI_ABORT_STATUS, FASTCGI_REQUEST_COMPLETE)[..]);
+        let _ = stream.write_all(&data[..]);
+    }
+
+    // FCGI_END_REQUEST with the appStatus and the protocolStatus
+    fn end_array(header: &Header, code: u32, protocol: u8) -> Vec<u8> {
         let record = Record {
             header: Header {
                 version: FASTCGI_VERSION,
@@ -632,15 +1014,12 @@ impl FastCGI {
                 reserved: 0,
             },
             data: ContentData::End(End{
-                code: 0,
-                protocol: FASTCGI_REQUEST_COMPLETE,
+                code,
+                protocol,
                 reserved: [0; 3],
             }),
         };
-        let data = FastCGI::record_array(record);
-        if let Err(_) = stream.write_all(&data[..]) {
-            return; 
-        }
+        FastCGI::record_array(record)
     }
 
     // Prepare record for writing 
@@ -704,4 +1083,402 @@ impl FastCGI {
         }
         data
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, sync::Arc};
+
+    use crate::sys::log::Log;
+
+    use super::*;
+
+    fn record(header_type: u8, request_id: u16, content: &[u8]) -> Vec<u8> {
+        let mut raw = vec![FASTCGI_VERSION, header_type];
+        raw.extend_from_slice(&request_id.to_be_bytes());
+        raw.extend_from_slice(&(content.len() as u16).to_be_bytes());
+        raw.extend_from_slice(&[0, 0]);
+        raw.extend_from_slice(content);
+        raw
+    }
+
+    fn begin(request_id: u16, keep: bool) -> Vec<u8> {
+        let flags = if keep { FASTCGI_KEEP_CONN } else { 0 };
+        record(FASTCGI_BEGIN_REQUEST, request_id, &[0, FASTCGI_RESPONDER as u8, flags, 0, 0, 0, 0, 0])
+    }
+
+    // Name-value pairs shorter than 128 bytes, then the empty record
+    fn params(request_id: u16, list: &[(&str, &str)]) -> Vec<u8> {
+        let mut content = Vec::new();
+        for (key, value) in list {
+            content.push(key.len() as u8);
+            content.push(value.len() as u8);
+            content.extend_from_slice(key.as_bytes());
+            content.extend_from_slice(value.as_bytes());
+        }
+        let mut raw = record(FASTCGI_PARAMS, request_id, &content);
+        raw.extend_from_slice(&record(FASTCGI_PARAMS, request_id, &[]));
+        raw
+    }
+
+    fn stdin(request_id: u16, body: &[u8]) -> Vec<u8> {
+        let mut raw = Vec::new();
+        for chunk in body.chunks(FASTCGI_MAX_CONTENT_LEN as usize) {
+            raw.extend_from_slice(&record(FASTCGI_STDIN, request_id, chunk));
+        }
+        raw.extend_from_slice(&record(FASTCGI_STDIN, request_id, &[]));
+        raw
+    }
+
+    fn request(request_id: u16, keep: bool, uri: &str) -> Vec<u8> {
+        let mut raw = begin(request_id, keep);
+        raw.extend_from_slice(&params(request_id, &[("REQUEST_URI", uri)]));
+        raw.extend_from_slice(&stdin(request_id, b""));
+        raw
+    }
+
+    // Records as the reader of the connection decodes them, with the answers of the low-level requests
+    fn read(raw: &[u8]) -> (Vec<Record>, Vec<u8>) {
+        let mut buffer = raw.to_vec();
+        let mut size = buffer.len();
+        let mut seek = 0;
+        let mut records = Vec::new();
+        let mut answer = Vec::new();
+        loop {
+            let start = seek;
+            match FastCGI::read(&mut seek, &mut size, &mut buffer, &mut answer) {
+                ReadStatus::Result(record) => records.push(record),
+                ReadStatus::Break if seek == start => break,
+                ReadStatus::Break | ReadStatus::Next => {},
+                ReadStatus::Continue | ReadStatus::ErrorStream => break,
+            };
+        }
+        (records, answer)
+    }
+
+    // Records written to the web server: type, request_id and content
+    fn answer(out: &[u8]) -> Vec<(u8, u16, Vec<u8>)> {
+        let mut list = Vec::new();
+        let mut seek = 0;
+        while seek < out.len() {
+            let len = usize::from(u16::from_be_bytes([out[seek + 4], out[seek + 5]]));
+            let start = seek + FASTCGI_HEADER_LEN;
+            list.push((out[seek + 1], u16::from_be_bytes([out[seek + 2], out[seek + 3]]), out[start..start + len].to_vec()));
+            seek = start + len + usize::from(out[seek + 6]);
+        }
+        list
+    }
+
+    struct Served {
+        run: Vec<RequestState>,         // Requests passed to the controller
+        closed: bool,                   // The connection is closed after the records
+        out: Vec<(u8, u16, Vec<u8>)>,
+        log: String,
+    }
+
+    // The records are handled as FastCGI::run does it, the controller answers "ok"
+    fn serve(raw: &[u8], max_body: usize, spool_body: usize) -> Served {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Log::new();
+        Log::set_path(Arc::clone(&log), dir.path().join("tiny.log").display().to_string());
+        let (records, _) = read(raw);
+        let mut requests = HashMap::new();
+        let mut close = false;
+        let mut run = Vec::new();
+        let mut out = Vec::new();
+        let mut closed = false;
+        for record in records {
+            match FastCGI::next(record, &mut requests, &mut close, max_body, spool_body, &mut out, Arc::clone(&log)) {
+                Next::Continue => {},
+                Next::Close => {
+                    closed = true;
+                    break;
+                },
+                Next::Run(request) => {
+                    assert!(FastCGI::finish(&request.begin, b"ok".to_vec(), false, &mut close, &mut out));
+                    run.push(request);
+                },
+            };
+        }
+        Log::stop(log);
+        Served {
+            run,
+            closed: closed || (close && requests.is_empty()),
+            out: answer(&out),
+            log: fs::read_to_string(dir.path().join("tiny.log")).unwrap_or_default(),
+        }
+    }
+
+    fn end(request_id: u16, code: u32, protocol: u8) -> (u8, u16, Vec<u8>) {
+        let mut content = code.to_be_bytes().to_vec();
+        content.extend_from_slice(&[protocol, 0, 0, 0]);
+        (FASTCGI_END_REQUEST, request_id, content)
+    }
+
+    fn stdout(request_id: u16, content: &[u8]) -> (u8, u16, Vec<u8>) {
+        (FASTCGI_STDOUT, request_id, content.to_vec())
+    }
+
+    #[test]
+    fn keep_conn_reuse() {
+        let mut raw = request(1, true, "/first");
+        raw.extend_from_slice(&request(1, true, "/second"));
+        let served = serve(&raw, 0, 0);
+        assert_eq!(served.run.len(), 2);
+        assert_eq!(served.run[0].param["REQUEST_URI"], "/first");
+        assert_eq!(served.run[1].param["REQUEST_URI"], "/second");
+        assert!(!served.closed);
+        assert_eq!(served.out, vec![
+            stdout(1, b"ok"), stdout(1, b""), end(1, 0, FASTCGI_REQUEST_COMPLETE),
+            stdout(1, b"ok"), stdout(1, b""), end(1, 0, FASTCGI_REQUEST_COMPLETE),
+        ]);
+        assert!(served.log.is_empty());
+    }
+
+    #[test]
+    fn keep_conn_close() {
+        let served = serve(&request(1, false, "/"), 0, 0);
+        assert_eq!(served.run.len(), 1);
+        assert!(served.closed);
+        // The second "begin" of the active request breaks the stream
+        let mut raw = begin(1, true);
+        raw.extend_from_slice(&begin(1, true));
+        let served = serve(&raw, 0, 0);
+        assert!(served.run.is_empty());
+        assert!(served.closed);
+    }
+
+    #[test]
+    fn interleaved_requests() {
+        let mut raw = begin(1, true);
+        raw.extend_from_slice(&begin(2, true));
+        raw.extend_from_slice(&params(2, &[("REQUEST_URI", "/two")]));
+        raw.extend_from_slice(&params(1, &[("REQUEST_URI", "/one")]));
+        raw.extend_from_slice(&record(FASTCGI_STDIN, 1, b"body 1"));
+        raw.extend_from_slice(&record(FASTCGI_STDIN, 2, b"body 2"));
+        raw.extend_from_slice(&record(FASTCGI_STDIN, 2, b""));
+        raw.extend_from_slice(&record(FASTCGI_STDIN, 1, b""));
+        let served = serve(&raw, 0, 0);
+        assert_eq!(served.run.len(), 2);
+        assert_eq!(served.run[0].begin.header.request_id, 2);
+        assert_eq!(served.run[0].param["REQUEST_URI"], "/two");
+        assert_eq!(served.run[0].stdin.read().unwrap().as_ref(), b"body 2");
+        assert_eq!(served.run[1].begin.header.request_id, 1);
+        assert_eq!(served.run[1].param["REQUEST_URI"], "/one");
+        assert_eq!(served.run[1].stdin.read().unwrap().as_ref(), b"body 1");
+        assert!(!served.closed);
+        // Each answer has the request_id of its request
+        assert_eq!(served.out, vec![
+            stdout(2, b"ok"), stdout(2, b""), end(2, 0, FASTCGI_REQUEST_COMPLETE),
+            stdout(1, b"ok"), stdout(1, b""), end(1, 0, FASTCGI_REQUEST_COMPLETE),
+        ]);
+    }
+
+    #[test]
+    fn close_after_last_request() {
+        // The request without FCGI_KEEP_CONN is finished first, the connection waits for the other one
+        let mut raw = begin(1, false);
+        raw.extend_from_slice(&begin(2, true));
+        raw.extend_from_slice(&params(1, &[("REQUEST_URI", "/one")]));
+        raw.extend_from_slice(&stdin(1, b""));
+        raw.extend_from_slice(&params(2, &[("REQUEST_URI", "/two")]));
+        let served = serve(&raw, 0, 0);
+        assert_eq!(served.run.len(), 1);
+        assert!(!served.closed);
+
+        raw.extend_from_slice(&stdin(2, b""));
+        let served = serve(&raw, 0, 0);
+        assert_eq!(served.run.len(), 2);
+        assert!(served.closed);
+        assert_eq!(served.out.last(), Some(&end(2, 0, FASTCGI_REQUEST_COMPLETE)));
+    }
+
+    #[test]
+    fn max_requests() {
+        let mut raw = Vec::new();
+        for request_id in 1..=FASTCGI_MAX_REQS as u16 {
+            raw.extend_from_slice(&begin(request_id, true));
+        }
+        // The request over the limit is refused, its records are ignored
+        raw.extend_from_slice(&request(100, true, "/"));
+        raw.extend_from_slice(&params(1, &[("REQUEST_URI", "/one")]));
+        raw.extend_from_slice(&stdin(1, b""));
+        let served = serve(&raw, 0, 0);
+        assert_eq!(served.run.len(), 1);
+        assert_eq!(served.run[0].begin.header.request_id, 1);
+        assert!(!served.closed);
+        assert_eq!(served.out[0], end(100, 0, FASTCGI_OVERLOADED));
+        assert_eq!(served.out.len(), 4);
+    }
+
+    #[test]
+    fn get_values() {
+        let (records, written) = read(&record(FASTCGI_GET_VALUES, 0, b"\x0d\x00FCGI_MAX_REQS\x0f\x00FCGI_MPXS_CONNS"));
+        assert!(records.is_empty());
+        let mut out = answer(&written);
+        assert_eq!(out.len(), 1);
+        assert_eq!((out[0].0, out[0].1), (FASTCGI_GET_VALUES_RESULT, 0));
+        let values = match FastCGI::read_param(&mut out[0].2) {
+            ContentData::Param(values) => values,
+            _ => panic!("the values are not decoded"),
+        };
+        assert_eq!(values.len(), 2);
+        assert_eq!(values["FCGI_MAX_REQS"], FASTCGI_MAX_REQS.to_string());
+        assert_eq!(values["FCGI_MPXS_CONNS"], "1");
+    }
+
+    #[test]
+    fn abort_request() {
+        // The abort comes before the body is received, the rest of the request is ignored
+        let mut raw = begin(1, true);
+        raw.extend_from_slice(&params(1, &[("REQUEST_URI", "/aborted")]));
+        raw.extend_from_slice(&record(FASTCGI_ABORT_REQUEST, 1, b""));
+        raw.extend_from_slice(&stdin(1, b"late body"));
+        raw.extend_from_slice(&record(FASTCGI_ABORT_REQUEST, 7, b""));
+        raw.extend_from_slice(&request(1, true, "/next"));
+        let served = serve(&raw, 0, 0);
+        assert_eq!(served.run.len(), 1);
+        assert_eq!(served.run[0].param["REQUEST_URI"], "/next");
+        assert!(!served.closed);
+        assert_eq!(served.out, vec![
+            stdout(1, b""), end(1, FASTCGI_ABORT_STATUS, FASTCGI_REQUEST_COMPLETE),
+            stdout(1, b"ok"), stdout(1, b""), end(1, 0, FASTCGI_REQUEST_COMPLETE),
+        ]);
+
+        let mut raw = begin(1, false);
+        raw.extend_from_slice(&record(FASTCGI_ABORT_REQUEST, 1, b""));
+        let served = serve(&raw, 0, 0);
+        assert!(served.closed);
+
+        // The controller has finished the aborted request, its answer is dropped
+        let (records, _) = read(&begin(3, true));
+        let mut out = Vec::new();
+        let mut close = false;
+        assert!(FastCGI::finish(&records[0], b"ok".to_vec(), true, &mut close, &mut out));
+        assert!(!close);
+        assert_eq!(answer(&out), vec![stdout(3, b""), end(3, FASTCGI_ABORT_STATUS, FASTCGI_REQUEST_COMPLETE)]);
+    }
+
+    #[test]
+    fn abort_running() {
+        let log = Log::new();
+        let active = Mutex::new(Active::default());
+        let (records, _) = read(&[begin(1, true), record(FASTCGI_ABORT_REQUEST, 1, b""), record(FASTCGI_ABORT_REQUEST, 2, b"")].concat());
+        let abort = Arc::new(AtomicBool::new(false));
+        assert!(Active::start(&active, 1, Arc::clone(&abort), Arc::clone(&log)));
+        // The abort of the other request doesn't stop the controller
+        Active::record(&active, &records[2].header, Arc::clone(&log));
+        assert!(!abort.load(Ordering::Acquire));
+        Active::record(&active, &records[1].header, Arc::clone(&log));
+        assert!(abort.load(Ordering::Acquire));
+        Active::finish(&active, Arc::clone(&log));
+
+        // The request aborted in the queue isn't started, the new "begin" with its request_id is
+        assert!(!Active::start(&active, 2, Arc::new(AtomicBool::new(false)), Arc::clone(&log)));
+        Active::record(&active, &records[2].header, Arc::clone(&log));
+        Active::record(&active, &records[0].header, Arc::clone(&log));
+        assert!(Active::start(&active, 1, Arc::new(AtomicBool::new(false)), Arc::clone(&log)));
+        Active::finish(&active, Arc::clone(&log));
+
+        // The web server has closed the connection
+        let abort = Arc::new(AtomicBool::new(false));
+        assert!(Active::start(&active, 1, Arc::clone(&abort), Arc::clone(&log)));
+        Active::close(&active, log);
+        assert!(abort.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn stream_answer() {
+        let (records, _) = read(&begin(5, true));
+        let body = vec![b'a'; FASTCGI_MAX_CONTENT_LEN as usize + 100];
+        let mut out = Vec::new();
+        {
+            // Each write of the controller is sent at once, the long one is split
+            let mut stream = Stream { tcp: &mut out, request_id: 5 };
+            stream.write_all(b"Status: 200 OK\r\n\r\n").unwrap();
+            stream.write_all(&body).unwrap();
+            // The empty record would end the answer, so it isn't sent
+            assert_eq!(stream.write(b"").unwrap(), 0);
+        }
+        assert_eq!(answer(&out).len(), 3);
+        let mut close = false;
+        assert!(FastCGI::finish(&records[0], b"end".to_vec(), false, &mut close, &mut out));
+        assert_eq!(answer(&out), vec![
+            stdout(5, b"Status: 200 OK\r\n\r\n"),
+            stdout(5, &body[..FASTCGI_MAX_CONTENT_LEN as usize]),
+            stdout(5, &body[FASTCGI_MAX_CONTENT_LEN as usize..]),
+            stdout(5, b"end"),
+            stdout(5, b""),
+            end(5, 0, FASTCGI_REQUEST_COMPLETE),
+        ]);
+
+        // The buffered answer is split the same way
+        let mut out = Vec::new();
+        assert!(FastCGI::finish(&records[0], body.clone(), false, &mut close, &mut out));
+        assert_eq!(answer(&out), vec![
+            stdout(5, &body[..FASTCGI_MAX_CONTENT_LEN as usize]),
+            stdout(5, &body[FASTCGI_MAX_CONTENT_LEN as usize..]),
+            stdout(5, b""),
+            end(5, 0, FASTCGI_REQUEST_COMPLETE),
+        ]);
+    }
+
+    #[test]
+    fn max_body() {
+        // CONTENT_LENGTH is over the limit, the body isn't received
+        let mut raw = begin(1, true);
+        raw.extend_from_slice(&params(1, &[("CONTENT_LENGTH", "100")]));
+        raw.extend_from_slice(&stdin(1, &[b'a'; 100]));
+        // The body is longer than the limit, CONTENT_LENGTH is unknown
+        raw.extend_from_slice(&begin(2, true));
+        raw.extend_from_slice(&params(2, &[("REQUEST_URI", "/")]));
+        raw.extend_from_slice(&stdin(2, &[b'a'; 11]));
+        raw.extend_from_slice(&begin(3, true));
+        raw.extend_from_slice(&params(3, &[("CONTENT_LENGTH", "10")]));
+        raw.extend_from_slice(&stdin(3, &[b'a'; 10]));
+        let served = serve(&raw, 10, 0);
+        assert_eq!(served.run.len(), 1);
+        assert_eq!(served.run[0].begin.header.request_id, 3);
+        assert_eq!(served.run[0].stdin.read().unwrap().as_ref(), &[b'a'; 10]);
+        assert!(!served.closed);
+        assert_eq!(served.out.len(), 9);
+        for (i, request_id) in [1, 2].into_iter().enumerate() {
+            assert_eq!((served.out[i * 3].0, served.out[i * 3].1), (FASTCGI_STDOUT, request_id));
+            assert!(served.out[i * 3].2.starts_with(b"HTTP/1.1 413"));
+            assert_eq!(served.out[i * 3 + 2], end(request_id, 0, FASTCGI_REQUEST_COMPLETE));
+        }
+
+        // The rejected request without FCGI_KEEP_CONN closes the connection after its body
+        let mut raw = begin(1, false);
+        raw.extend_from_slice(&params(1, &[("CONTENT_LENGTH", "100")]));
+        let served = serve(&raw, 10, 0);
+        assert!(!served.closed);
+        raw.extend_from_slice(&stdin(1, &[b'a'; 100]));
+        let served = serve(&raw, 10, 0);
+        assert!(served.run.is_empty());
+        assert!(served.closed);
+    }
+
+    #[test]
+    fn spool_body() {
+        let body: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let mut raw = begin(1, true);
+        raw.extend_from_slice(&params(1, &[("REQUEST_URI", "/upload")]));
+        for chunk in body.chunks(1000) {
+            raw.extend_from_slice(&record(FASTCGI_STDIN, 1, chunk));
+        }
+        raw.extend_from_slice(&record(FASTCGI_STDIN, 1, b""));
+
+        let served = serve(&raw, 0, 1500);
+        assert!(matches!(served.run[0].stdin, Body::File(_, 3000)));
+        assert_eq!(served.run[0].stdin.read().unwrap().as_ref(), &body[..]);
+        assert!(served.log.is_empty());
+
+        // The body isn't larger than the threshold, or spooling is off
+        for spool_body in [3000, 0] {
+            let served = serve(&raw, 0, spool_body);
+            assert!(matches!(served.run[0].stdin, Body::Memory(_)));
+            assert_eq!(served.run[0].stdin.read().unwrap().as_ref(), &body[..]);
+        }
+    }
+}
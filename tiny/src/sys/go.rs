@@ -1,13 +1,102 @@
-use std::{sync::{Arc, Mutex, mpsc::{self, Sender, Receiver}, atomic::{AtomicBool, Ordering}, RwLock}, net::{TcpListener, SocketAddr, TcpStream, IpAddr, Ipv4Addr, Shutdown}, time::Duration, io::{Read, Write}, thread::{self, JoinHandle}, process, collections::HashMap};
+use std::{sync::{Arc, Mutex, mpsc::{self, Sender, SyncSender, Receiver, RecvTimeoutError, TrySendError}, atomic::{AtomicBool, Ordering}, RwLock}, net::{TcpListener, SocketAddr, TcpStream}, time::{Duration, Instant}, io::{ErrorKind, Write}, thread::{self, JoinHandle}, process, collections::HashMap, fs::remove_file, os::unix::io::{AsRawFd, RawFd}};
 
-use crate::{work::{worker::{Worker, MessageWork}, cache::Cache, db::DBConfig, action::{ActMap, Act}, html::Html, lang::Lang}};
+use crate::{sys::socket::Socket, work::{worker::{Worker, WorkerConf, MessageWork}, cache::Cache, db::DB, watch::{Watch, WatchStatus}, action::{ActMap, Act, Upload}, html::Html, lang::Lang}};
 
-use super::{log::Log, app::App, init::Mode};
+#[cfg(feature = "async")]
+use super::runtime::Runtime;
+use super::{log::Log, app::App, init::Mode, fastcgi::FastCGI, http::Http, socket::{self, Listener}, accept::Accept, restart::Restart, rpc::{Control, Handshake, Rpc}, signal::Signal};
 
-const ANY_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+// The pool is checked for the idle workers at least so often
+const WORKER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// All workers are busy for this time, so the new one is started
+const WORKER_SPAWN_DELAY: Duration = Duration::from_millis(100);
+// The listeners and the idle connections check the stop flags so often
+pub const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+// The server is stopping or restarting, so the connections are closed after the current request
+static DRAIN: AtomicBool = AtomicBool::new(false);
+
+// Starts the worker with the given id
+type Spawn = Box<dyn Fn(u8) -> (Worker, Sender<MessageWork>, Arc<Watch>) + Send>;
+
+// Limits of the worker pool
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConf {
+    pub min_workers: usize,             // The idle workers are not retired below this number
+    pub idle_timeout: Duration,         // The idle worker is retired after it, 0 - never
+    pub request_timeout: Duration,      // The request is answered with 504 after it, 0 - unlimited
+    pub queue_timeout: Duration,        // The connection waits for the free worker no longer, 0 - unlimited
+}
+
+// Threads of the server, they stop after accept_stop is set
+struct Threads {
+    run: JoinHandle<()>,                // Dispatcher of the workers or the async runtime
+    main: Option<JoinHandle<()>>,       // FastCGI listener, None - the async runtime accepts the connections
+    http: Option<JoinHandle<()>>,       // Built-in HTTP server
+    accept_stop: Arc<AtomicBool>,
+}
+
+// Workers of the dispatcher. The id of the worker is the index, the retired worker leaves None, so its id is reused.
+struct Pool {
+    workers: Vec<Option<Worker>>,
+    senders: Vec<Option<Sender<MessageWork>>>,
+    watches: Vec<Option<Arc<Watch>>>,
+    len: u8,                            // Number of the running workers
+    control: Arc<Control>,
+}
+
+impl Pool {
+    fn grow(&mut self, spawn: &Spawn) {
+        if let Some(ind) = self.workers.iter().position(|w| w.is_none()) {
+            let (worker, sender, watch) = spawn(ind as u8);
+            self.workers[ind] = Some(worker);
+            self.senders[ind] = Some(sender);
+            self.watches[ind] = Some(watch);
+            self.len += 1;
+            self.control.workers.store(self.len as usize, Ordering::Release);
+        }
+    }
+
+    // The "workers" command has raised the limit, so the new ids are added
+    fn resize(&mut self, max_workers: u8) {
+        let max_workers = max_workers as usize;
+        if self.workers.len() < max_workers {
+            self.workers.resize_with(max_workers, || None);
+            self.senders.resize_with(max_workers, || None);
+            self.watches.resize_with(max_workers, || None);
+        }
+    }
+
+    // The worker is idle, so it stops at once
+    fn retire(&mut self, ind: u8, log: Arc<Mutex<Log>>) {
+        if let Some(sender) = self.senders[ind as usize].take() {
+            if let Err(e) = sender.send(MessageWork::Terminate) {
+                Log::push_error(log, 701, Some(e.to_string()));
+            };
+        }
+        self.watches[ind as usize] = None;
+        if let Some(worker) = self.workers[ind as usize].take() {
+            Go::join_worker(worker, log);
+            self.len -= 1;
+            self.control.workers.store(self.len as usize, Ordering::Release);
+        }
+    }
+
+    // The stuck worker is left to finish by itself, its id is given to the new worker
+    fn abandon(&mut self, ind: u8) {
+        self.senders[ind as usize] = None;
+        self.watches[ind as usize] = None;
+        if self.workers[ind as usize].take().is_some() {
+            self.len -= 1;
+            self.control.workers.store(self.len as usize, Ordering::Release);
+        }
+    }
+}
 
 pub struct Go {}
 
+// The worker threads are not used with the async runtime
+#[cfg_attr(feature = "async", allow(dead_code))]
 impl Go {
     
     fn get_engine() -> ActMap{
@@ -17,123 +106,369 @@ impl Go {
     pub fn run(app: App) {
         let log = Arc::clone(&app.log);
         let html = match Html::new(&app.init.root_path, Arc::clone(&log)) {
-            Some(h) => Arc::new(RwLock::new(h)),
+            Some(h) => Arc::new(RwLock::new(Arc::new(h))),
             None => return,
         };
-        let lang = Arc::new(RwLock::new(Lang::new(&app.init.root_path, &app.init.conf.db, Arc::clone(&log))));
-        let bind_socket = SocketAddr::new(app.init.conf.bind_ip, app.init.conf.bind_port);
-        let bind = match TcpListener::bind(&bind_socket) {
-            Ok(i) => i,
-            Err(e) => {
-                Log::push_stop(log, 500, Some(e.to_string()));
-                return;
+        let lang = Arc::new(RwLock::new(Arc::new(Lang::new(&app.init.root_path, &app.init.conf.db, Arc::clone(&log)))));
+        // On restart the listening sockets come from the old process, so no connection is refused
+        let mut restart = Restart::inherit();
+        let bind = match &app.init.conf.socket {
+            Some(socket) => match restart.unix("fastcgi", &socket.path) {
+                Some(l) => l,
+                None => match Listener::bind_unix(socket) {
+                    Ok(i) => i,
+                    Err(e) => {
+                        Log::push_stop(log, 519, Some(e));
+                        return;
+                    },
+                },
+            },
+            None => {
+                let bind_socket = SocketAddr::new(app.init.conf.bind_ip, app.init.conf.bind_port);
+                match restart.tcp("fastcgi", bind_socket) {
+                    Some(l) => Listener::Tcp(l),
+                    None => match TcpListener::bind(bind_socket) {
+                        Ok(i) => Listener::Tcp(i),
+                        Err(e) => {
+                            Log::push_stop(log, 500, Some(e.to_string()));
+                            return;
+                        },
+                    },
+                }
             },
         };
-        let bind_accept = if app.init.conf.bind_accept != ANY_IP {
-            Some(app.init.conf.rpc_accept)
-        } else {
+        let bind_accept = if app.init.conf.bind_accept.is_any() {
             None
+        } else {
+            Some(app.init.conf.bind_accept.clone())
         };
-        let irc = match TcpListener::bind(SocketAddr::new(app.init.conf.rpc_ip, app.init.conf.rpc_port)) {
-            Ok(i) => i,
-            Err(e) => {
-                Log::push_stop(log, 202, Some(e.to_string()));
-                return;
+        let rpc_socket = SocketAddr::new(app.init.conf.rpc_ip, app.init.conf.rpc_port);
+        let irc = match restart.tcp("rpc", rpc_socket) {
+            Some(l) => l,
+            None => match TcpListener::bind(rpc_socket) {
+                Ok(i) => i,
+                Err(e) => {
+                    Log::push_stop(log, 202, Some(e.to_string()));
+                    return;
+                },
             },
         };
-        
-        let stop = Arc::new(AtomicBool::new(false));
+        let http = match app.init.conf.http_port {
+            Some(port) => {
+                let http_socket = SocketAddr::new(app.init.conf.bind_ip, port);
+                match restart.tcp("http", http_socket) {
+                    Some(l) => Some(l),
+                    None => match TcpListener::bind(http_socket) {
+                        Ok(i) => Some(i),
+                        Err(e) => {
+                            Log::push_stop(log, 516, Some(e.to_string()));
+                            return;
+                        },
+                    },
+                }
+            },
+            None => None,
+        };
+        // These sockets are passed to the new process on restart
+        let mut fds = vec![("fastcgi", bind.as_raw_fd()), ("rpc", irc.as_raw_fd())];
+        if let Some(l) = &http {
+            fds.push(("http", l.as_raw_fd()));
+        }
 
         let cache = Cache::new();
-        let (sender, receiver) = mpsc::channel();
         let engine = Go::get_engine();
-        let (workers, senders_work, receiver_ready) = Go::start_worker(app.init.conf.max, cache, html, lang, Arc::clone(&log), app.init.conf.salt.clone(), app.init.root_path.clone(), app.init.conf.db.clone(), app.init.conf.zone.clone(), app.init.conf.lang_id, engine);
-        let run = Go::run_worker(receiver_ready, Arc::clone(&senders_work), Arc::clone(&stop), Arc::clone(&log), receiver);
+        Worker::set_panic_hook(Arc::clone(&log));
+        let queue_timeout = Duration::from_millis(app.init.conf.queue_timeout);
+        let accept_stop = Arc::new(AtomicBool::new(false));
+        let control = Control::new(app.init.conf.max_workers);
+        let rpc = Arc::new(Rpc {
+            control: Arc::clone(&control),
+            cache: Arc::clone(&cache),
+            html: Arc::clone(&html),
+            lang: Arc::clone(&lang),
+            log: Arc::clone(&log),
+            root: app.init.root_path.clone(),
+            db: app.init.conf.db.clone(),
+            reloading: Mutex::new(()),
+        });
+        let signal_stop = Arc::new(AtomicBool::new(false));
+        Go::listen_signal(Arc::clone(&rpc), Arc::clone(&signal_stop));
+        DB::listen(app.init.conf.db.clone(), Arc::clone(&log), Arc::clone(&cache));
+        if app.init.conf.watch {
+            Rpc::watch(Arc::clone(&rpc));
+        }
+        let conf = WorkerConf {
+            cache,
+            html,
+            lang,
+            log: Arc::clone(&log),
+            salt: app.init.conf.salt.clone(),
+            path: app.init.root_path.clone(),
+            db: app.init.conf.db.clone(),
+            timezone: app.init.conf.zone.clone(),
+            lang_id: app.init.conf.lang_id,
+            engine,
+            max_body: app.init.conf.max_body,
+            spool_body: app.init.conf.spool_body,
+            upload: Upload { max_file: app.init.conf.max_file, max_files: app.init.conf.max_files, max_form: app.init.conf.max_form },
+        };
+        let pool = PoolConf {
+            min_workers: app.init.conf.min_workers,
+            idle_timeout: Duration::from_secs(app.init.conf.idle_timeout),
+            request_timeout: Duration::from_secs(app.init.conf.request_timeout),
+            queue_timeout,
+        };
+
+        // Async runtime serves all connections, the worker threads are not started
+        #[cfg(feature = "async")]
+        {
+            let run = Runtime::run(bind, http, bind_accept, Arc::clone(&accept_stop), control, conf, pool);
+            restart.ready();
+            Go::listen_rpc(irc, app, Threads { run, main: None, http: None, accept_stop }, log, fds, rpc, signal_stop);
+        }
 
-        let main = Go::wait_incoming(&app.init.conf.salt, bind, bind_accept, Arc::clone(&app.log), sender);
+        #[cfg(not(feature = "async"))]
+        {
+            // Pending connections wait for the free worker in the bounded queue
+            let (sender, receiver) = mpsc::sync_channel(app.init.conf.queue);
+            let (sender_overload, receiver_overload) = mpsc::sync_channel(app.init.conf.queue);
+            Go::run_overload(receiver_overload, queue_timeout, Arc::clone(&log));
+            let (spawn, receiver_ready) = Go::start_worker(conf, pool.request_timeout);
+            let run = Go::run_worker(spawn, pool, Arc::clone(&control), receiver_ready, Arc::clone(&log), receiver, sender_overload.clone());
 
-        Go::listen_rpc(irc, app, workers, senders_work, run, stop, log, main, &bind_socket);
+            // Built-in HTTP server
+            let http = http.map(|l| Go::wait_http(l, bind_accept.clone(), Arc::clone(&accept_stop), Arc::clone(&log), sender.clone(), sender_overload.clone(), Arc::clone(&control)));
 
+            let main = Go::wait_incoming(bind, bind_accept, Arc::clone(&accept_stop), Arc::clone(&app.log), sender, sender_overload, Arc::clone(&control));
+            restart.ready();
+            Go::listen_rpc(irc, app, Threads { run, main: Some(main), http, accept_stop }, log, fds, rpc, signal_stop);
+        }
+
+    }
+
+    // The server is stopping or restarting
+    pub fn is_draining() -> bool {
+        DRAIN.load(Ordering::Acquire)
     }
 
-    fn start_worker<'a>(
-        max: u8, 
-        cache: Arc<Mutex<Cache>>, 
-        html: Arc<RwLock<Html>>, 
-        lang: Arc<RwLock<Lang>>, 
-        log: Arc<Mutex<Log>>, 
-        salt: String, 
-        path: String, 
-        db: DBConfig, 
-        timezone: String, 
-        lang_id: u64, 
-        engine: ActMap
-    ) -> (Vec<Worker>, Arc<Mutex<Vec<Sender<MessageWork>>>>, Receiver<u8>) {
-        let mut workers = Vec::with_capacity(max as usize);
-        let mut senders_work = Vec::with_capacity(max as usize);
+    // Returns the function that starts the worker with the given id, and the channel of the ready workers
+    fn start_worker(conf: WorkerConf, request_timeout: Duration) -> (Spawn, Receiver<u8>) {
         let (sender_ready, receiver_ready) = mpsc::channel();
         let sender_ready = Arc::new(Mutex::new(sender_ready));
-        for i in 0..max {
+        let spawn = Box::new(move |id| {
             let (sender_work, receiver_work) = mpsc::channel();
-            workers.push(Worker::new(i, receiver_work, Arc::clone(&sender_ready), Arc::clone(&cache), Arc::clone(&html), Arc::clone(&lang), Arc::clone(&log), salt.clone(), path.clone(), db.clone(), timezone.clone(), lang_id, engine.clone()));
-            senders_work.push(sender_work);
-        }
-        (workers, Arc::new(Mutex::new(senders_work)), receiver_ready)
+            let watch = Arc::new(Watch::new(request_timeout));
+            let worker = Worker::new(id, receiver_work, Arc::clone(&sender_ready), conf.clone(), Arc::clone(&watch));
+            (worker, sender_work, watch)
+        });
+        (spawn, receiver_ready)
     }
 
-    fn run_worker(receivers_ready: Receiver<u8>, senders_work: Arc<Mutex<Vec<Sender<MessageWork>>>>, stop: Arc<AtomicBool>, log: Arc<Mutex<Log>>, receiver: Receiver<MessageWork>) -> JoinHandle<()> {
+    // Dispatcher of the connections. It owns the worker pool, so it starts the new workers when all are busy
+    // and retires the idle ones. On stop it terminates all workers.
+    fn run_worker(spawn: Spawn, conf: PoolConf, control: Arc<Control>, receivers_ready: Receiver<u8>, log: Arc<Mutex<Log>>, receiver: Receiver<(MessageWork, Instant)>, overload: SyncSender<MessageWork>) -> JoinHandle<()> {
+        let PoolConf { min_workers, idle_timeout, queue_timeout, .. } = conf;
+        // The worker ids are u8, the config and the "workers" command keep the limits in this range
+        let min_workers = u8::try_from(min_workers).unwrap_or(u8::MAX);
         thread::spawn(move || {
+            let mut max_workers = u8::try_from(control.max_workers.load(Ordering::Acquire)).unwrap_or(u8::MAX);
+            let mut pool = Pool {
+                workers: (0..max_workers).map(|_| None).collect(),
+                senders: (0..max_workers).map(|_| None).collect(),
+                watches: (0..max_workers).map(|_| None).collect(),
+                len: 0,
+                control: Arc::clone(&control),
+            };
+            for _ in 0..min_workers {
+                pool.grow(&spawn);
+            }
+            // Ready workers with the time they became ready. The last one is taken first, so the rest stay idle and can be retired.
+            let mut idle: Vec<(u8, Instant)> = Vec::with_capacity(max_workers as usize);
             loop {
-                match receiver.recv() {
-                    Ok(m) => match m {
-                        MessageWork::Terminate => break,
-                        MessageWork::Job(tcp) => {
-                            match receivers_ready.recv() {
-                                Ok(ind) => {
-                                    if stop.load(Ordering::Acquire) {
-                                        continue;
-                                    }
-                                    match Mutex::lock(&senders_work) {
-                                        Ok(senders) => {
-                                            let sender = match senders.get(ind as usize) {
-                                                Some(s) => s,
-                                                None => Log::push_error(log, 704, None),
-                                            };
-                                            if let Err(e) = sender.send(MessageWork::Job(tcp)) {
-                                                Log::push_error(log, 705, Some(e.to_string()));
-                                            }
-                                        },
-                                        Err(e) => Log::error(log, e.to_string()),
-                                    };
-                                },
-                                Err(e) => Log::push_error(log, 706, Some(e.to_string())),
+                Go::watch(&mut pool, Some(&spawn), Arc::clone(&log));
+                Go::collect_ready(&receivers_ready, &mut idle);
+                // The limit may be changed by the "workers" command, the idle workers over it are retired at once
+                max_workers = u8::try_from(control.max_workers.load(Ordering::Acquire)).unwrap_or(u8::MAX);
+                pool.resize(max_workers);
+                let min_workers = min_workers.min(max_workers);
+                let mut retired = false;
+                while pool.len > min_workers && !idle.is_empty() && (pool.len > max_workers || (!idle_timeout.is_zero() && idle[0].1.elapsed() >= idle_timeout)) {
+                    let (ind, _) = idle.remove(0);
+                    pool.retire(ind, Arc::clone(&log));
+                    retired = true;
+                }
+                if retired {
+                    Log::push_info(Arc::clone(&log), 708, Some(format!("workers={}", pool.len)));
+                }
+                control.busy.store((pool.len as usize).saturating_sub(idle.len()), Ordering::Release);
+                let (job, time) = match receiver.recv_timeout(WORKER_CHECK_INTERVAL) {
+                    Ok((MessageWork::Terminate, _)) => break,
+                    Ok(j) => j,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(e) => Log::push_error(log, 511, Some(e.to_string())),
+                };
+                control.queue.fetch_sub(1, Ordering::AcqRel);
+                Go::collect_ready(&receivers_ready, &mut idle);
+                // The connection waits for the free worker no longer than queue_timeout.
+                // If all workers are busy for WORKER_SPAWN_DELAY, the new one is started.
+                let ready = loop {
+                    if let Some((ind, _)) = idle.pop() {
+                        break Some(ind);
+                    }
+                    let wait = if queue_timeout.is_zero() {
+                        WORKER_SPAWN_DELAY
+                    } else {
+                        queue_timeout.saturating_sub(time.elapsed()).min(WORKER_SPAWN_DELAY)
+                    };
+                    if wait.is_zero() {
+                        break None;
+                    }
+                    match receivers_ready.recv_timeout(wait) {
+                        Ok(ind) => break Some(ind),
+                        Err(RecvTimeoutError::Timeout) => {
+                            Go::watch(&mut pool, Some(&spawn), Arc::clone(&log));
+                            if pool.len < max_workers && time.elapsed() >= WORKER_SPAWN_DELAY {
+                                pool.grow(&spawn);
+                                Log::push_info(Arc::clone(&log), 707, Some(format!("workers={}", pool.len)));
                             }
                         },
+                        Err(e) => Log::push_error(log, 706, Some(e.to_string())),
+                    };
+                };
+                match ready {
+                    Some(ind) => {
+                        let sender = match pool.senders.get(ind as usize) {
+                            Some(Some(s)) => s,
+                            _ => Log::push_error(log, 704, None),
+                        };
+                        if let Err(e) = sender.send(job) {
+                            Log::push_error(log, 705, Some(e.to_string()));
+                        }
+                        control.busy.store((pool.len as usize).saturating_sub(idle.len()), Ordering::Release);
                     },
-                    Err(e) => Log::push_error(log, 511, Some(e.to_string())),
+                    None => {
+                        Log::push_warning(Arc::clone(&log), 522, None);
+                        Go::overload(&overload, job);
+                    },
+                };
+            }
+
+            for s in pool.senders.iter().flatten() {
+                if let Err(e) = s.send(MessageWork::Terminate) {
+                    Log::push_error(log, 701, Some(e.to_string()));
                 };
             }
+            // The stuck workers are not waited for
+            while pool.watches.iter().flatten().any(|w| w.is_enabled()) && pool.workers.iter().flatten().any(|w| !w.is_finished()) {
+                Go::watch(&mut pool, None, Arc::clone(&log));
+                thread::sleep(WORKER_SPAWN_DELAY);
+            }
+            for w in pool.workers.into_iter().flatten() {
+                Go::join_worker(w, Arc::clone(&log));
+            }
         })
     }
 
-    fn wait_incoming(salt: &str, bind: TcpListener, accept: Option<IpAddr>, log: Arc<Mutex<Log>>, sender: Sender<MessageWork>) -> JoinHandle<()> {
-        let uuid_stop = format!("stop {}", salt).as_bytes().to_vec();
-        thread::spawn(move || {
+    // Watchdog of the requests. The expired request is answered with 504 and its query is cancelled,
+    // the stuck worker is replaced by the new one.
+    fn watch(pool: &mut Pool, spawn: Option<&Spawn>, log: Arc<Mutex<Log>>) {
+        for ind in 0..pool.watches.len() {
+            let status = match &pool.watches[ind] {
+                Some(w) => w.check(),
+                None => continue,
+            };
+            match status {
+                WatchStatus::Idle | WatchStatus::Busy => {},
+                WatchStatus::Expired(cancel) => {
+                    Log::push_warning(Arc::clone(&log), 709, Some(format!("worker={}", ind)));
+                    if let Some(token) = cancel {
+                        let log = Arc::clone(&log);
+                        thread::spawn(move || DB::cancel(token, log));
+                    }
+                },
+                WatchStatus::Stuck => {
+                    Log::push_warning(Arc::clone(&log), 710, Some(format!("worker={}", ind)));
+                    pool.abandon(ind as u8);
+                    if let Some(spawn) = spawn {
+                        pool.grow(spawn);
+                    }
+                },
+            }
+        }
+    }
 
-            let mut uuid_len;
-            let uuid_stop_len = uuid_stop.len();
-            let mut uuid_buf = vec![0; uuid_stop_len];
+    // Take the ready signals of the workers
+    fn collect_ready(receivers_ready: &Receiver<u8>, idle: &mut Vec<(u8, Instant)>) {
+        while let Ok(ind) = receivers_ready.try_recv() {
+            idle.push((ind, Instant::now()));
+        }
+    }
+
+    fn join_worker(worker: Worker, log: Arc<Mutex<Log>>) {
+        if let Err(e) = worker.join() {
+            match (e.downcast_ref::<&str>(), e.downcast_ref::<String>()) {
+                (Some(&e), _) => Log::push_error(log, 702, Some(e.to_owned())),
+                (_, Some(e)) => Log::push_error(log, 702, Some(e.to_owned())),
+                (None, None) =>  Log::push_error(log, 702, None),
+            };
+        };
+    }
+
+    // Put the connection into the queue, or answer 503 when the queue is full
+    fn enqueue(sender: &SyncSender<(MessageWork, Instant)>, overload: &SyncSender<MessageWork>, job: MessageWork, control: &Control, log: Arc<Mutex<Log>>) {
+        // Counted before sending, so the dispatcher never takes the connection off the count before it's added
+        control.queue.fetch_add(1, Ordering::AcqRel);
+        match sender.try_send((job, Instant::now())) {
+            Ok(()) => {},
+            Err(TrySendError::Full((job, _))) => {
+                control.queue.fetch_sub(1, Ordering::AcqRel);
+                Log::push_warning(log, 521, None);
+                Go::overload(overload, job);
+            },
+            Err(e) => Log::push_error(log, 510, Some(e.to_string())),
+        }
+    }
+
+    // Pass the connection to the overload thread. If it's busy too, the connection is just closed.
+    fn overload(overload: &SyncSender<MessageWork>, job: MessageWork) {
+        let _ = overload.try_send(job);
+    }
+
+    // Answer 503 with Retry-After to the connections that didn't get the worker
+    fn run_overload(receiver: Receiver<MessageWork>, queue_timeout: Duration, log: Arc<Mutex<Log>>) {
+        let retry = queue_timeout.as_secs().max(1);
+        thread::spawn(move || {
+            for job in receiver.iter() {
+                match job {
+                    MessageWork::Job(socket) => FastCGI::overload(socket, retry, Arc::clone(&log)),
+                    MessageWork::Http(tcp) => Http::overload(tcp, retry),
+                    MessageWork::Terminate => {},
+                }
+            }
+        });
+    }
 
-            for stream in bind.incoming() {
-                let tcp = match stream {
+    fn wait_incoming(bind: Listener, accept: Option<Accept>, stop: Arc<AtomicBool>, log: Arc<Mutex<Log>>, sender: SyncSender<(MessageWork, Instant)>, overload: SyncSender<MessageWork>, control: Arc<Control>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            // The listener may be shared with the new process on restart, so the connection can be taken by it after poll
+            if let Err(e) = bind.set_nonblocking(true) {
+                Log::push_error(log, 502, Some(e.to_string()));
+            }
+            loop {
+                if !Go::wait_accept(bind.as_raw_fd(), &stop, Arc::clone(&log)) {
+                    break;
+                }
+                let tcp = match bind.accept() {
                     Ok(s) => s,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
                     Err(e) => {
                         Log::push_warning(Arc::clone(&log), 502, Some(e.to_string()));
                         continue;
                     },
                 };
-                if let Some(a) = accept {
-                    let addr = match tcp.peer_addr() {
+                // The Unix socket is protected by the file permissions
+                if let (Some(a), Socket::Tcp(stream)) = (&accept, &tcp) {
+                    let addr = match stream.peer_addr() {
                         Ok(a) => a,
                         Err(e) => {
                             Log::push_warning(Arc::clone(&log), 503, Some(e.to_string()));
@@ -141,51 +476,151 @@ impl Go {
                         },
                     };
 
-                    if addr.ip() != a {
-                        Log::push_warning(Arc::clone(&log), 504, Some(format!("{}", addr)));
+                    if let Err(rule) = a.check(addr.ip()) {
+                        Log::push_warning(Arc::clone(&log), 504, Some(format!("{} {}", addr, rule)));
                         continue;
                     }
                 }
 
-                uuid_len = match tcp.peek(&mut uuid_buf) {
-                    Ok(l) => l,
+                Go::enqueue(&sender, &overload, MessageWork::Job(tcp), &control, Arc::clone(&log));
+            }
+            // The queued connections are served before the workers are terminated
+            if let Err(e) = sender.send((MessageWork::Terminate, Instant::now())) {
+                Log::push_error(log, 512, Some(e.to_string()));
+            };
+        })
+    }
+
+    fn wait_http(bind: TcpListener, accept: Option<Accept>, stop: Arc<AtomicBool>, log: Arc<Mutex<Log>>, sender: SyncSender<(MessageWork, Instant)>, overload: SyncSender<MessageWork>, control: Arc<Control>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            if let Err(e) = bind.set_nonblocking(true) {
+                Log::push_error(log, 502, Some(e.to_string()));
+            }
+            loop {
+                if !Go::wait_accept(bind.as_raw_fd(), &stop, Arc::clone(&log)) {
+                    break;
+                }
+                let tcp = match bind.accept() {
+                    Ok((s, _)) => s,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
                     Err(e) => {
-                        Log::push_warning(Arc::clone(&log), 505, Some(e.to_string()));
+                        Log::push_warning(Arc::clone(&log), 502, Some(e.to_string()));
                         continue;
                     },
                 };
-                if uuid_len == uuid_stop_len && &uuid_buf[..uuid_len] == &uuid_stop {
-                    if let Err(e) = sender.send(MessageWork::Terminate) {
-                        Log::push_error(log, 512, Some(e.to_string()));
+                if let Some(a) = &accept {
+                    let addr = match tcp.peer_addr() {
+                        Ok(a) => a,
+                        Err(e) => {
+                            Log::push_warning(Arc::clone(&log), 503, Some(e.to_string()));
+                            continue;
+                        },
                     };
-                    break;
+
+                    if let Err(rule) = a.check(addr.ip()) {
+                        Log::push_warning(Arc::clone(&log), 504, Some(format!("{} {}", addr, rule)));
+                        continue;
+                    }
                 }
-                if let Err(e) = sender.send(MessageWork::Job(tcp)) {
-                    Log::push_error(log, 510, Some(e.to_string()));
-                };
+                Go::enqueue(&sender, &overload, MessageWork::Http(tcp), &control, Arc::clone(&log));
             }
         })
     }
 
-    fn listen_rpc(irc: TcpListener, app: App, workers: Vec<Worker>, sends: Arc<Mutex<Vec<Sender<MessageWork>>>>, run: JoinHandle<()>, stop: Arc<AtomicBool>, log: Arc<Mutex<Log>>, main: JoinHandle<()>, stop_socket: &SocketAddr) {
-        let rpc_accept = if app.init.conf.rpc_accept != ANY_IP {
-            Some(app.init.conf.rpc_accept)
-        } else {
+    // Wait for the incoming connection, false - the listener is stopped
+    fn wait_accept(fd: RawFd, stop: &AtomicBool, log: Arc<Mutex<Log>>) -> bool {
+        loop {
+            if stop.load(Ordering::Acquire) {
+                return false;
+            }
+            match socket::wait_read(fd, DRAIN_CHECK_INTERVAL) {
+                Ok(true) => return !stop.load(Ordering::Acquire),
+                Ok(false) => {},
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => {
+                    Log::push_warning(log, 502, Some(e.to_string()));
+                    return false;
+                },
+            };
+        }
+    }
+
+    // SIGTERM and SIGINT stop the server like the "stop" command, SIGHUP reloads like the "reload" command.
+    // The log file is opened for every message, so after SIGUSR1 from logrotate the new file is created at once.
+    fn listen_signal(rpc: Arc<Rpc>, stop: Arc<AtomicBool>) {
+        let mut signal = match Signal::listen(&[libc::SIGTERM, libc::SIGINT, libc::SIGHUP, libc::SIGUSR1]) {
+            Ok(s) => s,
+            Err(e) => {
+                Log::push_warning(Arc::clone(&rpc.log), 233, Some(e));
+                return;
+            },
+        };
+        thread::spawn(move || {
+            while let Some(s) = signal.wait() {
+                match s {
+                    libc::SIGTERM | libc::SIGINT => {
+                        Log::push_info(Arc::clone(&rpc.log), 235, Some(if s == libc::SIGTERM { "SIGTERM" } else { "SIGINT" }.to_owned()));
+                        stop.store(true, Ordering::Release);
+                    },
+                    libc::SIGHUP => {
+                        Log::push_info(Arc::clone(&rpc.log), 232, None);
+                        rpc.reload();
+                    },
+                    libc::SIGUSR1 => Log::push_info(Arc::clone(&rpc.log), 236, None),
+                    _ => {},
+                }
+            }
+        });
+    }
+
+    fn listen_rpc(irc: TcpListener, app: App, threads: Threads, log: Arc<Mutex<Log>>, fds: Vec<(&str, RawFd)>, rpc: Arc<Rpc>, signal_stop: Arc<AtomicBool>) {
+        let rpc_accept = if app.init.conf.rpc_accept.is_any() {
             None
+        } else {
+            Some(app.init.conf.rpc_accept.clone())
         };
-        let stop_data = format!("stop {}", app.init.conf.salt);
-        for stream in irc.incoming() {
-            match stream {
-                Ok(mut s) => if let Some(m) = Go::get_rpc_connect(&mut s, Arc::clone(&log), &rpc_accept, &stop_data) {
+        let drain_timeout = Duration::from_secs(app.init.conf.drain_timeout);
+        let unlink = app.init.conf.socket.as_ref().map(|s| s.path.clone());
+        loop {
+            // The signal stops the server the same way as the command
+            if !Go::wait_accept(irc.as_raw_fd(), &signal_stop, Arc::clone(&log)) {
+                Go::stop(Arc::clone(&log), threads, unlink, drain_timeout);
+                break;
+            }
+            match irc.accept() {
+                Ok((mut s, _)) => if let Some((m, value)) = Go::get_rpc_connect(&mut s, Arc::clone(&log), &rpc_accept, &app.init.conf.salt) {
                     match m {
                         Mode::Stop => {
-                            Go::stop(workers, sends, Arc::clone(&log), run, stop, main, stop_socket, &stop_data);
+                            Go::stop(Arc::clone(&log), threads, unlink, drain_timeout);
                             if let Err(e) = s.write_all(format!("stop {}", process::id()).as_bytes()) {
                                 Log::push_warning(log, 216, Some(e.to_string()));
                             };
                             break;
                         },
-                        _ => {},
+                        Mode::Restart => match Restart::spawn(&app.init.exe_file, &app.init.exe_path, &app.init.root_path, &fds) {
+                            Ok(pid) => {
+                                Log::push_info(Arc::clone(&log), 527, Some(format!("PID={}", pid)));
+                                if let Err(e) = s.write_all(format!("restart {}", pid).as_bytes()) {
+                                    Log::push_warning(Arc::clone(&log), 216, Some(e.to_string()));
+                                };
+                                drop(s);
+                                // The socket file belongs to the new process now
+                                Go::stop(Arc::clone(&log), threads, None, drain_timeout);
+                                break;
+                            },
+                            Err(e) => {
+                                Log::push_warning(Arc::clone(&log), 526, Some(e.clone()));
+                                if let Err(e) = s.write_all(format!("error {}", e).as_bytes()) {
+                                    Log::push_warning(Arc::clone(&log), 216, Some(e.to_string()));
+                                };
+                            },
+                        },
+                        m => {
+                            let answer = rpc.exec(m, value.as_deref());
+                            if let Err(e) = s.write_all(answer.as_bytes()) {
+                                Log::push_warning(Arc::clone(&log), 216, Some(e.to_string()));
+                            };
+                        },
                     };
                 },
                 Err(e) => {
@@ -195,7 +630,8 @@ impl Go {
         }
     }
 
-    fn get_rpc_connect(tcp: &mut TcpStream, log: Arc<Mutex<Log>>, rpc_accept: &Option<IpAddr>, stop: &str) -> Option<Mode> {
+    // The server sends the nonce, the command is "<name>[ <value>] <signature>"
+    fn get_rpc_connect(tcp: &mut TcpStream, log: Arc<Mutex<Log>>, rpc_accept: &Option<Accept>, salt: &str) -> Option<(Mode, Option<String>)> {
         if let Some(a) = rpc_accept {
             let addr = match tcp.peer_addr() {
                 Ok(a) => a,
@@ -205,11 +641,12 @@ impl Go {
                 },
             };
 
-            if &addr.ip() == a {
-                Log::push_info(Arc::clone(&log), 209, Some(format!("{}", addr)));
-            } else {
-                Log::push_warning(log, 210, Some(format!("{}", addr)));
-                return None;
+            match a.check(addr.ip()) {
+                Ok(()) => Log::push_info(Arc::clone(&log), 209, Some(format!("{}", addr))),
+                Err(rule) => {
+                    Log::push_warning(log, 210, Some(format!("{} {}", addr, rule)));
+                    return None;
+                },
             }
         }
 
@@ -217,47 +654,103 @@ impl Go {
             Log::push_warning(log, 204, Some(e.to_string()));
             return None;
         };
-        let mut buf = [0; 1024];
-        let len = match tcp.read(&mut buf) {
-            Ok(l) => l,
+        let nonce = match Handshake::nonce() {
+            Ok(n) => n,
+            Err(e) => {
+                Log::push_warning(log, 228, Some(e));
+                return None;
+            },
+        };
+        if let Err(e) = tcp.write_all(format!("{}\n", nonce).as_bytes()) {
+            Log::push_warning(log, 216, Some(e.to_string()));
+            return None;
+        };
+        let buf = match Handshake::read_line(tcp) {
+            Ok(b) => b,
             Err(e) => {
                 Log::push_warning(log, 205, Some(e.to_string()));
                 return None;
             },
         };
-        if &buf[..len] == stop.as_bytes() {
-            Log::push_info(log, 207, None);
-            return Some(Mode::Stop);
-        } 
-        Log::push_warning(log, 206, Some(format!("{:x?}", &buf[..len])));
-        return None;
-    }
-
-    fn stop(workers: Vec<Worker>, senders_work: Arc<Mutex<Vec<Sender<MessageWork>>>>, log: Arc<Mutex<Log>>, run: JoinHandle<()>, stop: Arc<AtomicBool>, main: JoinHandle<()>, stop_socket: &SocketAddr, stop_data: &str) {
-        match TcpStream::connect_timeout(stop_socket, Duration::from_secs(1)) {
-            Ok(mut tcp) => {
-                if let Err(e) = tcp.write_all(stop_data.as_bytes()) {
-                    Log::push_error(log, 507, Some(e.to_string()));
-                };
-                if let Err(e) = tcp.shutdown(Shutdown::Both) {
-                    Log::push_warning(Arc::clone(&log), 508, Some(e.to_string()));
-                };
+        let (command, signature) = match std::str::from_utf8(&buf).ok().and_then(|c| c.rsplit_once(' ')) {
+            Some(c) => c,
+            None => {
+                Log::push_warning(log, 206, Some(format!("{:x?}", &buf)));
+                return None;
             },
-            Err(e) => {
-                Log::push_error(log, 506, Some(e.to_string()));
+        };
+        if !Handshake::verify(salt, &nonce, command, signature) {
+            Log::push_warning(log, 229, Some(command.to_owned()));
+            return None;
+        }
+        let (name, value) = match command.split_once(' ') {
+            Some((n, v)) => (n, Some(v.to_owned())),
+            None => (command, None),
+        };
+        let mode = match name {
+            "stop" => Mode::Stop,
+            "restart" => Mode::Restart,
+            "status" => Mode::Status,
+            "reload" => Mode::Reload,
+            "reload-routes" => Mode::ReloadRoutes,
+            "cache-clear" => Mode::CacheClear,
+            "log-level" => Mode::LogLevel,
+            "workers" => Mode::Workers,
+            _ => {
+                Log::push_warning(log, 206, Some(name.to_owned()));
+                return None;
             },
         };
+        match mode {
+            Mode::Stop => Log::push_info(log, 207, None),
+            Mode::Restart => Log::push_info(log, 223, None),
+            _ => Log::push_info(log, 225, Some(command.to_owned())),
+        };
+        Some((mode, value))
+    }
 
-        if let Err(e) = main.join() {
-            match (e.downcast_ref::<&str>(), e.downcast_ref::<String>()) {
-                (Some(&e), _) => Log::push_error(log, 501, Some(e.to_owned())),
-                (_, Some(e)) => Log::push_error(log, 501, Some(e.to_owned())),
-                (None, None) =>  Log::push_error(log, 501, None),
+    // Stop accepting the connections and wait for the open ones no longer than drain_timeout
+    fn stop(log: Arc<Mutex<Log>>, threads: Threads, unlink: Option<String>, drain_timeout: Duration) {
+        let Threads { run, main, http, accept_stop } = threads;
+        let deadline = Instant::now() + drain_timeout;
+        DRAIN.store(true, Ordering::Release);
+        accept_stop.store(true, Ordering::Release);
+
+        // The HTTP server is stopped first, so the main listener sends the terminate signal after all its connections
+        if let Some(http) = http {
+            if let Err(e) = http.join() {
+                match (e.downcast_ref::<&str>(), e.downcast_ref::<String>()) {
+                    (Some(&e), _) => Log::push_error(log, 517, Some(e.to_owned())),
+                    (_, Some(e)) => Log::push_error(log, 517, Some(e.to_owned())),
+                    (None, None) =>  Log::push_error(log, 517, None),
+                };
             };
-        };
+        }
 
-        stop.store(true, Ordering::Release);
+        if let Some(main) = main {
+            if let Err(e) = main.join() {
+                match (e.downcast_ref::<&str>(), e.downcast_ref::<String>()) {
+                    (Some(&e), _) => Log::push_error(log, 501, Some(e.to_owned())),
+                    (_, Some(e)) => Log::push_error(log, 501, Some(e.to_owned())),
+                    (None, None) =>  Log::push_error(log, 501, None),
+                };
+            };
+        }
+
+        if let Some(path) = unlink {
+            if let Err(e) = remove_file(path) {
+                Log::push_warning(Arc::clone(&log), 520, Some(e.to_string()));
+            }
+        }
 
+        // The workers finish the current requests, then the process exits anyway
+        while !run.is_finished() {
+            if Instant::now() >= deadline {
+                Log::push_warning(log, 528, None);
+                return;
+            }
+            thread::sleep(WORKER_SPAWN_DELAY);
+        }
         if let Err(e) = run.join() {
             match (e.downcast_ref::<&str>(), e.downcast_ref::<String>()) {
                 (Some(&e), _) => Log::push_error(log, 509, Some(e.to_owned())),
@@ -265,27 +758,6 @@ impl Go {
                 (None, None) =>  Log::push_error(log, 509, None),
             };
         };
-
-        match Mutex::lock(&senders_work) {
-            Ok(sender_work) => {
-                for s in sender_work.iter() {
-                    if let Err(e) = s.send(MessageWork::Terminate) {
-                        Log::push_error(log, 701, Some(e.to_string()));
-                    };
-                }
-            },
-            Err(e) => Log::error(log, e.to_string()),
-        };
-
-        for w in workers {
-            if let Err(e) = w.join() {
-                match (e.downcast_ref::<&str>(), e.downcast_ref::<String>()) {
-                    (Some(&e), _) => Log::push_error(log, 702, Some(e.to_owned())),
-                    (_, Some(e)) => Log::push_error(log, 702, Some(e.to_owned())),
-                    (None, None) =>  Log::push_error(log, 702, None),
-                };
-            };
-        }
     }
 
 }
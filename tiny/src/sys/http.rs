@@ -0,0 +1,495 @@
+use std::{borrow::Cow, net::{TcpStream, Shutdown, SocketAddr}, io::{BufRead, BufReader, ErrorKind, Read, Write}, collections::HashMap, sync::{Arc, Mutex, atomic::Ordering}, time::Duration};
+
+use crate::{sys::{log::Log, go::Go}, work::{action::{DataRun, Body, Action, Handler}, watch::Output}};
+
+pub const HTTP_KEEP_ALIVE: Duration = Duration::from_secs(5);
+// The slow client may send the large body longer than the next request is waited for
+pub const HTTP_BODY_TIMEOUT: Duration = Duration::from_secs(60);
+pub const HTTP_MAX_HEADER_LEN: u64 = 65536;
+
+// Head of the HTTP request
+struct Head {
+    param: HashMap<String, String>,     // CGI params
+    keep_alive: bool,                   // Connection is not closed after the answer
+    chunked: bool,                      // Transfer-Encoding: chunked
+    len: usize,                         // Content-Length
+    head: bool,                         // HEAD request, the answer is sent without the body
+}
+
+// The open connection of the built-in HTTP server
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: Output<TcpStream>,          // The controller and the watchdog write to the connection
+    remote: SocketAddr,
+    local: SocketAddr,
+}
+
+// Writer of the streamed answer.
+// The length of the streamed answer is unknown, so the connection is closed after it.
+pub struct Stream<'a> {
+    tcp: &'a mut dyn Write,
+    used: bool,
+    head: bool,                         // HEAD request, only the header of the answer is sent
+    tail: Vec<u8>,                      // Last bytes of the header, its end may be split between the parts
+    skip: bool,                         // The header is sent, the rest of the answer to HEAD is dropped
+}
+
+impl<'a> Write for Stream<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.skip {
+            return Ok(buf.len());
+        }
+        let mut part = Cow::Borrowed(buf);
+        if !self.used {
+            // The first part starts with the header
+            self.used = true;
+            let mut first = buf.to_vec();
+            Http::set_connection(&mut first, false);
+            part = Cow::Owned(first);
+        }
+        if self.head {
+            let mut seen = std::mem::take(&mut self.tail);
+            seen.extend_from_slice(&part);
+            if let Some(i) = seen.windows(4).position(|w| w == b"\r\n\r\n") {
+                let end = i + 4 + part.len() - seen.len();
+                self.skip = true;
+                self.tcp.write_all(&part[..end])?;
+                return Ok(buf.len());
+            }
+            self.tail = seen.split_off(seen.len().saturating_sub(3));
+        }
+        self.tcp.write_all(&part)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.tcp.flush()
+    }
+}
+
+// Built-in HTTP/1.1 server for the development and the tests.
+// The request is converted to the same CGI params and stdin as FastCGI gives.
+pub struct Http { }
+
+impl Http {
+
+    pub fn run(f: &Handler, tcp: TcpStream, mut data: DataRun, log: Arc<Mutex<Log>>) {
+        let mut conn = match Http::open(tcp, Arc::clone(&log)) {
+            Some(c) => c,
+            None => return,
+        };
+        while Http::serve(f, &mut conn, &mut data, Arc::clone(&log)) {}
+    }
+
+    // Prepare the accepted connection
+    pub fn open(tcp: TcpStream, log: Arc<Mutex<Log>>) -> Option<Connection> {
+        let writer = match tcp.try_clone() {
+            Ok(t) => Output::new(t),
+            Err(e) => {
+                Log::push_warning(log, 513, Some(e.to_string()));
+                return None;
+            },
+        };
+        if let Err(e) = tcp.set_read_timeout(Some(HTTP_KEEP_ALIVE)) {
+            Log::push_warning(log, 518, Some(e.to_string()));
+            return None;
+        }
+        let remote = match tcp.peer_addr() {
+            Ok(a) => a,
+            Err(e) => {
+                Log::push_warning(log, 503, Some(e.to_string()));
+                return None;
+            },
+        };
+        let local = match tcp.local_addr() {
+            Ok(a) => a,
+            Err(e) => {
+                Log::push_warning(log, 503, Some(e.to_string()));
+                return None;
+            },
+        };
+        Some(Connection { reader: BufReader::new(tcp), writer, remote, local })
+    }
+
+    // Serve one request of the connection. Returns false, when the connection must be closed.
+    pub fn serve(f: &Handler, conn: &mut Connection, data: &mut DataRun, log: Arc<Mutex<Log>>) -> bool {
+        let mut head = match Http::read_head(&mut conn.reader) {
+            Ok(Some(h)) => h,
+            Ok(None) => return false,
+            Err(code) => {
+                Http::write_status(code, &mut conn.writer);
+                return false;
+            },
+        };
+        head.param.insert("REMOTE_ADDR".to_owned(), conn.remote.ip().to_string());
+        head.param.insert("REMOTE_PORT".to_owned(), conn.remote.port().to_string());
+        head.param.insert("SERVER_ADDR".to_owned(), conn.local.ip().to_string());
+        head.param.insert("SERVER_PORT".to_owned(), conn.local.port().to_string());
+        head.param.insert("DOCUMENT_ROOT".to_owned(), format!("{}/www", data.path));
+
+        if data.max_body > 0 && head.len > data.max_body {
+            Http::write_status(413, &mut conn.writer);
+            return false;
+        }
+        if let Some(expect) = head.param.get("HTTP_EXPECT") {
+            if expect.eq_ignore_ascii_case("100-continue") && conn.writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").is_err() {
+                return false;
+            }
+        }
+        if let Err(e) = conn.reader.get_ref().set_read_timeout(Some(HTTP_BODY_TIMEOUT)) {
+            Log::push_warning(log, 518, Some(e.to_string()));
+            return false;
+        }
+        let stdin = match Http::read_body(&mut conn.reader, &head, data.max_body, data.spool_body) {
+            Ok(b) => b,
+            Err(code) => {
+                Http::write_status(code, &mut conn.writer);
+                return false;
+            },
+        };
+        if let Err(e) = conn.reader.get_ref().set_read_timeout(Some(HTTP_KEEP_ALIVE)) {
+            Log::push_warning(log, 518, Some(e.to_string()));
+            return false;
+        }
+
+        let (number, mut controller) = conn.writer.start();
+        if data.watch.is_enabled() {
+            // The watchdog answers 504 and closes the connection, when the deadline has passed
+            let watchdog = conn.writer.clone();
+            data.watch.start(Box::new(move || {
+                watchdog.expire(number, |tcp, written| {
+                    // The started answer can't be replaced, it is just cut
+                    if !written {
+                        Http::write_status(504, tcp);
+                    }
+                    let _ = tcp.shutdown(Shutdown::Both);
+                });
+            }), data.db.cancel_token(), Arc::clone(&data.abort));
+        }
+        let mut stream = Stream { tcp: &mut controller, used: false, head: head.head, tail: Vec::new(), skip: false };
+        let mut answer = f(head.param, stdin, data, &mut stream, log);
+        if data.watch.finish() {
+            data.abort.store(false, Ordering::Release);
+            return false;
+        }
+        if stream.used {
+            return false;
+        }
+        if head.head {
+            if let Some(i) = answer.windows(4).position(|w| w == b"\r\n\r\n") {
+                answer.truncate(i + 4);
+            }
+        }
+        // On stop the keep-alive connection is closed after the answer
+        let keep_alive = head.keep_alive && !Go::is_draining();
+        Http::set_connection(&mut answer, keep_alive);
+        conn.writer.write_all(&answer).is_ok() && keep_alive
+    }
+
+    // The connection is kept or closed by the server, so the header is added after the status line of the answer
+    fn set_connection(answer: &mut Vec<u8>, keep_alive: bool) {
+        if let Some(i) = answer.windows(2).position(|w| w == b"\r\n") {
+            let header: &[u8] = if keep_alive { b"Connection: keep-alive\r\n" } else { b"Connection: close\r\n" };
+            answer.splice(i + 2..i + 2, header.iter().copied());
+        }
+    }
+
+    // The next request has already been read into the buffer
+    pub fn is_pending(conn: &Connection) -> bool {
+        !conn.reader.buffer().is_empty()
+    }
+
+    // The request is read by the blocking calls, the async runtime waits for the next one without them
+    pub fn set_nonblocking(conn: &Connection, nonblocking: bool) -> std::io::Result<()> {
+        conn.reader.get_ref().set_nonblocking(nonblocking)
+    }
+
+    // Read the request line and the headers
+    fn read_head<R: BufRead>(reader: &mut R) -> Result<Option<Head>, u16> {
+        let mut limit = reader.take(HTTP_MAX_HEADER_LEN);
+        let mut line = Vec::with_capacity(256);
+        // Empty lines before the request line are ignored
+        loop {
+            line.clear();
+            match limit.read_until(b'\n', &mut line) {
+                Ok(0) => return Ok(None),
+                Ok(_) => {},
+                // Keep-alive timeout or the connection is broken
+                Err(_) => return Ok(None),
+            };
+            if line != b"\r\n" && line != b"\n" {
+                break;
+            }
+        }
+        let request = match std::str::from_utf8(&line) {
+            Ok(s) => s.trim_end().to_owned(),
+            Err(_) => return Err(400),
+        };
+        let parts: Vec<&str> = request.split(' ').collect();
+        if parts.len() != 3 || !parts[2].starts_with("HTTP/1.") {
+            return Err(400);
+        }
+        let method = parts[0];
+        let target = parts[1];
+        let protocol = parts[2];
+
+        let mut param: HashMap<String, String> = HashMap::with_capacity(64);
+        loop {
+            line.clear();
+            match limit.read_until(b'\n', &mut line) {
+                Ok(0) if limit.limit() == 0 => return Err(431),
+                // The connection is closed before the end of the headers
+                Ok(0) => return Err(400),
+                Ok(_) => {},
+                Err(_) => return Err(400),
+            };
+            if !line.ends_with(b"\n") {
+                return Err(if limit.limit() == 0 { 431 } else { 400 });
+            }
+            let header = match std::str::from_utf8(&line) {
+                Ok(s) => s.trim_end(),
+                Err(_) => return Err(400),
+            };
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = match header.split_once(':') {
+                Some((n, v)) => (n.trim(), v.trim()),
+                None => return Err(400),
+            };
+            let key = match name.to_uppercase().replace('-', "_").as_str() {
+                "CONTENT_TYPE" => "CONTENT_TYPE".to_owned(),
+                "CONTENT_LENGTH" => "CONTENT_LENGTH".to_owned(),
+                n => format!("HTTP_{}", n),
+            };
+            // Repeated headers are joined into one
+            match param.get_mut(&key) {
+                Some(v) => {
+                    v.push_str(if key == "HTTP_COOKIE" { "; " } else { ", " });
+                    v.push_str(value);
+                },
+                None => {
+                    param.insert(key, value.to_owned());
+                },
+            };
+        }
+
+        let chunked = match param.get("HTTP_TRANSFER_ENCODING") {
+            Some(t) => t.to_lowercase().contains("chunked"),
+            None => false,
+        };
+        let len = match param.get("CONTENT_LENGTH") {
+            Some(l) => match l.parse::<usize>() {
+                Ok(l) => l,
+                Err(_) => return Err(400),
+            },
+            None => 0,
+        };
+        let keep_alive = match param.get("HTTP_CONNECTION") {
+            Some(c) => {
+                let c = c.to_lowercase();
+                if protocol == "HTTP/1.0" {
+                    c.contains("keep-alive")
+                } else {
+                    !c.contains("close")
+                }
+            },
+            None => protocol != "HTTP/1.0",
+        };
+        let query = match target.split_once('?') {
+            Some((_, q)) => q,
+            None => "",
+        };
+        param.insert("REQUEST_METHOD".to_owned(), method.to_owned());
+        param.insert("REQUEST_URI".to_owned(), target.to_owned());
+        param.insert("REDIRECT_URL".to_owned(), target.to_owned());
+        param.insert("QUERY_STRING".to_owned(), query.to_owned());
+        param.insert("SERVER_PROTOCOL".to_owned(), protocol.to_owned());
+        param.insert("REQUEST_SCHEME".to_owned(), "http".to_owned());
+        param.insert("GATEWAY_INTERFACE".to_owned(), "CGI/1.1".to_owned());
+
+        Ok(Some(Head {
+            param,
+            keep_alive,
+            chunked,
+            len,
+            head: method == "HEAD",
+        }))
+    }
+
+    // Read the body of the request by Content-Length or by chunks
+    fn read_body<R: BufRead>(reader: &mut R, head: &Head, max_body: usize, spool_body: usize) -> Result<Body, u16> {
+        let mut body = Body::None;
+        let mut buf = [0; 65536];
+        if head.chunked {
+            let mut line = Vec::with_capacity(64);
+            loop {
+                line.clear();
+                if reader.take(1024).read_until(b'\n', &mut line).is_err() || !line.ends_with(b"\n") {
+                    return Err(400);
+                }
+                let size = match std::str::from_utf8(&line) {
+                    Ok(s) => match usize::from_str_radix(s.split(';').next().unwrap_or("").trim(), 16) {
+                        Ok(s) => s,
+                        Err(_) => return Err(400),
+                    },
+                    Err(_) => return Err(400),
+                };
+                if size == 0 {
+                    // Skip the trailers
+                    loop {
+                        line.clear();
+                        if reader.take(HTTP_MAX_HEADER_LEN).read_until(b'\n', &mut line).is_err() || !line.ends_with(b"\n") {
+                            return Err(400);
+                        }
+                        if line == b"\r\n" || line == b"\n" {
+                            return Ok(body);
+                        }
+                    }
+                }
+                if max_body > 0 && body.len() + size > max_body {
+                    return Err(413);
+                }
+                Http::read_exact(reader, size, &mut buf, &mut body, spool_body)?;
+                line.clear();
+                if reader.take(2).read_until(b'\n', &mut line).is_err() || !line.ends_with(b"\n") {
+                    return Err(400);
+                }
+            }
+        }
+        Http::read_exact(reader, head.len, &mut buf, &mut body, spool_body)?;
+        Ok(body)
+    }
+
+    // Read exactly len bytes into the body
+    fn read_exact<R: BufRead>(reader: &mut R, mut len: usize, buf: &mut [u8], body: &mut Body, spool_body: usize) -> Result<(), u16> {
+        while len > 0 {
+            let size = len.min(buf.len());
+            if let Err(e) = reader.read_exact(&mut buf[..size]) {
+                return match e.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => Err(408),
+                    _ => Err(400),
+                };
+            }
+            if !body.append(&buf[..size], spool_body) {
+                return Err(500);
+            }
+            len -= size;
+        }
+        Ok(())
+    }
+
+    // Answer 503 when there is no free worker for the connection
+    pub fn overload(mut tcp: TcpStream, retry: u64) {
+        let _ = tcp.write_all(&Http::overload_answer(retry));
+    }
+
+    pub fn overload_answer(retry: u64) -> Vec<u8> {
+        format!("HTTP/1.1 {}\r\nRetry-After: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", Action::http_code_get(503), retry).into_bytes()
+    }
+
+    // Answer with the HTTP status only and close the connection
+    fn write_status(code: u16, tcp: &mut dyn Write) {
+        let answer = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", Action::http_code_get(code));
+        let _ = tcp.write_all(answer.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn head(raw: &str) -> Result<Option<Head>, u16> {
+        Http::read_head(&mut Cursor::new(raw.as_bytes()))
+    }
+
+    fn body(raw: &str, max_body: usize, spool_body: usize) -> Result<Vec<u8>, u16> {
+        let mut reader = Cursor::new(raw.as_bytes());
+        let head = match Http::read_head(&mut reader)? {
+            Some(head) => head,
+            None => return Err(0),
+        };
+        let body = Http::read_body(&mut reader, &head, max_body, spool_body)?;
+        Ok(body.read().map(|b| b.into_owned()).unwrap_or_default())
+    }
+
+    #[test]
+    fn head_params() {
+        let head = head("\r\nGET /news/list?page=2&sort=date HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 10.0.0.1\r\nX-Forwarded-For: 10.0.0.2\r\nCookie: a=1\r\nCookie: b=2\r\nContent-Type: text/plain\r\n\r\n").unwrap().unwrap();
+        assert_eq!(head.param["REQUEST_METHOD"], "GET");
+        assert_eq!(head.param["REQUEST_URI"], "/news/list?page=2&sort=date");
+        assert_eq!(head.param["QUERY_STRING"], "page=2&sort=date");
+        assert_eq!(head.param["SERVER_PROTOCOL"], "HTTP/1.1");
+        assert_eq!(head.param["HTTP_HOST"], "example.com");
+        assert_eq!(head.param["HTTP_X_FORWARDED_FOR"], "10.0.0.1, 10.0.0.2");
+        assert_eq!(head.param["HTTP_COOKIE"], "a=1; b=2");
+        assert_eq!(head.param["CONTENT_TYPE"], "text/plain");
+        assert!(head.keep_alive);
+        assert!(!head.chunked);
+        assert!(!head.head);
+        assert_eq!(head.len, 0);
+    }
+
+    #[test]
+    fn head_keep_alive() {
+        assert!(!head("GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap().unwrap().keep_alive);
+        assert!(!head("GET / HTTP/1.0\r\n\r\n").unwrap().unwrap().keep_alive);
+        assert!(head("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap().unwrap().keep_alive);
+        assert!(head("HEAD / HTTP/1.1\n\n").unwrap().unwrap().head);
+    }
+
+    #[test]
+    fn head_errors() {
+        assert!(matches!(head(""), Ok(None)));
+        assert!(matches!(head("\r\n\r\n"), Ok(None)));
+        assert_eq!(head("GET /\r\n\r\n").err(), Some(400));
+        assert_eq!(head("GET / SPDY/3\r\n\r\n").err(), Some(400));
+        assert_eq!(head("GET / HTTP/1.1\r\nHost\r\n\r\n").err(), Some(400));
+        assert_eq!(head("POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n").err(), Some(400));
+        // The connection is closed before the empty line
+        assert_eq!(head("GET / HTTP/1.1\r\nHost: a").err(), Some(400));
+        assert_eq!(head("GET / HTTP/1.1\r\nHost: a\r\n").err(), Some(400));
+        let long = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(HTTP_MAX_HEADER_LEN as usize));
+        assert_eq!(head(&long).err(), Some(431));
+    }
+
+    #[test]
+    fn stream_head() {
+        let answer = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nhello";
+        // The first part holds the status line, the end of the header is split anyhow
+        for split in 17..answer.len() {
+            let mut out = Vec::new();
+            let mut stream = Stream { tcp: &mut out, used: false, head: true, tail: Vec::new(), skip: false };
+            stream.write_all(&answer[..split]).unwrap();
+            stream.write_all(&answer[split..]).unwrap();
+            stream.write_all(b", world").unwrap();
+            assert_eq!(out, b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: text/plain\r\n\r\n");
+        }
+        let mut out = Vec::new();
+        let mut stream = Stream { tcp: &mut out, used: false, head: false, tail: Vec::new(), skip: false };
+        stream.write_all(&answer[..]).unwrap();
+        stream.write_all(b", world").unwrap();
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: text/plain\r\n\r\nhello, world");
+    }
+
+    #[test]
+    fn body_length() {
+        assert_eq!(body("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello, next request", 0, 0).unwrap(), b"hello");
+        assert_eq!(body("GET / HTTP/1.1\r\n\r\n", 0, 0).unwrap(), b"");
+        // The client has sent less than Content-Length
+        assert_eq!(body("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello", 0, 0).err(), Some(400));
+    }
+
+    #[test]
+    fn body_chunked() {
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\nA\r\n, world!!!\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        assert_eq!(body(raw, 0, 0).unwrap(), b"hello, world!!!");
+        // The body is spooled to the temp file and read back the same
+        assert_eq!(body(raw, 0, 8).unwrap(), b"hello, world!!!");
+        assert_eq!(body(raw, 10, 0).err(), Some(413));
+        assert_eq!(body("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nhello\r\n0\r\n\r\n", 0, 0).err(), Some(400));
+        assert_eq!(body("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel", 0, 0).err(), Some(400));
+    }
+}
@@ -3,36 +3,84 @@ use serde_json::Value;
 
 use crate::work::db::DBConfig;
 
-use super::log::Log;
+use super::{log::Log, socket::SocketConfig, accept::Accept};
+
+// Limit of max_workers. The worker threads have the u8 ids, the async mode has no threads of its own for the controllers.
+#[cfg(not(feature = "async"))]
+pub const MAX_WORKERS: usize = u8::MAX as usize;
+#[cfg(feature = "async")]
+pub const MAX_WORKERS: usize = u16::MAX as usize;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub version: String,
     pub max: u8,
-    pub bind_accept: IpAddr,
+    pub bind_accept: Accept,
     pub bind_port: u16,
     pub bind_ip: IpAddr,
     pub rpc_port: u16,
     pub rpc_ip: IpAddr,
-    pub rpc_accept: IpAddr,
+    pub rpc_accept: Accept,
     pub zone: String,
     pub salt: String,
     pub lang_id: u64,
     pub db: DBConfig,
+    pub max_body: usize,            // Maximum size of the request body, 0 - unlimited
+    pub spool_body: usize,          // The larger request body is saved to the temp file, 0 - never
+    pub max_file: usize,            // Maximum size of the one uploaded file, 0 - unlimited
+    pub max_files: usize,           // Maximum number of the uploaded files in the request, 0 - unlimited
+    pub max_form: usize,            // Maximum size of the form data parsed in memory, 0 - unlimited
+    pub http_port: Option<u16>,     // Port of the built-in HTTP server, None - disabled
+    pub socket: Option<SocketConfig>,   // FastCGI listens on the Unix socket instead of ip:port
+    pub queue: usize,               // Maximum number of connections waiting for the free worker
+    pub queue_timeout: u64,         // Maximum time in the queue in milliseconds, 0 - unlimited
+    pub min_workers: usize,         // Workers that are never retired
+    pub max_workers: usize,         // The pool doesn't grow over this number
+    pub idle_timeout: u64,          // The idle worker over min_workers is retired after this time in seconds, 0 - never
+    pub request_timeout: u64,       // Deadline of the request in seconds, then 504 is answered, 0 - unlimited
+    pub drain_timeout: u64,         // On stop and restart the open connections are waited for so many seconds
+    pub watch: bool,                // Templates and translations are reloaded, when the files in app/ are changed
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum Mode {
     Start,
     Stop,
+    Restart,
     Help,
     Go,
+    Status,
+    Reload,
+    ReloadRoutes,
+    CacheClear,
+    LogLevel,
+    Workers,
+}
+
+impl Mode {
+    // Name of the command on the rpc port
+    pub fn command(&self) -> &'static str {
+        match self {
+            Mode::Start => "start",
+            Mode::Stop => "stop",
+            Mode::Restart => "restart",
+            Mode::Help => "help",
+            Mode::Go => "go",
+            Mode::Status => "status",
+            Mode::Reload => "reload",
+            Mode::ReloadRoutes => "reload-routes",
+            Mode::CacheClear => "cache-clear",
+            Mode::LogLevel => "log-level",
+            Mode::Workers => "workers",
+        }
+    }
 }
 
 
 #[derive(Debug)]
 pub struct Init {
     pub mode: Mode,
+    pub value: Option<String>,      // Value of the command: level for "log-level", number for "workers"
     pub conf: Config,
     pub exe_file: String,
     pub exe_path: String,
@@ -65,6 +113,7 @@ impl Init {
             },
         };
         let mut mode = Mode::Help;
+        let mut value = None;
         let mut conf_found = false;
         let mut conf = None;
         let mut args = env::args();
@@ -97,7 +146,22 @@ impl Init {
                 },
                 "start" => mode = Mode::Start,
                 "stop" => mode = Mode::Stop,
+                "restart" => mode = Mode::Restart,
                 "go" => mode = Mode::Go,
+                "status" => mode = Mode::Status,
+                "reload" => mode = Mode::Reload,
+                "reload-routes" => mode = Mode::ReloadRoutes,
+                "cache-clear" => mode = Mode::CacheClear,
+                "log-level" | "workers" => {
+                    mode = if arg == "log-level" { Mode::LogLevel } else { Mode::Workers };
+                    value = match args.next() {
+                        Some(v) if v != "-r" => Some(v),
+                        _ => {
+                            Log::push_stop(log, 17, Some(arg));
+                            return None;
+                        },
+                    };
+                },
                 _ => {},
             },
         };
@@ -195,6 +259,7 @@ impl Init {
 
         Some(Init {
             mode,
+            value,
             conf,
             exe_file,
             exe_path,
@@ -299,18 +364,35 @@ impl Init {
                     },
                 };
                 let bind_accept = match json.get("accept") {
-                    Some(v) => match v.as_str() {
-                        Some(s) => match IpAddr::from_str(s) {
-                            Ok(i) => i,
+                    Some(v) => {
+                        // One address or the list of addresses and networks
+                        let list: Vec<&str> = match v {
+                            Value::String(s) => vec![s.as_str()],
+                            Value::Array(a) => {
+                                let mut list = Vec::with_capacity(a.len());
+                                for i in a {
+                                    match i.as_str() {
+                                        Some(s) => list.push(s),
+                                        None => {
+                                            Log::push_stop(log, 92, None);
+                                            return None;
+                                        },
+                                    };
+                                }
+                                list
+                            },
+                            _ => {
+                                Log::push_stop(log, 92, None);
+                                return None;
+                            },
+                        };
+                        match Accept::new(&list) {
+                            Ok(a) => a,
                             Err(e) => {
-                                Log::push_stop(log, 93, Some(format!("ip in file={}. Error: {}", s, e.to_string())));
+                                Log::push_stop(log, 93, Some(e));
                                 return None;
                             },
-                        },
-                        None => {
-                            Log::push_stop(log, 92, None);
-                            return None;
-                        },
+                        }
                     },
                     None => {
                         Log::push_stop(log, 91, None);
@@ -337,18 +419,35 @@ impl Init {
                     },
                 };
                 let rpc_accept = match json.get("rpc_accept") {
-                    Some(v) => match v.as_str() {
-                        Some(s) => match IpAddr::from_str(s) {
-                            Ok(i) => i,
+                    Some(v) => {
+                        // One address or the list of addresses and networks
+                        let list: Vec<&str> = match v {
+                            Value::String(s) => vec![s.as_str()],
+                            Value::Array(a) => {
+                                let mut list = Vec::with_capacity(a.len());
+                                for i in a {
+                                    match i.as_str() {
+                                        Some(s) => list.push(s),
+                                        None => {
+                                            Log::push_stop(log, 89, None);
+                                            return None;
+                                        },
+                                    };
+                                }
+                                list
+                            },
+                            _ => {
+                                Log::push_stop(log, 89, None);
+                                return None;
+                            },
+                        };
+                        match Accept::new(&list) {
+                            Ok(a) => a,
                             Err(e) => {
-                                Log::push_stop(log, 90, Some(format!("ip in file={}. Error: {}", s, e.to_string())));
+                                Log::push_stop(log, 90, Some(e));
                                 return None;
                             },
-                        },
-                        None => {
-                            Log::push_stop(log, 89, None);
-                            return None;
-                        },
+                        }
                     },
                     None => {
                         Log::push_stop(log, 88, None);
@@ -505,6 +604,260 @@ impl Init {
                         return None;
                     },
                 };
+                let max_body = match json.get("max_body") {
+                    Some(v) => match v.as_u64() {
+                        Some(s) => match usize::try_from(s) {
+                            Ok(m) => m,
+                            Err(e) => {
+                                Log::push_stop(log, 98, Some(e.to_string()));
+                                return None;
+                            },
+                        },
+                        None => {
+                            Log::push_stop(log, 97, None);
+                            return None;
+                        },
+                    },
+                    None => 0,
+                };
+                let spool_body = match json.get("spool_body") {
+                    Some(v) => match v.as_u64() {
+                        Some(s) => match usize::try_from(s) {
+                            Ok(m) => m,
+                            Err(e) => {
+                                Log::push_stop(log, 100, Some(e.to_string()));
+                                return None;
+                            },
+                        },
+                        None => {
+                            Log::push_stop(log, 99, None);
+                            return None;
+                        },
+                    },
+                    None => 1048576,
+                };
+                let max_file = match json.get("max_file") {
+                    Some(v) => match v.as_u64() {
+                        Some(s) => match usize::try_from(s) {
+                            Ok(m) => m,
+                            Err(e) => {
+                                Log::push_stop(log, 122, Some(e.to_string()));
+                                return None;
+                            },
+                        },
+                        None => {
+                            Log::push_stop(log, 121, None);
+                            return None;
+                        },
+                    },
+                    None => 0,
+                };
+                let max_files = match json.get("max_files") {
+                    Some(v) => match v.as_u64() {
+                        Some(s) => match usize::try_from(s) {
+                            Ok(m) => m,
+                            Err(e) => {
+                                Log::push_stop(log, 124, Some(e.to_string()));
+                                return None;
+                            },
+                        },
+                        None => {
+                            Log::push_stop(log, 123, None);
+                            return None;
+                        },
+                    },
+                    None => 20,
+                };
+                let max_form = match json.get("max_form") {
+                    Some(v) => match v.as_u64() {
+                        Some(s) => match usize::try_from(s) {
+                            Ok(m) => m,
+                            Err(e) => {
+                                Log::push_stop(log, 127, Some(e.to_string()));
+                                return None;
+                            },
+                        },
+                        None => {
+                            Log::push_stop(log, 126, None);
+                            return None;
+                        },
+                    },
+                    None => 2097152,
+                };
+                let http_port = match json.get("http_port") {
+                    Some(v) => match v.as_i64() {
+                        Some(s) => match u16::try_from(s) {
+                            Ok(m) => Some(m),
+                            Err(e) => {
+                                Log::push_stop(log, 102, Some(e.to_string()));
+                                return None;
+                            },
+                        },
+                        None => {
+                            Log::push_stop(log, 101, None);
+                            return None;
+                        },
+                    },
+                    None => None,
+                };
+                let socket = match json.get("socket") {
+                    Some(v) => match v.as_object() {
+                        Some(socket) => {
+                            let path = match socket.get("path") {
+                                Some(v) => match v.as_str() {
+                                    Some(s) => s.to_owned(),
+                                    None => {
+                                        Log::push_stop(log, 105, None);
+                                        return None;
+                                    },
+                                },
+                                None => {
+                                    Log::push_stop(log, 104, None);
+                                    return None;
+                                },
+                            };
+                            let mode = match socket.get("mode") {
+                                Some(v) => match v.as_str() {
+                                    Some(s) => match u32::from_str_radix(s, 8) {
+                                        Ok(m) => Some(m),
+                                        Err(e) => {
+                                            Log::push_stop(log, 107, Some(e.to_string()));
+                                            return None;
+                                        },
+                                    },
+                                    None => {
+                                        Log::push_stop(log, 106, None);
+                                        return None;
+                                    },
+                                },
+                                None => None,
+                            };
+                            let owner = match socket.get("owner") {
+                                Some(v) => match v.as_str() {
+                                    Some(s) => Some(s.to_owned()),
+                                    None => {
+                                        Log::push_stop(log, 108, None);
+                                        return None;
+                                    },
+                                },
+                                None => None,
+                            };
+                            Some(SocketConfig { path, mode, owner })
+                        },
+                        None => {
+                            Log::push_stop(log, 103, None);
+                            return None;
+                        },
+                    },
+                    None => None,
+                };
+                let queue = match json.get("queue") {
+                    Some(v) => match v.as_u64() {
+                        Some(s) => match usize::try_from(s) {
+                            // The worker threads need the queue to get the connections
+                            Ok(0) => {
+                                Log::push_stop(log, 125, None);
+                                return None;
+                            },
+                            Ok(m) => m,
+                            Err(e) => {
+                                Log::push_stop(log, 110, Some(e.to_string()));
+                                return None;
+                            },
+                        },
+                        None => {
+                            Log::push_stop(log, 109, None);
+                            return None;
+                        },
+                    },
+                    None => 1024,
+                };
+                let queue_timeout = match json.get("queue_timeout") {
+                    Some(v) => match v.as_u64() {
+                        Some(s) => s,
+                        None => {
+                            Log::push_stop(log, 111, None);
+                            return None;
+                        },
+                    },
+                    None => 5000,
+                };
+                let max_workers = match json.get("max_workers") {
+                    Some(v) => match v.as_i64() {
+                        Some(s) => match usize::try_from(s) {
+                            Ok(m) if m <= MAX_WORKERS => m,
+                            _ => {
+                                Log::push_stop(log, 115, Some(s.to_string()));
+                                return None;
+                            },
+                        },
+                        None => {
+                            Log::push_stop(log, 114, None);
+                            return None;
+                        },
+                    },
+                    None => usize::from(max),
+                };
+                let min_workers = match json.get("min_workers") {
+                    Some(v) => match v.as_i64() {
+                        Some(s) => match usize::try_from(s) {
+                            Ok(m) if m <= MAX_WORKERS => m,
+                            _ => {
+                                Log::push_stop(log, 113, Some(s.to_string()));
+                                return None;
+                            },
+                        },
+                        None => {
+                            Log::push_stop(log, 112, None);
+                            return None;
+                        },
+                    },
+                    None => max_workers,
+                };
+                if max_workers == 0 || min_workers > max_workers {
+                    Log::push_stop(log, 117, Some(format!("min_workers={} max_workers={}", min_workers, max_workers)));
+                    return None;
+                }
+                let idle_timeout = match json.get("idle_timeout") {
+                    Some(v) => match v.as_u64() {
+                        Some(s) => s,
+                        None => {
+                            Log::push_stop(log, 116, None);
+                            return None;
+                        },
+                    },
+                    None => 60,
+                };
+                let request_timeout = match json.get("request_timeout") {
+                    Some(v) => match v.as_u64() {
+                        Some(s) => s,
+                        None => {
+                            Log::push_stop(log, 118, None);
+                            return None;
+                        },
+                    },
+                    None => 0,
+                };
+                let drain_timeout = match json.get("drain_timeout") {
+                    Some(v) => match v.as_u64() {
+                        Some(s) => s,
+                        None => {
+                            Log::push_stop(log, 119, None);
+                            return None;
+                        },
+                    },
+                    None => 30,
+                };
+                let watch = match json.get("watch") {
+                    Some(v) => match v.as_bool() {
+                        Some(w) => w,
+                        None => {
+                            Log::push_stop(log, 120, None);
+                            return None;
+                        },
+                    },
+                    None => false,
+                };
                 Some(Config {
                     version,
                     max,
@@ -518,6 +871,21 @@ impl Init {
                     salt,
                     lang_id,
                     db,
+                    max_body,
+                    spool_body,
+                    max_file,
+                    max_files,
+                    max_form,
+                    http_port,
+                    socket,
+                    queue,
+                    queue_timeout,
+                    min_workers,
+                    max_workers,
+                    idle_timeout,
+                    request_timeout,
+                    drain_timeout,
+                    watch,
                 })
             },
             Err(e) => {
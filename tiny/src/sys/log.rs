@@ -1,13 +1,18 @@
-use std::{thread::{JoinHandle, self}, sync::{Mutex, Arc, mpsc}, path::PathBuf, process, fs::OpenOptions, io::Write};
+use std::{thread::{JoinHandle, self}, sync::{Mutex, Arc, mpsc}, path::PathBuf, process, fs::OpenOptions, io::Write, cell::RefCell};
 
 use chrono::Local;
 
+thread_local! {
+    // Id of the request, which this thread serves now. It's added to all messages of the request.
+    static REQUEST: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
 enum LogEvent {
     Exit,
     Add(LogText),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 enum LogView{
     Info,       // Informational message only
     Warning,    // Warning, the program may continue to run
@@ -21,6 +26,7 @@ pub struct LogText {
     view: LogView,
     number: u16,
     text: Option<String>,
+    request: Option<String>,            // Id of the request, which has written the message
 }
 
 #[derive(Debug)]
@@ -29,6 +35,7 @@ pub struct Log {
     thread: Option<JoinHandle<()>>,
     pid: u32,
     sender: Arc<Mutex<mpsc::Sender<LogEvent>>>,
+    level: LogView,                     // Messages of the lower level are not saved
 }
 
 impl Log {
@@ -40,6 +47,7 @@ impl Log {
             thread: None,
             pid: process::id(),
             sender: Arc::new(Mutex::new(sender)),
+            level: LogView::Info,
         }));
         
         let log_thread = Arc::clone(&log);
@@ -68,7 +76,10 @@ impl Log {
 
     fn save(&self, log: LogText) {
         let time = Local::now().format("%Y.%m.%d %H:%M:%S%.9f").to_string();
-        let str = format!("ID:{} {} {:?}: {}\n", self.pid, time, log.view, Log::get_description(log.number, log.text));
+        let str = match log.request {
+            Some(id) => format!("ID:{} {} {:?}: request_id={} {}\n", self.pid, time, log.view, id, Log::get_description(log.number, log.text)),
+            None => format!("ID:{} {} {:?}: {}\n", self.pid, time, log.view, Log::get_description(log.number, log.text)),
+        };
         match OpenOptions::new().create(true).write(true).append(true).open(&self.file) {
             Ok(mut file) => match file.write_all(str.as_bytes()) {
                 Ok(f) => f,
@@ -123,7 +134,7 @@ impl Log {
     pub fn push_error(log: Arc<Mutex<Log>>, number: u16, text: Option<String>) -> ! {
         match Mutex::lock(&log) {
             Ok(g) => {
-                g.save( LogText { view: LogView::Error, number, text});
+                g.save( LogText { view: LogView::Error, number, text, request: Log::get_request() });
                 process::exit(1);
             },
             Err(e) => Log::panic(e.to_string()),
@@ -137,7 +148,9 @@ impl Log {
 
     fn push(log: Arc<Mutex<Log>>, view: LogView, number: u16, text: Option<String>) {
         match Mutex::lock(&log) {
-            Ok(mut l) => l.push_str(view, number, text),
+            Ok(mut l) => if view >= l.level {
+                l.push_str(view, number, text);
+            },
             Err(e) => {
                 Log::panic(e.to_string());
             },
@@ -146,7 +159,7 @@ impl Log {
 
     fn push_str(&mut self, view: LogView, number: u16, text: Option<String>) {
         match Mutex::lock(&self.sender) {
-            Ok(s) => if let Err(e) = s.send(LogEvent::Add(LogText {view, number, text, })) {
+            Ok(s) => if let Err(e) = s.send(LogEvent::Add(LogText {view, number, text, request: Log::get_request() })) {
                 Log::panic(e.to_string())
             },
             Err(e) => Log::panic(e.to_string()),
@@ -170,6 +183,37 @@ impl Log {
         }
     }
 
+    // Set the id of the request, which the current thread serves. None - the request is finished.
+    pub fn set_request(id: Option<String>) {
+        REQUEST.with(|r| *r.borrow_mut() = id);
+    }
+
+    fn get_request() -> Option<String> {
+        REQUEST.with(|r| r.borrow().clone())
+    }
+
+    // Set the lowest level of the saved messages: "info", "warning" or "stop"
+    pub fn set_level(log: Arc<Mutex<Log>>, level: &str) -> bool {
+        let level = match level {
+            "info" => LogView::Info,
+            "warning" => LogView::Warning,
+            "stop" => LogView::Stop,
+            _ => return false,
+        };
+        match Mutex::lock(&log) {
+            Ok(mut g) => g.level = level,
+            Err(e) => Log::panic(e.to_string()),
+        };
+        true
+    }
+
+    pub fn get_level(log: Arc<Mutex<Log>>) -> String {
+        match Mutex::lock(&log) {
+            Ok(g) => format!("{:?}", g.level).to_lowercase(),
+            Err(e) => Log::panic(e.to_string()),
+        }
+    }
+
     fn panic(text: String) -> ! {
         let time = Local::now().format("%Y.%m.%d %H:%M:%S%.9f").to_string();
         let str = format!("{} {:?}: {}\n", time, LogView::Critical, Log::get_description(0, Some(text)));
@@ -199,6 +243,7 @@ impl Log {
             14 => "Can't read the config file",
             15 => "The config file is not found",
             16 => "Can't detect the app path",
+            17 => "The command requires the value",
 
             50 => "Error parsing the config file",
             51 => "The option \"log\" in the config file is required",
@@ -239,14 +284,45 @@ impl Log {
             86 => "The option \"pwd\" in the object \"db\" in the config file is required",
             87 => "The option \"pwd\" in the object \"db\" in the config file must be a string",
             88 => "The option \"rpc_accept\" in the config file is required",
-            89 => "The option \"rpc_accept\" in the config file must be a number",
-            90 => "The option \"rpc_accept\" in the config file must be a u16",
-            91 => "The option \"accept\" in the config file must be a u16",
-            92 => "The option \"accept\" in the config file must be a u16",
-            93 => "The option \"accept\" in the config file must be a u16",
+            89 => "The option \"rpc_accept\" in the config file must be a string or an array of strings",
+            90 => "The option \"rpc_accept\" in the config file must contain IP addresses or CIDR networks",
+            91 => "The option \"accept\" in the config file is required",
+            92 => "The option \"accept\" in the config file must be a string or an array of strings",
+            93 => "The option \"accept\" in the config file must contain IP addresses or CIDR networks",
             94 => "The option \"lang\" in the config file is required",
             95 => "The option \"lang\" in the config file must be a number",
             96 => "The option \"lang\" in the config file must be a u8",
+            97 => "The option \"max_body\" in the config file must be a number",
+            98 => "The option \"max_body\" in the config file must be a usize",
+            99 => "The option \"spool_body\" in the config file must be a number",
+            100 => "The option \"spool_body\" in the config file must be a usize",
+            101 => "The option \"http_port\" in the config file must be a number",
+            102 => "The option \"http_port\" in the config file must be a u16",
+            103 => "The option \"socket\" in the config file must be an object",
+            104 => "The option \"path\" in the object \"socket\" in the config file is required",
+            105 => "The option \"path\" in the object \"socket\" in the config file must be a string",
+            106 => "The option \"mode\" in the object \"socket\" in the config file must be a string",
+            107 => "The option \"mode\" in the object \"socket\" in the config file must be an octal number",
+            108 => "The option \"owner\" in the object \"socket\" in the config file must be a string",
+            109 => "The option \"queue\" in the config file must be a number",
+            110 => "The option \"queue\" in the config file must be a usize",
+            111 => "The option \"queue_timeout\" in the config file must be a number",
+            112 => "The option \"min_workers\" in the config file must be a number",
+            113 => "The option \"min_workers\" in the config file must be from 0 to 255, or to 65535 with the async feature",
+            114 => "The option \"max_workers\" in the config file must be a number",
+            115 => "The option \"max_workers\" in the config file must be from 0 to 255, or to 65535 with the async feature",
+            116 => "The option \"idle_timeout\" in the config file must be a number",
+            117 => "The option \"max_workers\" in the config file must be greater than 0 and not less than \"min_workers\"",
+            118 => "The option \"request_timeout\" in the config file must be a number",
+            119 => "The option \"drain_timeout\" in the config file must be a number",
+            120 => "The option \"watch\" in the config file must be a boolean",
+            121 => "The option \"max_file\" in the config file must be a number",
+            122 => "The option \"max_file\" in the config file must be a usize",
+            123 => "The option \"max_files\" in the config file must be a number",
+            124 => "The option \"max_files\" in the config file must be a usize",
+            125 => "The option \"queue\" in the config file must be greater than 0",
+            126 => "The option \"max_form\" in the config file must be a number",
+            127 => "The option \"max_form\" in the config file must be a usize",
 
             200 => "Start",
             201 => "Stop",
@@ -262,13 +338,29 @@ impl Log {
             211 => "The app start succesful",
             212 => "Can't start the app",
             213 => "Can't connect to the server",
-            214 => "Can't send 'stop' signal to the server",
+            214 => "Can't send the command to the server",
             215 => "'Stop' signal sent successfully",
-            216 => "Can't write 'stop' signal to the stream",
+            216 => "Can't write the answer to the rpc stream",
             217 => "Can't set read_timeout",
             218 => "Can't read signal from stream",
             219 => "Read data is very short",
             220 => "Read wrong data",
+            221 => "'Restart' signal sent successfully",
+            222 => "The server can't restart",
+            223 => "Restart command was received on the rpc port",
+            224 => "The server has rejected the command",
+            225 => "The command was received on the rpc port",
+            226 => "Templates, translations, routes and redirects are reloaded",
+            227 => "Can't reload, the old data is kept",
+            228 => "Can't create the nonce for the rpc handshake",
+            229 => "The signature of the rpc command is wrong",
+            230 => "Can't read the nonce from the server",
+            231 => "Files in app/ are changed, templates and translations are reloaded",
+            232 => "SIGHUP was received",
+            233 => "Can't set the signal handlers",
+            234 => "Routes and redirects are reloaded",
+            235 => "The signal to stop was received",
+            236 => "SIGUSR1 was received, the log file is reopened",
 
             500 => "Unable to open fastcgi server port",
             501 => "Can't join main process",
@@ -283,12 +375,34 @@ impl Log {
             510 => "Can't send run signal into worker process",
             511 => "Can't receive run signal",
             512 => "Can't send terminate signal",
+            513 => "Can't clone the FastCGI stream",
+            514 => "Can't join the FastCGI reader thread",
+            515 => "Can't save the request body to the temp file",
+            516 => "Unable to open http server port",
+            517 => "Can't join http process",
+            518 => "Can't set read timeout on http stream",
+            519 => "Unable to open fastcgi unix socket",
+            520 => "Can't remove the unix socket file",
+            521 => "The queue of the incoming connections is full, answered 503",
+            522 => "The connection has waited for the free worker too long, answered 503",
+            523 => "Unable to start the async runtime",
+            524 => "Can't register the listener in the async runtime",
+            525 => "The controller task has failed",
+            526 => "Can't start the new server process",
+            527 => "The new server process is ready, the old one is draining",
+            528 => "The drain_timeout has passed, the open connections are dropped",
 
             600 => "Can't create tlsconnector to database",
             601 => "Can't connect to database",
             602 => "Can't execute query",
             603 => "Can't init database",
             604 => "Can't prepare statement #1",
+            605 => "Can't cancel the database query",
+            606 => "Listening to the \"tiny_reload\" channel of the database",
+            607 => "The database has notified on the \"tiny_reload\" channel, routes and redirects are reloaded",
+            608 => "Can't listen to the \"tiny_reload\" channel of the database",
+            609 => "Can't load routes and redirects, the old ones are kept",
+            610 => "Can't set application_name of the request",
 
             700 => "Error in mpsc::Receiver",
             701 => "Can't send stop signal to the workers",
@@ -297,10 +411,23 @@ impl Log {
             704 => "Threads are inconsistent",
             705 => "Can't send work signal to the workers",
             706 => "Sender is disconected",
+            707 => "The worker pool has grown",
+            708 => "The worker pool has shrunk",
+            709 => "The request has exceeded request_timeout, answered 504",
+            710 => "The worker is stuck on the expired request, replaced",
 
             1020 => "Can't delete input file",
+            1021 => "Panic",
+            1022 => "The controller has panicked, answered 500",
+            1023 => "The request body isn't valid JSON",
+            1024 => "Can't serialize the answer to JSON",
+            1025 => "The uploaded file is larger than max_file, it is skipped",
+            1026 => "The request has more files than max_files, the rest are skipped",
+            1027 => "The form data is larger than max_form, it is skipped",
+            1028 => "The cookie has the invalid name, value or attribute, it isn't sent",
 
             1100 => "Can't open root_dir/app",
+            1101 => "The template has an error, the old one is kept",
 
             1150 => "Can't load languages from database",
             1151 => "Language list is empty",
@@ -311,6 +438,8 @@ impl Log {
             1201 => "Unable to specify \"if\" node type",
             1202 => "Unable to specify \"loop\" node type",
 
+            1300 => "Can't read the static file",
+
             _ => "Unknown error"
         }
     }
@@ -0,0 +1,116 @@
+use std::{collections::HashMap, env, fs::File, io::{self, Read, Write}, net::{SocketAddr, TcpListener}, os::unix::{io::{AsRawFd, FromRawFd, RawFd}, net::UnixListener, process::CommandExt}, path::Path, process::Command, time::Duration};
+
+use super::socket::{self, Listener};
+
+// Listening sockets of the old process, like "fastcgi=3,rpc=4,http=5"
+const RESTART_LISTEN_FDS: &str = "TINY_LISTEN_FDS";
+// Pipe, the new process writes "ready" into it, when it serves the connections
+const RESTART_READY_FD: &str = "TINY_READY_FD";
+// The new process must be ready in this time, otherwise it's killed and the old one keeps working
+pub const RESTART_READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Listening sockets, which the new process got from the old one on restart
+pub struct Restart {
+    fds: HashMap<String, RawFd>,
+    ready: Option<RawFd>,
+}
+
+impl Restart {
+
+    // Take the sockets from the environment. They are removed from it, so the next restart doesn't see them.
+    pub fn inherit() -> Restart {
+        let mut fds = HashMap::new();
+        if let Ok(list) = env::var(RESTART_LISTEN_FDS) {
+            for item in list.split(',') {
+                if let Some((name, fd)) = item.split_once('=') {
+                    if let Ok(fd) = fd.parse::<RawFd>() {
+                        fds.insert(name.to_owned(), fd);
+                    }
+                }
+            }
+        }
+        let ready = match env::var(RESTART_READY_FD) {
+            Ok(fd) => fd.parse::<RawFd>().ok(),
+            Err(_) => None,
+        };
+        env::remove_var(RESTART_LISTEN_FDS);
+        env::remove_var(RESTART_READY_FD);
+        Restart { fds, ready }
+    }
+
+    // The inherited TCP socket, if it listens on the same address. Otherwise it's closed and the new one is bound.
+    pub fn tcp(&mut self, name: &str, addr: SocketAddr) -> Option<TcpListener> {
+        let fd = self.fds.remove(name)?;
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        match listener.local_addr() {
+            Ok(a) if a == addr => Some(listener),
+            _ => None,
+        }
+    }
+
+    // The inherited Unix socket, if it listens on the same path
+    pub fn unix(&mut self, name: &str, path: &str) -> Option<Listener> {
+        let fd = self.fds.remove(name)?;
+        let listener = unsafe { UnixListener::from_raw_fd(fd) };
+        match listener.local_addr() {
+            Ok(a) if a.as_pathname() == Some(Path::new(path)) => Some(Listener::Unix(listener)),
+            _ => None,
+        }
+    }
+
+    // The new process serves the connections, so the old one can drain
+    pub fn ready(self) {
+        for fd in self.fds.into_values() {
+            unsafe { libc::close(fd) };
+        }
+        if let Some(fd) = self.ready {
+            let mut pipe = unsafe { File::from_raw_fd(fd) };
+            let _ = pipe.write_all(b"ready");
+        }
+    }
+
+    // Start the new process with the listening sockets and wait until it is ready. Returns its pid.
+    pub fn spawn(exe: &str, path: &str, root: &str, fds: &[(&str, RawFd)]) -> Result<u32, String> {
+        let mut pipe: [libc::c_int; 2] = [0; 2];
+        if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(format!("Can't create the pipe. Error: {}", io::Error::last_os_error()));
+        }
+        let (mut read, write) = unsafe { (File::from_raw_fd(pipe[0]), File::from_raw_fd(pipe[1])) };
+
+        let list: Vec<String> = fds.iter().map(|(name, fd)| format!("{}={}", name, fd)).collect();
+        let mut keep: Vec<RawFd> = fds.iter().map(|(_, fd)| *fd).collect();
+        keep.push(write.as_raw_fd());
+        let args = ["go", "-r", root];
+        let mut command = Command::new(exe);
+        command.args(args).current_dir(path).env(RESTART_LISTEN_FDS, list.join(",")).env(RESTART_READY_FD, write.as_raw_fd().to_string());
+        // The sockets are opened with FD_CLOEXEC, so it's cleared for the new process only
+        unsafe {
+            command.pre_exec(move || {
+                for fd in &keep {
+                    if libc::fcntl(*fd, libc::F_SETFD, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        let mut child = match command.spawn() {
+            Ok(c) => c,
+            Err(e) => return Err(format!("{} {}. Error: {}", exe, args.join(" "), e)),
+        };
+        // The pipe is closed, when the new process exits without the "ready"
+        drop(write);
+
+        let mut buf = [0; 5];
+        let ready = match socket::wait_read(read.as_raw_fd(), RESTART_READY_TIMEOUT) {
+            Ok(true) => matches!(read.read(&mut buf), Ok(5)) && &buf == b"ready",
+            _ => false,
+        };
+        if ready {
+            return Ok(child.id());
+        }
+        let _ = child.kill();
+        let _ = child.wait();
+        Err(format!("PID {} is not ready", child.id()))
+    }
+}
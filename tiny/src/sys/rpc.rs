@@ -0,0 +1,323 @@
+use std::{fs::read_dir, io::{self, BufRead, BufReader, Read}, net::TcpStream, path::{Path, PathBuf}, process, sync::{Arc, Mutex, RwLock, atomic::{AtomicUsize, Ordering}}, thread, time::{Duration, Instant, SystemTime}};
+
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::work::{cache::Cache, db::{DB, DBConfig}, html::Html, lang::Lang};
+
+use super::{init::{Mode, MAX_WORKERS}, log::Log};
+
+// Counters of the server for the "status" command and the limit, which "workers" changes
+pub struct Control {
+    start: Instant,
+    pub workers: AtomicUsize,           // Running workers, database connections in the async runtime
+    pub busy: AtomicUsize,              // Workers, which serve the connections now
+    pub queue: AtomicUsize,             // Connections waiting for the free worker
+    pub max_workers: AtomicUsize,       // The pool doesn't grow over this number
+}
+
+impl Control {
+    pub fn new(max_workers: usize) -> Arc<Control> {
+        Arc::new(Control {
+            start: Instant::now(),
+            workers: AtomicUsize::new(0),
+            busy: AtomicUsize::new(0),
+            queue: AtomicUsize::new(0),
+            max_workers: AtomicUsize::new(max_workers),
+        })
+    }
+}
+
+// Random bytes, which the server sends before the command
+const RPC_NONCE_LEN: usize = 32;
+// Max length of the handshake line
+const RPC_LINE_LEN: u64 = 1024;
+// The watcher checks the files in app/ so often
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Challenge-response of the rpc port. The server sends "<nonce>\n", the client answers "<command> <signature>\n",
+// where the signature is HMAC-SHA256 of the nonce and the command with the key derived from the salt.
+// The salt is never sent, and the signature is valid for one connection only.
+pub struct Handshake;
+
+impl Handshake {
+
+    // New hex nonce for the connection
+    pub fn nonce() -> Result<String, String> {
+        let mut buf = [0; RPC_NONCE_LEN];
+        match getrandom::fill(&mut buf) {
+            Ok(()) => Ok(Handshake::hex(&buf)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    // Hex signature of the command
+    pub fn sign(salt: &str, nonce: &str, command: &str) -> Option<String> {
+        Handshake::mac(salt, nonce, command).map(|m| Handshake::hex(&m.finalize().into_bytes()))
+    }
+
+    // Check the signature in constant time
+    pub fn verify(salt: &str, nonce: &str, command: &str, signature: &str) -> bool {
+        match (Handshake::unhex(signature), Handshake::mac(salt, nonce, command)) {
+            (Some(s), Some(m)) => m.verify_slice(&s).is_ok(),
+            _ => false,
+        }
+    }
+
+    // Read one line without "\n"
+    pub fn read_line(tcp: &TcpStream) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(128);
+        BufReader::new(tcp.take(RPC_LINE_LEN)).read_until(b'\n', &mut buf)?;
+        match buf.pop() {
+            Some(b'\n') => Ok(buf),
+            _ => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the line isn't finished")),
+        }
+    }
+
+    fn mac(salt: &str, nonce: &str, command: &str) -> Option<Hmac<Sha256>> {
+        // The key isn't the salt itself, so the signature doesn't reveal anything about the salt used in other places
+        let key = Sha256::new().chain_update(b"tiny rpc ").chain_update(salt.as_bytes()).finalize();
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).ok()?;
+        mac.update(nonce.as_bytes());
+        mac.update(b"\n");
+        mac.update(command.as_bytes());
+        Some(mac)
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn unhex(hex: &str) -> Option<Vec<u8>> {
+        if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+            return None;
+        }
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+    }
+}
+
+// Commands of the rpc port, except stop and restart
+pub struct Rpc {
+    pub control: Arc<Control>,
+    pub cache: Arc<Mutex<Cache>>,
+    pub html: Arc<RwLock<Arc<Html>>>,
+    pub lang: Arc<RwLock<Arc<Lang>>>,
+    pub log: Arc<Mutex<Log>>,
+    pub root: String,
+    pub db: DBConfig,
+    pub reloading: Mutex<()>,           // The rpc command, SIGHUP and the watcher don't reload at the same time
+}
+
+impl Rpc {
+
+    // Run the command and return the answer for the client. The failed command is answered with "error <text>".
+    pub fn exec(&self, mode: Mode, value: Option<&str>) -> String {
+        match mode {
+            Mode::Status => self.status(),
+            Mode::Reload => self.reload(),
+            Mode::ReloadRoutes => {
+                if DB::reload(&self.db, Arc::clone(&self.log), Arc::clone(&self.cache)) {
+                    Log::push_info(Arc::clone(&self.log), 234, None);
+                    "reload-routes ok".to_owned()
+                } else {
+                    Log::push_warning(Arc::clone(&self.log), 227, Some("routes".to_owned()));
+                    "error Can't load routes from the database, the old ones are kept".to_owned()
+                }
+            },
+            Mode::CacheClear => {
+                // Routes and redirects are kept in the cache, so the new cache is loaded with them before the old one is dropped
+                if DB::reset(&self.db, Arc::clone(&self.log), Arc::clone(&self.cache)) {
+                    "cache-clear ok".to_owned()
+                } else {
+                    "error Can't load routes from the database, the cache is kept".to_owned()
+                }
+            },
+            Mode::LogLevel => match value {
+                Some(level) if Log::set_level(Arc::clone(&self.log), level) => format!("log-level {}", level),
+                _ => "error The level must be info, warning or stop".to_owned(),
+            },
+            Mode::Workers => match value.map(|v| v.parse::<usize>()) {
+                Some(Ok(n)) if n > 0 && n <= MAX_WORKERS => {
+                    self.control.max_workers.store(n, Ordering::Release);
+                    format!("workers {}", n)
+                },
+                _ => format!("error The number of workers must be from 1 to {}", MAX_WORKERS),
+            },
+            _ => "error Unknown command".to_owned(),
+        }
+    }
+
+    fn status(&self) -> String {
+        let workers = self.control.workers.load(Ordering::Acquire);
+        let busy = self.control.busy.load(Ordering::Acquire).min(workers);
+        let mut answer = Vec::with_capacity(8);
+        answer.push(format!("pid: {}", process::id()));
+        answer.push(format!("uptime: {}", self.control.start.elapsed().as_secs()));
+        answer.push(format!("workers: {}", workers));
+        answer.push(format!("busy: {}", busy));
+        answer.push(format!("idle: {}", workers - busy));
+        answer.push(format!("max_workers: {}", self.control.max_workers.load(Ordering::Acquire)));
+        answer.push(format!("queue: {}", self.control.queue.load(Ordering::Acquire)));
+        answer.push(format!("log_level: {}", Log::get_level(Arc::clone(&self.log))));
+        answer.join("\n")
+    }
+
+    // Load templates, translations, routes and redirects again. The failed part keeps the old data.
+    pub fn reload(&self) -> String {
+        let _guard = match Mutex::lock(&self.reloading) {
+            Ok(g) => g,
+            Err(e) => Log::error(Arc::clone(&self.log), e.to_string()),
+        };
+        let mut failed = self.reload_view();
+        if !DB::reload(&self.db, Arc::clone(&self.log), Arc::clone(&self.cache)) {
+            failed.push("routes".to_owned());
+        }
+        if failed.is_empty() {
+            Log::push_info(Arc::clone(&self.log), 226, None);
+            "reload ok".to_owned()
+        } else {
+            Log::push_warning(Arc::clone(&self.log), 227, Some(failed.join(", ")));
+            format!("error Can't reload {}", failed.join(", "))
+        }
+    }
+
+    // Templates and translations are swapped between the requests, the running request keeps the old ones
+    fn reload_view(&self) -> Vec<String> {
+        let mut failed = Vec::new();
+        let old = match RwLock::read(&self.html) {
+            Ok(h) => Arc::clone(&h),
+            Err(e) => Log::error(Arc::clone(&self.log), e.to_string()),
+        };
+        match old.reload(&self.root, Arc::clone(&self.log)) {
+            Some((h, rejected)) => {
+                match RwLock::write(&self.html) {
+                    Ok(mut html) => *html = Arc::new(h),
+                    Err(e) => Log::error(Arc::clone(&self.log), e.to_string()),
+                };
+                if !rejected.is_empty() {
+                    failed.push(format!("templates {}", rejected.join(" ")));
+                }
+            },
+            None => failed.push("templates".to_owned()),
+        };
+        let lang = Lang::new(&self.root, &self.db, Arc::clone(&self.log));
+        if lang.langs.is_empty() {
+            failed.push("translations".to_owned());
+        } else {
+            match RwLock::write(&self.lang) {
+                Ok(mut l) => *l = Arc::new(lang),
+                Err(e) => Log::error(Arc::clone(&self.log), e.to_string()),
+            };
+        }
+        failed
+    }
+
+    // Development mode: templates and translations are reloaded, when the files in app/ are changed
+    pub fn watch(rpc: Arc<Rpc>) {
+        thread::spawn(move || {
+            let path = format!("{}/app", rpc.root);
+            let mut files = Rpc::scan(Path::new(&path));
+            loop {
+                thread::sleep(WATCH_INTERVAL);
+                let list = Rpc::scan(Path::new(&path));
+                if list == files {
+                    continue;
+                }
+                files = list;
+                let _guard = match Mutex::lock(&rpc.reloading) {
+                    Ok(g) => g,
+                    Err(e) => Log::error(Arc::clone(&rpc.log), e.to_string()),
+                };
+                let failed = rpc.reload_view();
+                if failed.is_empty() {
+                    Log::push_info(Arc::clone(&rpc.log), 231, None);
+                } else {
+                    Log::push_warning(Arc::clone(&rpc.log), 227, Some(failed.join(", ")));
+                }
+            }
+        });
+    }
+
+    // Templates and translations in app/module/class/ with the modification time
+    fn scan(path: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+        let mut list = Vec::new();
+        for module in Rpc::entries(path) {
+            for class in Rpc::entries(&module) {
+                for file in Rpc::entries(&class) {
+                    if matches!(file.extension().and_then(|e| e.to_str()), Some("html") | Some("lang")) {
+                        let modified = file.metadata().and_then(|m| m.modified()).ok();
+                        list.push((file, modified));
+                    }
+                }
+            }
+        }
+        list.sort();
+        list
+    }
+
+    fn entries(path: &Path) -> Vec<PathBuf> {
+        match read_dir(path) {
+            Ok(r) => r.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, net::{TcpListener, TcpStream}};
+
+    use super::Handshake;
+
+    const SALT: &str = "secret salt";
+    const NONCE: &str = "0123abcd";
+
+    #[test]
+    fn nonce() {
+        let a = Handshake::nonce().unwrap();
+        let b = Handshake::nonce().unwrap();
+        assert_eq!(a.len(), 64);
+        assert!(a.bytes().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn sign() {
+        // HMAC-SHA256 of "<nonce>\n<command>" with the key SHA256("tiny rpc <salt>")
+        let signature = Handshake::sign(SALT, NONCE, "status").unwrap();
+        assert_eq!(signature, "42c8a1bd83804c54682986ffc30edc5bc1b250c882310430f34785a039051fba");
+        assert!(Handshake::verify(SALT, NONCE, "status", &signature));
+        assert!(Handshake::verify(SALT, NONCE, "status", &signature.to_uppercase()));
+    }
+
+    #[test]
+    fn verify() {
+        let signature = Handshake::sign(SALT, NONCE, "workers 4").unwrap();
+        assert!(!Handshake::verify("other salt", NONCE, "workers 4", &signature));
+        assert!(!Handshake::verify(SALT, "0123abce", "workers 4", &signature));
+        assert!(!Handshake::verify(SALT, NONCE, "workers 40", &signature));
+        assert!(!Handshake::verify(SALT, NONCE, "workers 4", &signature[..62]));
+        assert!(!Handshake::verify(SALT, NONCE, "workers 4", &signature[..63]));
+        assert!(!Handshake::verify(SALT, NONCE, "workers 4", ""));
+        assert!(!Handshake::verify(SALT, NONCE, "workers 4", &format!("{}00", signature)));
+        assert!(!Handshake::verify(SALT, NONCE, "workers 4", &signature.replace(|c: char| c.is_ascii_digit(), "g")));
+        assert!(!Handshake::verify(SALT, NONCE, "workers 4", "ф0"));
+    }
+
+    #[test]
+    fn read_line() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.write_all(b"status 42c8\nrest").unwrap();
+        drop(client);
+        assert_eq!(Handshake::read_line(&server).unwrap(), b"status 42c8");
+
+        // The line isn't finished before the connection is closed
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.write_all(b"status").unwrap();
+        drop(client);
+        assert!(Handshake::read_line(&server).is_err());
+    }
+}
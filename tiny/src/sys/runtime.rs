@@ -0,0 +1,617 @@
+use std::{collections::HashMap, io::{self, ErrorKind, Write}, net::{IpAddr, TcpListener}, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, net, sync::{mpsc::{self, Receiver, Sender, UnboundedSender}, Semaphore, SemaphorePermit}, task};
+
+use crate::work::{action::DataRun, watch::{Output, Watch, WatchStatus}, db::DB, worker::{Worker, WorkerConf}};
+
+use super::{accept::Accept, fastcgi::{Active, FastCGI, Next, ReadStatus, Record, RequestState, Stream, FASTCGI_MAX_REQUEST_LEN}, go::{Go, PoolConf, DRAIN_CHECK_INTERVAL}, http::{Http, HTTP_KEEP_ALIVE}, init::MAX_WORKERS, log::Log, rpc::Control, socket::Listener};
+
+// The pool is checked for the idle database connections so often
+const WORKER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// Writes of the connection waiting for the socket. The controller, which writes faster than the client reads, waits.
+const WRITER_QUEUE_LEN: usize = 32;
+
+// Database connection of the one running controller
+struct Slot {
+    db: DB,
+    abort: Arc<AtomicBool>,             // The client has aborted the request of this controller
+    used: Instant,                      // The last time the connection was returned to the pool
+    watch: Arc<Watch>,                  // Deadline of the request
+}
+
+// Database connections. The pool grows up to max_workers, when all connections are busy,
+// and the idle connections over min_workers are closed after idle_timeout.
+struct Pool {
+    free: Vec<Slot>,                    // Free connections, the last used is the last one
+    len: usize,                         // Number of all connections
+}
+
+// Data shared by all connections
+struct Shared {
+    conf: WorkerConf,
+    limit: PoolConf,
+    accept: Option<Accept>,
+    pool: Mutex<Pool>,
+    permits: Semaphore,                 // Number of the controllers that can be run now
+    connections: AtomicUsize,           // Open connections, they are waited for on stop
+    control: Arc<Control>,              // Counters for the "status" command and max_workers, which "workers" changes
+    log: Arc<Mutex<Log>>,
+}
+
+// The open connection, it's counted until the task ends
+struct Open(Arc<Shared>);
+
+impl Open {
+    fn new(shared: &Arc<Shared>) -> Open {
+        shared.connections.fetch_add(1, Ordering::AcqRel);
+        Open(Arc::clone(shared))
+    }
+}
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// Writer that passes data to the task, which owns the socket. It's used only outside of the runtime:
+// by the controller in the blocking pool and by the watchdog thread.
+struct Channel(Sender<Vec<u8>>);
+
+impl Write for Channel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The empty data closes the connection
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.0.blocking_send(buf.to_vec()).is_err() {
+            return Err(io::Error::from(ErrorKind::BrokenPipe));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Async execution mode (cargo feature "async").
+// Connections are tasks, so idle connections don't hold threads, and the database connections are driven by the runtime.
+// Only the controllers keep the blocking fn(&mut Action) -> Answer signature, so they run in the blocking pool,
+// no more than "max_workers" at once, each with its own database connection. A connection takes the controller for one request only.
+pub struct Runtime {}
+
+impl Runtime {
+
+    pub fn run(bind: Listener, http: Option<TcpListener>, accept: Option<Accept>, stop: Arc<AtomicBool>, control: Arc<Control>, conf: WorkerConf, limit: PoolConf) -> JoinHandle<()> {
+        let log = Arc::clone(&conf.log);
+        let max_workers = control.max_workers.load(Ordering::Acquire);
+        thread::spawn(move || {
+            let rt = match tokio::runtime::Builder::new_multi_thread().enable_all().max_blocking_threads(MAX_WORKERS).build() {
+                Ok(rt) => rt,
+                Err(e) => Log::push_error(log, 523, Some(e.to_string())),
+            };
+            let free = rt.block_on(async {
+                let mut free = Vec::with_capacity(max_workers);
+                for _ in 0..limit.min_workers {
+                    free.push(Runtime::open(&conf, &limit).await);
+                }
+                free
+            });
+            control.workers.store(limit.min_workers, Ordering::Release);
+            let shared = Arc::new(Shared {
+                conf,
+                limit,
+                accept,
+                pool: Mutex::new(Pool { free, len: limit.min_workers }),
+                permits: Semaphore::new(max_workers),
+                connections: AtomicUsize::new(0),
+                control,
+                log: Arc::clone(&log),
+            });
+
+            rt.block_on(async {
+                let mut listeners = Vec::with_capacity(2);
+                match bind {
+                    Listener::Tcp(l) => {
+                        let l = match l.set_nonblocking(true).and_then(|_| net::TcpListener::from_std(l)) {
+                            Ok(l) => l,
+                            Err(e) => Log::push_error(log, 524, Some(e.to_string())),
+                        };
+                        let shared = Arc::clone(&shared);
+                        listeners.push(tokio::spawn(async move {
+                            loop {
+                                match l.accept().await {
+                                    Ok((s, addr)) => {
+                                        tokio::spawn(Runtime::fastcgi(s, Some(addr.ip()), Arc::clone(&shared), Open::new(&shared)));
+                                    },
+                                    Err(e) => Log::push_warning(Arc::clone(&shared.log), 502, Some(e.to_string())),
+                                };
+                            }
+                        }));
+                    },
+                    Listener::Unix(l) => {
+                        let l = match l.set_nonblocking(true).and_then(|_| net::UnixListener::from_std(l)) {
+                            Ok(l) => l,
+                            Err(e) => Log::push_error(log, 524, Some(e.to_string())),
+                        };
+                        let shared = Arc::clone(&shared);
+                        listeners.push(tokio::spawn(async move {
+                            loop {
+                                match l.accept().await {
+                                    Ok((s, _)) => {
+                                        tokio::spawn(Runtime::fastcgi(s, None, Arc::clone(&shared), Open::new(&shared)));
+                                    },
+                                    Err(e) => Log::push_warning(Arc::clone(&shared.log), 502, Some(e.to_string())),
+                                };
+                            }
+                        }));
+                    },
+                };
+                if let Some(l) = http {
+                    let l = match l.set_nonblocking(true).and_then(|_| net::TcpListener::from_std(l)) {
+                        Ok(l) => l,
+                        Err(e) => Log::push_error(log, 524, Some(e.to_string())),
+                    };
+                    let shared = Arc::clone(&shared);
+                    listeners.push(tokio::spawn(async move {
+                        loop {
+                            match l.accept().await {
+                                Ok((s, addr)) => {
+                                    tokio::spawn(Runtime::http(s, addr.ip(), Arc::clone(&shared), Open::new(&shared)));
+                                },
+                                Err(e) => Log::push_warning(Arc::clone(&shared.log), 502, Some(e.to_string())),
+                            };
+                        }
+                    }));
+                }
+
+                tokio::spawn(Runtime::retire(Arc::clone(&shared), max_workers));
+
+                while !stop.load(Ordering::Acquire) {
+                    tokio::time::sleep(DRAIN_CHECK_INTERVAL).await;
+                }
+                // The listeners are closed, the open connections are closed after the current request
+                for l in listeners {
+                    l.abort();
+                    let _ = l.await;
+                }
+                while shared.connections.load(Ordering::Acquire) > 0 {
+                    tokio::time::sleep(DRAIN_CHECK_INTERVAL).await;
+                }
+            });
+            rt.shutdown_timeout(Duration::from_secs(1));
+        })
+    }
+
+    // One FastCGI connection
+    async fn fastcgi<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S, ip: Option<IpAddr>, shared: Arc<Shared>, _open: Open) {
+        let log = Arc::clone(&shared.log);
+        // The rejected peer is closed before anything is read from it
+        if let (Some(a), Some(ip)) = (&shared.accept, ip) {
+            if let Err(rule) = a.check(ip) {
+                Log::push_warning(log, 504, Some(format!("{} {}", ip, rule)));
+                return;
+            }
+        }
+        let (mut rd, wr) = tokio::io::split(stream);
+        let mut buffer = vec![0; FASTCGI_MAX_REQUEST_LEN];
+        let size = match rd.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+
+        let (writer, receiver) = mpsc::channel(WRITER_QUEUE_LEN);
+        let write = tokio::spawn(Runtime::writer(wr, receiver));
+        let (sender, mut records) = mpsc::unbounded_channel();
+        let active = Arc::new(Mutex::new(Active::default()));
+        let read = tokio::spawn(Runtime::reader(rd, buffer, size, sender, writer.clone(), Arc::clone(&active), Arc::clone(&log)));
+        let retry = shared.limit.queue_timeout.as_secs().max(1);
+
+        let mut requests: HashMap<u16, RequestState> = HashMap::new();
+        let mut close = false;
+        // The controller and the watchdog write through the channel, the task itself collects its records here
+        let output = Output::new(Channel(writer.clone()));
+        let mut out = Vec::new();
+        loop {
+            if !Runtime::send(&writer, &mut out).await {
+                break;
+            }
+            let received = if close && requests.is_empty() {
+                // No active requests are left, only the records, which have come already, may begin the new ones
+                Ok(records.try_recv().ok())
+            } else {
+                tokio::time::timeout(DRAIN_CHECK_INTERVAL, records.recv()).await
+            };
+            // On stop the idle connection is closed, the web server opens the new one to the new process
+            let record = match received {
+                Ok(Some(r)) => r,
+                Ok(None) => break,
+                Err(_) => if Go::is_draining() && requests.is_empty() {
+                    break;
+                } else {
+                    continue;
+                },
+            };
+            let request = match FastCGI::next(record, &mut requests, &mut close, shared.conf.max_body, shared.conf.spool_body, &mut out, Arc::clone(&log)) {
+                Next::Continue => continue,
+                Next::Close => break,
+                Next::Run(request) => request,
+            };
+            let RequestState { begin, param, stdin, .. } = request;
+            let request_id = begin.header.request_id;
+
+            let permit = match Runtime::acquire(&shared).await {
+                Some(p) => p,
+                None => {
+                    Log::push_warning(Arc::clone(&log), 522, None);
+                    if !FastCGI::write_overload(&begin.header, retry, &mut out) {
+                        break;
+                    }
+                    FastCGI::done(&begin, &mut close);
+                    continue;
+                },
+            };
+            let slot = Runtime::take(&shared).await;
+            if !Active::start(&active, request_id, Arc::clone(&slot.abort), Arc::clone(&log)) {
+                // The web server has aborted the request before the controller started
+                Runtime::give(&shared, slot);
+                drop(permit);
+                FastCGI::write_abort(&begin.header, &mut out);
+                FastCGI::done(&begin, &mut close);
+                continue;
+            }
+            let (number, mut controller) = output.start();
+            let watch = Arc::clone(&slot.watch);
+            if watch.is_enabled() {
+                // The watchdog ends only this request with 504, when the deadline has passed
+                let watchdog = output.clone();
+                let header = begin.header.clone();
+                watch.start(Box::new(move || {
+                    watchdog.expire(number, |channel, written| {
+                        // The started answer can't be replaced, it is just ended
+                        if written {
+                            FastCGI::write_abort(&header, channel);
+                        } else {
+                            FastCGI::write_status(&header, 504, channel);
+                        }
+                    });
+                }), slot.db.cancel_token(), Arc::clone(&slot.abort));
+            }
+            let run_shared = Arc::clone(&shared);
+            let mut handle = task::spawn_blocking(move || {
+                let mut slot = slot;
+                let mut stream = Stream { tcp: &mut controller, request_id };
+                let answer = Runtime::execute(&run_shared, &mut slot, |data, log| Worker::run(param, stdin, data, &mut stream, log));
+                (slot, answer)
+            });
+            let result = Runtime::wait(&mut handle, &watch, Arc::clone(&log)).await;
+            Active::finish(&active, Arc::clone(&log));
+            let (slot, answer) = match result {
+                Some(Ok(r)) => r,
+                Some(Err(e)) => {
+                    Log::push_warning(Arc::clone(&log), 525, Some(e.to_string()));
+                    Runtime::lose(&shared, permit);
+                    break;
+                },
+                None => {
+                    Runtime::lose(&shared, permit);
+                    break;
+                },
+            };
+            let expired = watch.finish();
+            let aborted = slot.abort.swap(false, Ordering::AcqRel);
+            Runtime::give(&shared, slot);
+            drop(permit);
+            if expired {
+                // 504 has been sent instead of the answer, the other requests of the connection go on
+                FastCGI::done(&begin, &mut close);
+            } else if !FastCGI::finish(&begin, answer, aborted, &mut close, &mut out) {
+                break;
+            }
+            if Go::is_draining() && requests.is_empty() {
+                break;
+            }
+        }
+        // Stop the reader, then the writer sends the rest and closes the socket.
+        // The stuck controller may still hold the writer, so the writer is stopped by the empty data.
+        read.abort();
+        Runtime::send(&writer, &mut out).await;
+        let _ = writer.send(Vec::new()).await;
+        drop(output);
+        drop(writer);
+        let _ = write.await;
+    }
+
+    // Read FastCGI records, so FCGI_ABORT_REQUEST is noticed while the controller is running
+    async fn reader<R: AsyncRead + Unpin>(mut rd: R, mut buffer: Vec<u8>, mut size: usize, sender: UnboundedSender<Record>, writer: Sender<Vec<u8>>, active: Arc<Mutex<Active>>, log: Arc<Mutex<Log>>) {
+        let mut seek = 0;
+        'read: loop {
+            // Decode all records in the buffer
+            loop {
+                // Answers of the low-level requests, like FCGI_GET_VALUES
+                let mut answer = Vec::new();
+                let start = seek;
+                let status = FastCGI::read(&mut seek, &mut size, &mut buffer, &mut answer);
+                if !Runtime::send(&writer, &mut answer).await {
+                    return;
+                }
+                match status {
+                    ReadStatus::Continue => break,
+                    // The wrong header, the stream can't be decoded further
+                    ReadStatus::Break if seek == start => break 'read,
+                    ReadStatus::Break | ReadStatus::Next => continue,
+                    ReadStatus::ErrorStream => break 'read,
+                    ReadStatus::Result(record) => {
+                        Active::record(&active, &record.header, Arc::clone(&log));
+                        if sender.send(record).is_err() {
+                            return;
+                        }
+                    },
+                }
+            }
+            if seek >= size {
+                size = 0;
+                seek = 0;
+            } else if seek > 0 {
+                buffer.copy_within(seek..size, 0);
+                size -= seek;
+                seek = 0;
+            }
+            match rd.read(&mut buffer[size..]).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => size += n,
+            };
+        }
+        // The web server has closed the connection
+        Active::close(&active, log);
+    }
+
+    // Write the data of the connection in the order it was sent
+    async fn writer<W: AsyncWrite + Unpin>(mut wr: W, mut receiver: Receiver<Vec<u8>>) {
+        while let Some(data) = receiver.recv().await {
+            if data.is_empty() || wr.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        let _ = wr.shutdown().await;
+    }
+
+    // Pass the collected data of the task to the writer
+    async fn send(writer: &Sender<Vec<u8>>, data: &mut Vec<u8>) -> bool {
+        data.is_empty() || writer.send(std::mem::take(data)).await.is_ok()
+    }
+
+    // One connection of the built-in HTTP server. The controller is taken for each request,
+    // the idle keep-alive connection holds neither the thread nor the database connection.
+    async fn http(stream: net::TcpStream, ip: IpAddr, shared: Arc<Shared>, _open: Open) {
+        let log = Arc::clone(&shared.log);
+        if let Some(a) = &shared.accept {
+            if let Err(rule) = a.check(ip) {
+                Log::push_warning(log, 504, Some(format!("{} {}", ip, rule)));
+                return;
+            }
+        }
+        // The task waits for the request on the socket, the controller reads it by the blocking calls from the clone
+        let (stream, tcp) = match stream.into_std().and_then(|s| s.try_clone().map(|c| (s, c))) {
+            Ok((s, c)) => match net::TcpStream::from_std(s) {
+                Ok(s) => (s, c),
+                Err(e) => {
+                    Log::push_warning(log, 513, Some(e.to_string()));
+                    return;
+                },
+            },
+            Err(e) => {
+                Log::push_warning(log, 513, Some(e.to_string()));
+                return;
+            },
+        };
+        let mut conn = match Http::open(tcp, Arc::clone(&log)) {
+            Some(c) => c,
+            None => return,
+        };
+        loop {
+            if !Http::is_pending(&conn) && !Runtime::wait_http(&stream).await {
+                break;
+            }
+            let permit = match Runtime::acquire(&shared).await {
+                Some(p) => p,
+                None => {
+                    Log::push_warning(log, 522, None);
+                    let mut stream = stream;
+                    let _ = stream.write_all(&Http::overload_answer(shared.limit.queue_timeout.as_secs().max(1))).await;
+                    return;
+                },
+            };
+            let slot = Runtime::take(&shared).await;
+            if let Err(e) = Http::set_nonblocking(&conn, false) {
+                Log::push_warning(Arc::clone(&log), 513, Some(e.to_string()));
+                Runtime::give(&shared, slot);
+                break;
+            }
+            let watch = Arc::clone(&slot.watch);
+            let run_shared = Arc::clone(&shared);
+            let mut handle = task::spawn_blocking(move || {
+                let mut slot = slot;
+                let mut conn = conn;
+                let keep = Runtime::execute(&run_shared, &mut slot, |data, log| Http::serve(&Worker::run_http, &mut conn, data, log));
+                (slot, conn, keep)
+            });
+            match Runtime::wait(&mut handle, &watch, Arc::clone(&log)).await {
+                Some(Ok((slot, c, keep))) => {
+                    slot.abort.store(false, Ordering::Release);
+                    Runtime::give(&shared, slot);
+                    drop(permit);
+                    if !keep || Http::set_nonblocking(&c, true).is_err() {
+                        break;
+                    }
+                    conn = c;
+                },
+                Some(Err(e)) => {
+                    Log::push_warning(log, 525, Some(e.to_string()));
+                    Runtime::lose(&shared, permit);
+                    break;
+                },
+                None => {
+                    Runtime::lose(&shared, permit);
+                    break;
+                },
+            };
+        }
+    }
+
+    // Wait for the next request of the keep-alive connection. On stop the idle connection is closed.
+    async fn wait_http(stream: &net::TcpStream) -> bool {
+        let start = Instant::now();
+        let mut byte = [0; 1];
+        while start.elapsed() < HTTP_KEEP_ALIVE {
+            match tokio::time::timeout(DRAIN_CHECK_INTERVAL, stream.peek(&mut byte)).await {
+                Ok(Ok(n)) => return n > 0,
+                Ok(Err(_)) => return false,
+                Err(_) => if Go::is_draining() {
+                    return false;
+                },
+            };
+        }
+        false
+    }
+
+    // Wait for the free database connection no longer than queue_timeout
+    async fn acquire(shared: &Shared) -> Option<SemaphorePermit<'_>> {
+        shared.control.queue.fetch_add(1, Ordering::AcqRel);
+        let permit = if shared.limit.queue_timeout.is_zero() {
+            shared.permits.acquire().await.ok()
+        } else {
+            match tokio::time::timeout(shared.limit.queue_timeout, shared.permits.acquire()).await {
+                Ok(p) => p.ok(),
+                Err(_) => None,
+            }
+        };
+        shared.control.queue.fetch_sub(1, Ordering::AcqRel);
+        permit
+    }
+
+    // Wait for the controller and watch its deadline. None - the controller is stuck, so it's left to finish by itself.
+    async fn wait<T>(handle: &mut task::JoinHandle<T>, watch: &Watch, log: Arc<Mutex<Log>>) -> Option<Result<T, task::JoinError>> {
+        if !watch.is_enabled() {
+            return Some(handle.await);
+        }
+        loop {
+            match tokio::time::timeout(WORKER_CHECK_INTERVAL, &mut *handle).await {
+                Ok(r) => return Some(r),
+                Err(_) => match watch.check() {
+                    WatchStatus::Idle | WatchStatus::Busy => {},
+                    WatchStatus::Expired(cancel) => {
+                        Log::push_warning(Arc::clone(&log), 709, None);
+                        if let Some(token) = cancel {
+                            DB::cancel(token, Arc::clone(&log));
+                        }
+                    },
+                    WatchStatus::Stuck => {
+                        Log::push_warning(log, 710, None);
+                        return None;
+                    },
+                },
+            };
+        }
+    }
+
+    // The database connection is lost with the controller, so the new one can be opened instead
+    fn lose(shared: &Shared, permit: SemaphorePermit) {
+        permit.forget();
+        shared.permits.add_permits(1);
+        shared.control.busy.fetch_sub(1, Ordering::AcqRel);
+        match Mutex::lock(&shared.pool) {
+            Ok(mut pool) => {
+                pool.len -= 1;
+                shared.control.workers.store(pool.len, Ordering::Release);
+            },
+            Err(e) => Log::error(Arc::clone(&shared.log), e.to_string()),
+        };
+    }
+
+    // Run the closure with the data of the controller in the blocking thread
+    fn execute<T>(shared: &Shared, slot: &mut Slot, f: impl FnOnce(&mut DataRun, Arc<Mutex<Log>>) -> T) -> T {
+        let mut data = shared.conf.data(&mut slot.db, &slot.abort, &slot.watch);
+        f(&mut data, Arc::clone(&shared.log))
+    }
+
+    // Free database connection. The new one is opened, when all are busy.
+    async fn take(shared: &Arc<Shared>) -> Slot {
+        let slot = match Mutex::lock(&shared.pool) {
+            Ok(mut pool) => pool.free.pop(),
+            Err(e) => Log::error(Arc::clone(&shared.log), e.to_string()),
+        };
+        shared.control.busy.fetch_add(1, Ordering::AcqRel);
+        if let Some(slot) = slot {
+            return slot;
+        }
+        let slot = Runtime::open(&shared.conf, &shared.limit).await;
+        match Mutex::lock(&shared.pool) {
+            Ok(mut pool) => {
+                pool.len += 1;
+                shared.control.workers.store(pool.len, Ordering::Release);
+                Log::push_info(Arc::clone(&shared.log), 707, Some(format!("workers={}", pool.len)));
+            },
+            Err(e) => Log::error(Arc::clone(&shared.log), e.to_string()),
+        };
+        slot
+    }
+
+    // New database connection of the controller
+    async fn open(conf: &WorkerConf, limit: &PoolConf) -> Slot {
+        let abort = Arc::new(AtomicBool::new(false));
+        let db = conf.open(Arc::clone(&abort)).await;
+        Slot { db, abort, used: Instant::now(), watch: Arc::new(Watch::new(limit.request_timeout)) }
+    }
+
+    fn give(shared: &Shared, mut slot: Slot) {
+        slot.used = Instant::now();
+        shared.control.busy.fetch_sub(1, Ordering::AcqRel);
+        match Mutex::lock(&shared.pool) {
+            Ok(mut pool) => pool.free.push(slot),
+            Err(e) => Log::error(Arc::clone(&shared.log), e.to_string()),
+        };
+    }
+
+    // Close the database connections over min_workers, which are idle longer than idle_timeout.
+    // The "workers" command changes max_workers, so the permits are added or taken away here.
+    async fn retire(shared: Arc<Shared>, max_workers: usize) {
+        let mut interval = tokio::time::interval(WORKER_CHECK_INTERVAL);
+        let mut limit = max_workers;
+        // Permits to take away, they are busy now
+        let mut debt = 0;
+        loop {
+            interval.tick().await;
+            let max = shared.control.max_workers.load(Ordering::Acquire);
+            if max > limit {
+                let add = max - limit;
+                let paid = add.min(debt);
+                debt -= paid;
+                shared.permits.add_permits(add - paid);
+            } else if max < limit {
+                debt += limit - max;
+            }
+            limit = max;
+            if debt > 0 {
+                debt -= shared.permits.forget_permits(debt);
+            }
+            let min_workers = shared.limit.min_workers.min(max);
+            let (free, len) = match Mutex::lock(&shared.pool) {
+                Ok(mut pool) => {
+                    let mut free = Vec::new();
+                    while pool.len > min_workers && !pool.free.is_empty() && (pool.len > max || (!shared.limit.idle_timeout.is_zero() && pool.free[0].used.elapsed() >= shared.limit.idle_timeout)) {
+                        free.push(pool.free.remove(0));
+                        pool.len -= 1;
+                    }
+                    (free, pool.len)
+                },
+                Err(e) => Log::error(Arc::clone(&shared.log), e.to_string()),
+            };
+            if !free.is_empty() {
+                shared.control.workers.store(len, Ordering::Release);
+                Log::push_info(Arc::clone(&shared.log), 708, Some(format!("workers={}", len)));
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::{fs::File, io::{self, Read}, os::unix::io::FromRawFd, sync::atomic::{AtomicI32, Ordering}};
+
+// Write end of the pipe, the signal handler writes the signal number into it
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+// Only async-signal-safe calls are allowed here, so the signal is passed to the thread over the pipe
+extern "C" fn handler(signal: libc::c_int) {
+    let fd = PIPE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        let errno = io::Error::last_os_error().raw_os_error().unwrap_or(0);
+        let byte = signal as u8;
+        unsafe {
+            libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+            *libc::__errno_location() = errno;
+        }
+    }
+}
+
+// Signals of the server process. The handlers are reset in the new process on restart.
+pub struct Signal {
+    read: File,
+}
+
+impl Signal {
+
+    // Set the handlers of the signals
+    pub fn listen(signals: &[libc::c_int]) -> Result<Signal, String> {
+        let mut pipe: [libc::c_int; 2] = [0; 2];
+        if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(format!("Can't create the pipe. Error: {}", io::Error::last_os_error()));
+        }
+        // The handler never blocks, the signal is lost if the pipe is full
+        unsafe { libc::fcntl(pipe[1], libc::F_SETFL, libc::O_NONBLOCK) };
+        PIPE.store(pipe[1], Ordering::Release);
+        for signal in signals {
+            let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+            action.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            unsafe { libc::sigemptyset(&mut action.sa_mask) };
+            if unsafe { libc::sigaction(*signal, &action, std::ptr::null_mut()) } != 0 {
+                return Err(format!("Signal {}. Error: {}", signal, io::Error::last_os_error()));
+            }
+        }
+        Ok(Signal { read: unsafe { File::from_raw_fd(pipe[0]) } })
+    }
+
+    // Wait for the next signal
+    pub fn wait(&mut self) -> Option<libc::c_int> {
+        let mut buf = [0; 1];
+        loop {
+            match self.read.read(&mut buf) {
+                Ok(1) => return Some(buf[0] as libc::c_int),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                _ => return None,
+            }
+        }
+    }
+}
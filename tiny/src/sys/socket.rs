@@ -0,0 +1,170 @@
+use std::{net::{TcpStream, TcpListener, Shutdown}, os::unix::{net::{UnixStream, UnixListener}, fs::{PermissionsExt, FileTypeExt}, io::{AsRawFd, RawFd}}, io::{self, Read, Write}, fs, ffi::CString, path::Path, time::Duration};
+
+#[derive(Debug, Clone)]
+pub struct SocketConfig {
+    pub path: String,                   // Path to the Unix socket file
+    pub mode: Option<u32>,              // Permissions of the socket file
+    pub owner: Option<String>,          // Owner of the socket file "user" or "user:group"
+}
+
+// Connection with the web server over TCP or Unix socket
+pub enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Socket {
+    pub fn try_clone(&self) -> io::Result<Socket> {
+        match self {
+            Socket::Tcp(s) => Ok(Socket::Tcp(s.try_clone()?)),
+            Socket::Unix(s) => Ok(Socket::Unix(s.try_clone()?)),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Socket::Tcp(s) => s.shutdown(how),
+            Socket::Unix(s) => s.shutdown(how),
+        }
+    }
+
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Tcp(s) => s.set_read_timeout(dur),
+            Socket::Unix(s) => s.set_read_timeout(dur),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(s) => s.read(buf),
+            Socket::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(s) => s.write(buf),
+            Socket::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(s) => s.flush(),
+            Socket::Unix(s) => s.flush(),
+        }
+    }
+}
+
+// Listener of the FastCGI server
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn accept(&self) -> io::Result<Socket> {
+        match self {
+            Listener::Tcp(l) => Ok(Socket::Tcp(l.accept()?.0)),
+            Listener::Unix(l) => Ok(Socket::Unix(l.accept()?.0)),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(l) => l.set_nonblocking(nonblocking),
+            Listener::Unix(l) => l.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(l) => l.as_raw_fd(),
+            Listener::Unix(l) => l.as_raw_fd(),
+        }
+    }
+
+    // Bind the Unix socket. The stale socket file is removed, but the working one is not touched.
+    pub fn bind_unix(conf: &SocketConfig) -> Result<Listener, String> {
+        let path = Path::new(&conf.path);
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(format!("{} is not a socket", conf.path));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(format!("{} is in use", conf.path));
+            }
+            if let Err(e) = fs::remove_file(path) {
+                return Err(format!("Can't remove the stale socket {}. Error: {}", conf.path, e));
+            }
+        }
+        let listener = match UnixListener::bind(path) {
+            Ok(l) => l,
+            Err(e) => return Err(format!("{}. Error: {}", conf.path, e)),
+        };
+        if let Some(mode) = conf.mode {
+            if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(mode)) {
+                let _ = fs::remove_file(path);
+                return Err(format!("Can't set mode {:o} on {}. Error: {}", mode, conf.path, e));
+            }
+        }
+        if let Some(owner) = &conf.owner {
+            if let Err(e) = Listener::chown(path, owner) {
+                let _ = fs::remove_file(path);
+                return Err(format!("Can't set owner {} on {}. Error: {}", owner, conf.path, e));
+            }
+        }
+        Ok(Listener::Unix(listener))
+    }
+
+    // Change owner to "user" or "user:group", names or numeric ids
+    fn chown(path: &Path, owner: &str) -> Result<(), String> {
+        let (user, group) = match owner.split_once(':') {
+            Some((u, g)) => (u, Some(g)),
+            None => (owner, None),
+        };
+        let uid = match user.parse::<u32>() {
+            Ok(u) => u,
+            Err(_) => {
+                let name = CString::new(user).map_err(|e| e.to_string())?;
+                let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+                if pw.is_null() {
+                    return Err(format!("Unknown user {}", user));
+                }
+                unsafe { (*pw).pw_uid }
+            },
+        };
+        let gid = match group {
+            Some(g) => match g.parse::<u32>() {
+                Ok(g) => Some(g),
+                Err(_) => {
+                    let name = CString::new(g).map_err(|e| e.to_string())?;
+                    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+                    if gr.is_null() {
+                        return Err(format!("Unknown group {}", g));
+                    }
+                    Some(unsafe { (*gr).gr_gid })
+                },
+            },
+            None => None,
+        };
+        std::os::unix::fs::chown(path, Some(uid), gid).map_err(|e| e.to_string())
+    }
+}
+
+// Wait until the descriptor can be read, false - the timeout has passed.
+// The listeners are polled, so the accept loop sees the stop flag and doesn't need the wake-up connection.
+pub fn wait_read(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut poll = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    match unsafe { libc::poll(&mut poll, 1, timeout) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
@@ -1,16 +1,26 @@
-use std::{collections::HashMap, sync::{Mutex, Arc}, io::Write};
+use std::{collections::HashMap, fmt, mem, str::FromStr, sync::{Mutex, Arc, RwLock, atomic::{AtomicBool, Ordering}}, io::{Write, Read, ErrorKind}, borrow::Cow, fs::File};
 
-use chrono::Local;
-use serde::{Serialize, Deserialize};
-use tempfile::NamedTempFile;
+use chrono::{DateTime, Local, Utc};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use serde_json::Value;
+use tempfile::{NamedTempFile, TempPath};
 use sha3::{Digest, Sha3_512};
 
 pub type Act = fn(&mut Action) -> Answer;
+
+// Maximum size of the headers of the one multipart/form-data part
+const MULTIPART_MAX_HEADER_LEN: usize = 16384;
+// Maximum length of the multipart/form-data boundary (RFC 2046)
+const MULTIPART_MAX_BOUNDARY_LEN: usize = 70;
 pub type ActMap = HashMap<&'static str, HashMap<&'static str, HashMap<&'static str, Act>>>;
+// Request handler of the FastCGI and HTTP servers: params, body, data of the controller and the stream of the answer
+pub type Handler = dyn Fn(HashMap<String, String>, Body, &mut DataRun, &mut dyn Write, Arc<Mutex<Log>>) -> Vec<u8>;
+// Fields, files and names of the skipped fields of the multipart/form-data body
+type Multipart = (Vec<(String, String)>, HashMap<String, Vec<WebFile>>, Vec<String>);
 
 use crate::sys::log::Log;
 
-use super::{cache::{Cache}, db::DB, html::{Html, Node}, lang::Lang};
+use super::{cache::{Cache}, db::DB, html::{Html, Node}, lang::Lang, param::{Params, ParamError}, worker::Worker, watch::Watch};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Data {
@@ -32,13 +42,32 @@ enum Route {
 
 pub struct DataRun<'a> {
     pub cache: Arc<Mutex<Cache>>,
-    pub html: &'a Html,
-    pub lang: &'a Lang,
+    pub html: Arc<RwLock<Arc<Html>>>,      // Templates, the reload replaces them for the next requests
+    pub lang: Arc<RwLock<Arc<Lang>>>,      // Translations
     pub salt: &'a str,
     pub lang_id: u64,
     pub path: &'a str,
     pub db: &'a mut DB,
     pub engine: &'a ActMap,
+    pub abort: Arc<AtomicBool>,
+    pub max_body: usize,
+    pub spool_body: usize,
+    pub upload: Upload,
+    pub watch: Arc<Watch>,
+}
+
+// Templates and translations taken for the request, the reload doesn't change them until it ends
+pub struct View<'a> {
+    pub html: &'a Html,
+    pub lang: &'a Lang,
+}
+
+// Limits of the uploaded files and forms, 0 - unlimited. The whole request is limited by max_body.
+#[derive(Debug, Clone, Copy)]
+pub struct Upload {
+    pub max_file: usize,                  // Size of the one file
+    pub max_files: usize,                 // Number of the files in the request
+    pub max_form: usize,                  // Size of the form data, which is parsed in memory: urlencoded or JSON body, text field of multipart
 }
 
 #[derive(Debug)]
@@ -52,25 +81,141 @@ pub enum Answer{
     None,               // With out answer
     String(String),     // Answer in the form of text
     Raw(Vec<u8>),       // Answer in binary data
+    Json(Value),        // Answer in JSON, the content type is application/json
 }
 
 #[derive(Debug)]
-pub struct WebFile<'a> {
+pub struct WebFile {
     pub size: usize,                      // File size
-    pub name: &'a str,                    // File name
-    pub tmp: std::path::PathBuf,      // Absolute path to file location
+    pub name: String,                     // Original file name without the path
+    pub content_type: String,             // Content-Type of the part, application/octet-stream by default
+    pub tmp: TempPath,                    // Absolute path to file location, the file is removed with it
+}
+
+// Body of the request (FastCGI stdin)
+#[derive(Debug)]
+pub enum Body {
+    None,
+    Memory(Vec<u8>),                    // Small body in memory
+    File(NamedTempFile, usize),         // Large body spooled to the temp file and its size
+}
+
+impl Body {
+    // Append data to the body. The body is moved to the temp file when it becomes larger than spool.
+    pub fn append(&mut self, data: &[u8], spool: usize) -> bool {
+        match self {
+            Body::None => *self = Body::Memory(data.to_vec()),
+            Body::Memory(vec) => vec.extend_from_slice(data),
+            Body::File(tmp, size) => {
+                *size += data.len();
+                return tmp.write_all(data).is_ok();
+            },
+        };
+        if let Body::Memory(vec) = self {
+            if spool > 0 && vec.len() > spool {
+                let mut tmp = match NamedTempFile::new() {
+                    Ok(tmp) => tmp,
+                    Err(_) => return false,
+                };
+                if tmp.write_all(vec).is_err() {
+                    return false;
+                }
+                let size = vec.len();
+                *self = Body::File(tmp, size);
+            }
+        }
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Body::None => 0,
+            Body::Memory(vec) => vec.len(),
+            Body::File(_, size) => *size,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Whole body. The spooled body is read from the temp file.
+    pub fn read(&self) -> Option<Cow<'_, [u8]>> {
+        match self {
+            Body::None => None,
+            Body::Memory(vec) => Some(Cow::Borrowed(&vec[..])),
+            Body::File(tmp, size) => {
+                let mut vec = Vec::with_capacity(*size);
+                match tmp.reopen() {
+                    Ok(mut f) => match f.read_to_end(&mut vec) {
+                        Ok(_) => Some(Cow::Owned(vec)),
+                        Err(_) => None,
+                    },
+                    Err(_) => None,
+                }
+            },
+        }
+    }
+}
+
+// Data of the one part of multipart/form-data.
+// The temp file is removed, when its path is dropped, so the panic in the middle of the parsing doesn't leave it.
+enum Part {
+    Memory(Vec<u8>),
+    File(Option<File>, Option<TempPath>, usize),
+    Skip,                               // The file over the limits, its data is not saved
+}
+
+impl Part {
+    fn new(is_file: bool) -> Part {
+        if is_file {
+            if let Ok(tmp) = NamedTempFile::new() {
+                let (f, p) = tmp.into_parts();
+                return Part::File(Some(f), Some(p), 0);
+            }
+            return Part::File(None, None, 0);
+        }
+        Part::Memory(Vec::new())
+    }
+
+    // The part over the limit is dropped at once, the file is removed, the rest of the data is skipped
+    fn write(&mut self, data: &[u8], upload: &Upload) {
+        match self {
+            Part::Memory(vec) => {
+                if upload.max_form > 0 && vec.len() + data.len() > upload.max_form {
+                    *self = Part::Skip;
+                } else {
+                    vec.extend_from_slice(data);
+                }
+            },
+            Part::File(f, _, size) => {
+                *size += data.len();
+                if upload.max_file > 0 && *size > upload.max_file {
+                    *self = Part::Skip;
+                } else if let Some(file) = f {
+                    if file.write_all(data).is_err() {
+                        *f = None;
+                    }
+                }
+            },
+            Part::Skip => {},
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Input<'a> {
-    pub get: Option<HashMap<&'a str, &'a str>>,         // GET data
-    pub post: Option<HashMap<&'a str, &'a str>>,        // POST data
-    pub file: Option<HashMap<&'a str, Vec<WebFile<'a>>>>,  // FILE data
+    pub get: Params,                                    // GET data
+    pub post: Params,                                   // POST data
+    pub file: Option<HashMap<String, Vec<WebFile>>>,    // FILE data
+    pub file_skipped: Vec<String>,                      // Fields of the files over max_file or max_files, they are not saved
     pub cookie: Option<HashMap<&'a str, &'a str>>,      // Cookies
+    pub json: Option<Value>,                            // Body of application/json
 }
 
 #[derive(Debug)]
 pub struct Request<'a> {
+    pub id: String,                           // X-Request-Id of the client or the generated one
     pub ajax: bool,                           // Ajax query (only software detect)
     pub host: &'a str,                       // Request host. Example: subdomain.domain.zone
     pub scheme: &'a str,                     // Request scheme. Example: http / https
@@ -84,12 +229,142 @@ pub struct Request<'a> {
     pub input: Input<'a>,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,               // The browser accepts it only with Secure
+}
+
+// Cookie of the answer. The cookie with the name or the value out of RFC 6265 isn't sent.
+#[derive(Debug, Clone)]
+pub struct Cookie {
+    pub name: String,                         // Token: visible ASCII without separators
+    pub value: String,                        // Visible ASCII without '"', ',', ';' and '\\', it may be in double quotes
+    pub path: Option<String>,
+    pub domain: Option<String>,               // None - the host of the request only
+    pub expires: Option<DateTime<Utc>>,       // None - the cookie is removed, when the browser is closed
+    pub max_age: Option<i64>,                 // Seconds, 0 - remove the cookie now
+    pub http_only: bool,                      // Not available from javascript
+    pub secure: bool,                         // Sent over https only
+    pub same_site: Option<SameSite>,
+}
+
+impl Cookie {
+
+    // Session cookie for the whole site
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            path: Some("/".to_owned()),
+            domain: None,
+            expires: None,
+            max_age: None,
+            http_only: true,
+            secure: false,
+            same_site: Some(SameSite::Lax),
+        }
+    }
+
+    // Value of Set-Cookie. None - the name, the value or the attributes would break the header.
+    pub fn to_header(&self) -> Option<String> {
+        if !Cookie::is_name(&self.name) || !Cookie::is_value(&self.value) {
+            return None;
+        }
+        if !self.path.as_deref().is_none_or(Cookie::is_attribute) || !self.domain.as_deref().is_none_or(Cookie::is_attribute) {
+            return None;
+        }
+        let mut cookie = format!("{}={}", self.name, self.value);
+        if let Some(expires) = &self.expires {
+            cookie.push_str(&format!("; Expires={}", expires.format("%a, %d %b %Y %H:%M:%S GMT")));
+        }
+        if let Some(max_age) = self.max_age {
+            cookie.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if let Some(path) = &self.path {
+            cookie.push_str(&format!("; Path={}", path));
+        }
+        if let Some(domain) = &self.domain {
+            cookie.push_str(&format!("; Domain={}", domain));
+        }
+        if self.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => cookie.push_str("; SameSite=Strict"),
+            Some(SameSite::Lax) => cookie.push_str("; SameSite=Lax"),
+            Some(SameSite::None) => cookie.push_str("; SameSite=None"),
+            None => {},
+        };
+        Some(cookie)
+    }
+
+    // cookie-name of RFC 6265 is the token of RFC 7230
+    fn is_name(name: &str) -> bool {
+        !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b))
+    }
+
+    // cookie-value of RFC 6265
+    fn is_value(value: &str) -> bool {
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+        value.bytes().all(|b| b.is_ascii_graphic() && b != b'"' && b != b',' && b != b';' && b != b'\\')
+    }
+
+    // Path and Domain can't have ';', which starts the next attribute, and the control characters
+    fn is_attribute(value: &str) -> bool {
+        value.bytes().all(|b| (b.is_ascii_graphic() || b == b' ') && b != b';')
+    }
+}
+
 #[derive(Debug)]
 pub struct Response {
     pub redirect: Option<Redirect>,
     pub http_code: Option<u16>,
     pub css: Vec<String>,                     // Addition css script
     pub js: Vec<String>,                      // Addition js script
+    pub content_type: Option<String>,         // None - text/html; charset=utf-8
+    pub headers: Vec<(String, String)>,       // Addition headers of the answer
+    pub cookies: Vec<Cookie>,                 // Cookies besides the session one
+}
+
+impl Response {
+
+    // Replace the header with the same name
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        if name.eq_ignore_ascii_case("Content-Type") {
+            self.content_type = Some(value.to_owned());
+            return;
+        }
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_owned(), value.to_owned()));
+    }
+
+    // Add one more header with this name, like Link
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_owned(), value.to_owned()));
+    }
+
+    pub fn set_content_type(&mut self, content_type: &str) {
+        self.content_type = Some(content_type.to_owned());
+    }
+
+    // Replace the cookie with the same name and path
+    pub fn set_cookie(&mut self, cookie: Cookie) {
+        self.cookies.retain(|c| c.name != cookie.name || c.path != cookie.path);
+        self.cookies.push(cookie);
+    }
+
+    // The browser removes the cookie with this name on the path "/"
+    pub fn delete_cookie(&mut self, name: &str) {
+        let mut cookie = Cookie::new(name, "");
+        cookie.max_age = Some(0);
+        cookie.expires = DateTime::from_timestamp(0, 0);
+        self.set_cookie(cookie);
+    }
 }
 
 #[derive(Debug)]
@@ -138,10 +413,13 @@ pub struct Action<'a> {
     pub internal: bool,
     pub data: HashMap<&'a str, Data>,
     pub param: Option<String>,
+    abort: Arc<AtomicBool>,
+    stream: &'a mut dyn Write,
+    streamed: bool,
 }
 
 impl<'a> Action<'a> {
-    pub fn new(param: &'a HashMap<String, String>, stdin: &'a Option<Vec<u8>>, data: DataRun<'a>, log: Arc<Mutex<Log>>) -> Action<'a> {
+    pub fn new(param: &'a HashMap<String, String>, stdin: &'a Body, data: &'a mut DataRun, view: View<'a>, stream: &'a mut dyn Write, id: String, log: Arc<Mutex<Log>>) -> Action<'a> {
         let ajax = match param.get("HTTP_X_REQUESTED_WITH") {
             Some(a) => a.to_lowercase().eq("xmlhttprequest"),
             None => false,
@@ -184,143 +462,54 @@ impl<'a> Action<'a> {
         };
         // Extract GET data 
         let get = match param.get("QUERY_STRING") {
-            Some(v) => {
-                if v.len() > 0 {
-                    let gets:Vec<&str> = v.split("&").collect();
-                    let mut list = HashMap::with_capacity(gets.len());
-                    for v in gets {
-                        let key: Vec<&str> = v.splitn(2, "=").collect();
-                        match key.len() {
-                            1 => list.insert(v, ""),
-                            _ => list.insert(key[0], key[1]),
-                        };
-                    }
-                    if list.len() == 0 {
-                        None
-                    } else {
-                        Some(list)
-                    }
-                } else {
-                    None
-                }
-            },
-            None => None,
+            Some(v) => Params::parse(v),
+            None => Params::default(),
         };
 
-        let post;
-        let file;
         // Extract POST data 
-        match param.get("CONTENT_TYPE") {
-            Some(c) => {
-                if c == "application/x-www-form-urlencoded" {
-                    //Simple post
-                    post = match &stdin {
-                        Some(d) => match std::str::from_utf8(d) {
-                            Ok(s) => {
-                                if s.len() == 0 {
-                                    None
-                                } else {
-                                    let val: Vec<&str> = s.split("&").collect();
-                                    let mut list = HashMap::with_capacity(val.len());
-                                    for v in val {
-                                        let val: Vec<&str> = v.splitn(2, "=").collect();
-                                        match val.len() {
-                                        1 => list.insert(v, ""),
-                                        _ => list.insert(val[0], val[1]),
-                                        };
-                                    }
-                                    if list.len() == 0 {
-                                        None
-                                    } else {
-                                        Some(list)
-                                    }
-                                }
-                            },
-                            Err(_) => None,
-                        }
-                        None => None,
-                    };
-                    file = None;
-                } else if c.len() > 30 {
-                    if let "multipart/form-data; boundary=" = &c[..30] { 
-                        // Multi post with files
-                        let boundary = format!("--{}", &c[30..]);
-                        let stop: [u8; 4] = [13, 10, 13, 10];
-                        match &stdin {
-                            Some(data) => {
-                                if data.len() == 0 {
-                                    post = None;
-                                    file = None;
-                                } else {
-                                    let mut seek: usize = 0;
-                                    let mut start: usize;
-                                    let b_len = boundary.len();
-                                    let len = data.len() - 4;
-                                    let mut found: Option<(usize, &str)> = None;
-                                    let mut list_post = HashMap::new();
-                                    let mut list_file = HashMap::new();
-                                    while seek < len {
-                                        // Find a boundary
-                                        if boundary.as_bytes() == &data[seek..seek + b_len] {
-                                            if seek + b_len == len {
-                                                if let Some((l, h)) = found {
-                                                    let d = &data[l..seek - 2];
-                                                    Action::get_post_file(h, d, &mut list_post, &mut list_file);
-                                                };
-                                                break;
-                                            }
-                                            seek += b_len + 2;
-                                            start = seek;
-                                            while seek < len {
-                                                if stop == data[seek..seek+4] {
-                                                    if let Ok(s) = std::str::from_utf8(&data[start..seek]) {
-                                                        if let Some((l, h)) = found {
-                                                            let d = &data[l..start-b_len-4];
-                                                            Action::get_post_file(h, d, &mut list_post, &mut list_file);
-                                                        };
-                                                        found = Some((seek+4, s));
-                                                    }
-                                                    seek += 4;
-                                                    break;
-                                                } else {
-                                                    seek += 1;
-                                                }
-                                            }
-                                        } else {
-                                            seek += 1;
-                                        }
-                                    }
-                                    if list_post.len() == 0 {
-                                        post = None;
-                                    } else {
-                                        post = Some(list_post);
-                                    }
-                                    if list_file.len() == 0 {
-                                        file = None;
-                                    } else {
-                                        file = Some(list_file);
-                                    }
-                                }
-                            },
-                            None => {
-                                post = None;
-                                file = None;
-                            },
-                        };
-                    } else {
-                        post = None;
-                        file = None;
+        let (post, file, file_skipped) = match param.get("CONTENT_TYPE").map(|c| Action::get_header_params(c)) {
+            // Simple post
+            Some((media, _)) if media == "application/x-www-form-urlencoded" => {
+                let post = match Action::read_form(stdin, data.upload, Arc::clone(&log)) {
+                    Some(d) => match std::str::from_utf8(&d) {
+                        Ok(s) => Params::parse(s),
+                        Err(_) => Params::default(),
                     }
-                } else {
-                    post = None;
-                    file = None;
-                }
+                    None => Params::default(),
+                };
+                (post, None, Vec::new())
             },
-            None => {
-                post = None;
-                file = None;
+            // Multi post with files
+            Some((media, params)) if media == "multipart/form-data" => match params.into_iter().find(|(n, _)| n == "boundary") {
+                Some((_, b)) if !b.is_empty() && b.len() <= MULTIPART_MAX_BOUNDARY_LEN => {
+                    let boundary = format!("\r\n--{}", b);
+                    let (list_post, list_file, skipped) = Action::get_multipart(boundary.as_bytes(), stdin, data.upload, Arc::clone(&log));
+                    let mut post = Params::default();
+                    for (name, value) in list_post {
+                        post.insert(&name, value);
+                    }
+                    let file = if list_file.is_empty() { None } else { Some(list_file) };
+                    (post, file, skipped)
+                },
+                _ => (Params::default(), None, Vec::new()),
             },
-        }
+            _ => (Params::default(), None, Vec::new()),
+        };
+
+        // Extract JSON data
+        let json = match param.get("CONTENT_TYPE") {
+            Some(c) if Action::is_json(c) => match Action::read_form(stdin, data.upload, Arc::clone(&log)) {
+                Some(d) => match serde_json::from_slice(&d) {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        Log::push_warning(Arc::clone(&log), 1023, Some(e.to_string()));
+                        None
+                    },
+                },
+                None => None,
+            },
+            _ => None,
+        };
 
         let session_key;
         let tiny_key = "tinysession";
@@ -405,10 +594,13 @@ impl<'a> Action<'a> {
             get,
             post,
             file,
+            file_skipped,
             cookie,
+            json,
         };
 
         let request = Request {
+            id,
             ajax,
             host,
             scheme,
@@ -427,11 +619,14 @@ impl<'a> Action<'a> {
             http_code: None,
             css: Vec::with_capacity(2),
             js: Vec::with_capacity(2),
+            content_type: None,
+            headers: Vec::new(),
+            cookies: Vec::new(),
         };
 
         Action {
-            salt: &data.salt,
-            cache: data.cache,
+            salt: data.salt,
+            cache: Arc::clone(&data.cache),
             db: data.db,
             request,
             response,
@@ -443,13 +638,16 @@ impl<'a> Action<'a> {
             engine: data.engine,
             current_module: None,
             current_class: None,
-            template: data.html,
-            language: data.lang,
+            template: view.html,
+            language: view.lang,
             html: None,
             lang: None,
             internal: false,
             data: HashMap::with_capacity(256),
             param: None,
+            abort: Arc::clone(&data.abort),
+            stream,
+            streamed: false,
         }
     }
 
@@ -462,6 +660,73 @@ impl<'a> Action<'a> {
         text.to_owned()
     }
 
+    // The typed GET parameter: this.get::<u64>("page")
+    pub fn get<T: FromStr>(&self, name: &str) -> Result<T, ParamError> where T::Err: fmt::Display {
+        self.request.input.get.get(name)
+    }
+
+    // The typed POST parameter
+    pub fn post<T: FromStr>(&self, name: &str) -> Result<T, ParamError> where T::Err: fmt::Display {
+        self.request.input.post.get(name)
+    }
+
+    // The JSON body in the struct of the controller
+    pub fn parse_json<T: DeserializeOwned>(&self) -> Result<T, String> {
+        match &self.request.input.json {
+            Some(json) => T::deserialize(json).map_err(|e| e.to_string()),
+            None => Err("The body isn't valid application/json".to_owned()),
+        }
+    }
+
+    // Answer the struct of the controller in JSON. If it can't be serialized, 500 is answered.
+    pub fn json<T: Serialize>(&mut self, value: &T) -> Answer {
+        match serde_json::to_value(value) {
+            Ok(v) => Answer::Json(v),
+            Err(e) => {
+                Log::push_warning(Arc::clone(&self.log), 1024, Some(e.to_string()));
+                self.response.http_code = Some(500);
+                Answer::None
+            },
+        }
+    }
+
+    // application/json or the type with +json suffix, like application/problem+json
+    fn is_json(content_type: &str) -> bool {
+        let media = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        media == "application/json" || (media.starts_with("application/") && media.ends_with("+json"))
+    }
+
+    // The body, which is parsed in memory. The larger one isn't read from the temp file at all.
+    fn read_form<'b>(stdin: &'b Body, upload: Upload, log: Arc<Mutex<Log>>) -> Option<Cow<'b, [u8]>> {
+        if upload.max_form > 0 && stdin.len() > upload.max_form {
+            Log::push_warning(log, 1027, Some(format!("len={}", stdin.len())));
+            return None;
+        }
+        stdin.read()
+    }
+
+    // The client has aborted the request, so the controller may stop early
+    pub fn is_aborted(&self) -> bool {
+        self.abort.load(Ordering::Acquire)
+    }
+
+    // Send the part of the answer to the client right away.
+    // The header is sent before the first part, so the response can't be changed after that.
+    pub fn write(&mut self, data: &[u8]) -> bool {
+        if !self.streamed {
+            let header = Worker::header(self, None);
+            if self.stream.write_all(&header).is_err() {
+                return false;
+            }
+            self.streamed = true;
+        }
+        self.stream.write_all(data).is_ok()
+    }
+
+    pub fn is_streamed(&self) -> bool {
+        self.streamed
+    }
+
     pub fn stop(mut self) {
         self.save_session();
     }
@@ -754,33 +1019,263 @@ impl<'a> Action<'a> {
         Route::Ok(module.to_owned(), class.to_owned(), action.to_owned(), param, None)
     }
 
-    // get post file from multipart/form-data
-    fn get_post_file(header: &'a str, data: &'a [u8], post: &mut HashMap<&'a str, &'a str>, file: &mut HashMap<&'a str, Vec<WebFile<'a>>>) {
-        let h: Vec<&str> = header.splitn(3, "; ").collect();
-        let len = h.len();
-        if len == 2 {
-            if let Ok(v) = std::str::from_utf8(data) {
-                let k = &h[1][6..h[1].len() - 1];
-                post.insert(k, v);
+    // Parse multipart/form-data body part by part, so the spooled body is never loaded in memory entirely
+    fn get_multipart(boundary: &[u8], stdin: &Body, upload: Upload, log: Arc<Mutex<Log>>) -> Multipart {
+        let mut post = Vec::new();
+        let mut file = HashMap::new();
+        let mut skipped = Vec::new();
+        let mut reader: Box<dyn Read + '_> = match stdin {
+            Body::None => return (post, file, skipped),
+            Body::Memory(data) => Box::new(&data[..]),
+            Body::File(tmp, _) => match tmp.reopen() {
+                Ok(f) => Box::new(f),
+                Err(_) => return (post, file, skipped),
+            },
+        };
+        // The first boundary is not preceded by CRLF
+        let mut buf = b"\r\n".to_vec();
+        let keep = boundary.len() - 1;
+        let mut files = 0;
+
+        // Skip the preamble
+        loop {
+            if let Some(i) = Action::find(&buf, boundary) {
+                buf.drain(..i + boundary.len());
+                break;
+            }
+            if buf.len() > keep {
+                buf.drain(..buf.len() - keep);
+            }
+            if !Action::fill(&mut reader, &mut buf) {
+                return (post, file, skipped);
+            }
+        }
+        loop {
+            // "--" after the boundary is the end of the body, the spaces before CRLF are allowed
+            let line = loop {
+                if let Some(i) = Action::find(&buf, b"\r\n") {
+                    break Some(i);
+                }
+                if buf.starts_with(b"--") || buf.len() > MULTIPART_MAX_HEADER_LEN || !Action::fill(&mut reader, &mut buf) {
+                    break None;
+                }
+            };
+            match line {
+                Some(i) if buf[..i].iter().all(|b| *b == b' ' || *b == b'\t') => buf.drain(..i + 2),
+                _ => break,
+            };
+            // Headers of the part
+            let header = loop {
+                // The part without headers
+                if buf.starts_with(b"\r\n") {
+                    buf.drain(..2);
+                    break String::new();
+                }
+                if let Some(i) = Action::find(&buf, b"\r\n\r\n") {
+                    let header = String::from_utf8_lossy(&buf[..i]).to_string();
+                    buf.drain(..i + 4);
+                    break header;
+                }
+                if buf.len() > MULTIPART_MAX_HEADER_LEN || !Action::fill(&mut reader, &mut buf) {
+                    return (post, file, skipped);
+                }
+            };
+            let (name, file_name, content_type) = Action::get_part_header(&header);
+            // Data of the part
+            let mut part = match &file_name {
+                // The empty file input
+                Some(f) if f.is_empty() => Part::Skip,
+                Some(_) if upload.max_files > 0 && files >= upload.max_files => {
+                    if !skipped.contains(&name) {
+                        skipped.push(name.clone());
+                    }
+                    if files == upload.max_files {
+                        Log::push_warning(Arc::clone(&log), 1026, Some(upload.max_files.to_string()));
+                    }
+                    files += 1;
+                    Part::Skip
+                },
+                Some(_) => {
+                    files += 1;
+                    Part::new(true)
+                },
+                None => Part::new(false),
+            };
+            let saved = matches!(part, Part::File(..));
+            let text = matches!(part, Part::Memory(_));
+            loop {
+                if let Some(i) = Action::find(&buf, boundary) {
+                    part.write(&buf[..i], &upload);
+                    buf.drain(..i + boundary.len());
+                    break;
+                }
+                if buf.len() > keep {
+                    part.write(&buf[..buf.len() - keep], &upload);
+                    buf.drain(..buf.len() - keep);
+                }
+                if !Action::fill(&mut reader, &mut buf) {
+                    return (post, file, skipped);
+                }
             }
-        } else if len == 3 {
-            let k = &h[1][6..h[1].len() - 1];
-            let n: Vec<&str> = h[2].splitn(2, "\r\n").collect();
-            let n = &n[0][10..n[0].len()-1];
+            if text && matches!(part, Part::Skip) {
+                Log::push_warning(Arc::clone(&log), 1027, Some(format!("field={}", name)));
+            }
+            if saved && matches!(part, Part::Skip) {
+                Log::push_warning(Arc::clone(&log), 1025, file_name.clone());
+                if !skipped.contains(&name) {
+                    skipped.push(name.clone());
+                }
+            }
+            if name.is_empty() {
+                continue;
+            }
+            match (part, file_name) {
+                (Part::Memory(data), None) => {
+                    if let Ok(v) = String::from_utf8(data) {
+                        post.push((name, v));
+                    }
+                },
+                (Part::File(Some(_), Some(tmp), size), Some(file_name)) => file.entry(name).or_insert_with(|| Vec::with_capacity(16)).push(WebFile { size, name: file_name, content_type, tmp }),
+                _ => {},
+            }
+        }
+        (post, file, skipped)
+    }
 
-            if let Ok(tmp) = NamedTempFile::new() {
-                if let Ok((mut f, p)) = tmp.keep() {
-                    if let Ok(_) = f.write_all(data) {
-                        if let None = file.get(&k) {
-                            file.insert(k, Vec::with_capacity(16));
-                        }
-                        if let Some(d) = file.get_mut(&k) {
-                            d.push(WebFile { size: data.len(), name: n, tmp: p})
-                        };
+    // Field name, file name and content type from the headers of the multipart part.
+    // The header names are case-insensitive, filename* (RFC 5987) is preferred over filename.
+    fn get_part_header(header: &str) -> (String, Option<String>, String) {
+        let mut name = String::new();
+        let mut file_name = None;
+        let mut content_type = None;
+        for line in header.split("\r\n") {
+            let (key, value) = match line.split_once(':') {
+                Some((k, v)) => (k.trim(), v),
+                None => continue,
+            };
+            if key.eq_ignore_ascii_case("content-disposition") {
+                let (_, params) = Action::get_header_params(value);
+                let mut plain = None;
+                let mut ext = None;
+                for (key, value) in params {
+                    match key.as_str() {
+                        "name" => name = value,
+                        "filename" => plain = Some(value),
+                        "filename*" => ext = Action::get_ext_value(&value),
+                        _ => {},
                     }
                 }
+                file_name = ext.or(plain).map(|f| Action::get_file_name(&f).to_owned());
+            } else if key.eq_ignore_ascii_case("content-type") {
+                let value = value.trim();
+                if !value.is_empty() {
+                    content_type = Some(value.to_owned());
+                }
             }
         }
+        (name, file_name, content_type.unwrap_or_else(|| "application/octet-stream".to_owned()))
+    }
+
+    // Value of the header with parameters: "form-data; name=\"a\"; filename=b.txt".
+    // The value is in lowercase, the names of the parameters too, the quoted values are unquoted.
+    fn get_header_params(header: &str) -> (String, Vec<(String, String)>) {
+        let mut items = Vec::new();
+        let mut item = String::new();
+        let mut quoted = false;
+        let mut chars = header.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    quoted = !quoted;
+                    item.push(c);
+                },
+                // Only \" and \\ are escaped, so the Windows path in the file name is kept
+                '\\' if quoted && matches!(chars.peek(), Some('"') | Some('\\')) => {
+                    item.push(c);
+                    if let Some(n) = chars.next() {
+                        item.push(n);
+                    }
+                },
+                ';' if !quoted => items.push(mem::take(&mut item)),
+                _ => item.push(c),
+            }
+        }
+        items.push(item);
+        let mut items = items.into_iter();
+        let value = items.next().unwrap_or_default().trim().to_lowercase();
+        let mut params = Vec::new();
+        for item in items {
+            let (key, value) = match item.split_once('=') {
+                Some((k, v)) => (k.trim().to_lowercase(), v.trim()),
+                None => continue,
+            };
+            let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(v) => {
+                    let mut s = String::with_capacity(v.len());
+                    let mut chars = v.chars().peekable();
+                    while let Some(c) = chars.next() {
+                        match (c, chars.peek()) {
+                            ('\\', Some('"')) | ('\\', Some('\\')) => {},
+                            _ => {
+                                s.push(c);
+                                continue;
+                            },
+                        }
+                        if let Some(n) = chars.next() {
+                            s.push(n);
+                        }
+                    }
+                    s
+                },
+                None => value.to_owned(),
+            };
+            params.push((key, value));
+        }
+        (value, params)
+    }
+
+    // Extended value of RFC 5987: charset'language'percent-encoded, UTF-8 and ISO-8859-1 are supported
+    fn get_ext_value(value: &str) -> Option<String> {
+        let mut split = value.splitn(3, '\'');
+        let charset = split.next()?;
+        let text = split.nth(1)?;
+        if charset.eq_ignore_ascii_case("utf-8") {
+            Some(Params::decode(text, false))
+        } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+            Some(Params::decode_bytes(text, false).into_iter().map(char::from).collect())
+        } else {
+            None
+        }
+    }
+
+    // File name without the path, some browsers send the full path
+    fn get_file_name(name: &str) -> &str {
+        match name.rfind(['/', '\\']) {
+            Some(i) => &name[i + 1..],
+            None => name,
+        }
+    }
+
+    // Read the next portion of the body
+    fn fill(reader: &mut dyn Read, buf: &mut Vec<u8>) -> bool {
+        let mut chunk = [0; 65536];
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    buf.extend_from_slice(&chunk[..n]);
+                    return true;
+                },
+                Err(e) => if e.kind() != ErrorKind::Interrupted {
+                    return false;
+                },
+            }
+        }
+    }
+
+    // Position of the pattern in the data
+    fn find(data: &[u8], pattern: &[u8]) -> Option<usize> {
+        data.windows(pattern.len()).position(|w| w == pattern)
     }
 
     pub fn http_code_get(code: u16) -> String {
@@ -854,4 +1349,195 @@ impl<'a> Action<'a> {
         };
         s
       }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::{Arc, Mutex}};
+
+    use chrono::{TimeZone, Utc};
+    use tempfile::TempDir;
+
+    use crate::sys::log::Log;
+
+    use super::{Action, Body, Cookie, Multipart, SameSite, Upload};
+
+    const BOUNDARY: &[u8] = b"\r\n--XyZ";
+
+    fn upload(max_file: usize, max_files: usize, max_form: usize) -> Upload {
+        Upload { max_file, max_files, max_form }
+    }
+
+    // The log of the test is written to the temp dir
+    fn log() -> (Arc<Mutex<Log>>, TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Log::new();
+        Log::set_path(Arc::clone(&log), dir.path().join("tiny.log").display().to_string());
+        (log, dir)
+    }
+
+    fn multipart(raw: &[u8], spool: usize, upload: Upload) -> (Multipart, String) {
+        let (log, dir) = log();
+        let mut body = Body::None;
+        for chunk in raw.chunks(1000) {
+            assert!(body.append(chunk, spool));
+        }
+        assert_eq!(matches!(body, Body::File(..)), spool > 0 && raw.len() > spool);
+        let form = Action::get_multipart(BOUNDARY, &body, upload, Arc::clone(&log));
+        Log::stop(log);
+        let text = fs::read_to_string(dir.path().join("tiny.log")).unwrap_or_default();
+        (form, text)
+    }
+
+    fn body(parts: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut raw = b"This is the preamble, it is ignored\r\n".to_vec();
+        for (header, data) in parts {
+            raw.extend_from_slice(b"--XyZ\r\n");
+            raw.extend_from_slice(header.as_bytes());
+            raw.extend_from_slice(b"\r\n\r\n");
+            raw.extend_from_slice(data);
+            raw.extend_from_slice(b"\r\n");
+        }
+        raw.extend_from_slice(b"--XyZ--\r\nThis is the epilogue\r\n");
+        raw
+    }
+
+    #[test]
+    fn multipart_form() {
+        let large: Vec<u8> = (0..200000).map(|i| if i % 100 == 99 { b'\n' } else { b'-' }).collect();
+        let raw = body(&[
+            ("Content-Disposition: form-data; name=\"title\"", b"Hello, \xD1\x84"),
+            ("content-disposition: form-data; name=\"note\"", b"line 1\r\n-XyZ\r\n--Xy\r\nline 2"),
+            ("Content-Disposition: form-data; name=\"doc\"; filename=\"C:\\Users\\me\\report.txt\"\r\nContent-Type: text/plain", b"report"),
+            ("Content-Disposition: form-data; name=\"doc\"; filename=\"fallback.bin\"; filename*=UTF-8''%D1%84%20%D0%B0.bin", &large),
+            ("Content-Disposition: form-data; name=\"empty\"; filename=\"\"\r\nContent-Type: application/octet-stream", b""),
+            ("Content-Disposition: form-data", b"no name"),
+        ]);
+        // In memory and spooled to the temp file the body is parsed the same
+        for spool in [0, 4096] {
+            let ((post, file, skipped), _) = multipart(&raw, spool, upload(0, 0, 0));
+            assert_eq!(post, vec![("title".to_owned(), "Hello, ф".to_owned()), ("note".to_owned(), "line 1\r\n-XyZ\r\n--Xy\r\nline 2".to_owned())]);
+            assert!(skipped.is_empty());
+            assert_eq!(file.len(), 1);
+            let doc = &file["doc"];
+            assert_eq!(doc.len(), 2);
+            assert_eq!(doc[0].name, "report.txt");
+            assert_eq!(doc[0].content_type, "text/plain");
+            assert_eq!(doc[0].size, 6);
+            assert_eq!(fs::read(&doc[0].tmp).unwrap(), b"report");
+            assert_eq!(doc[1].name, "ф а.bin");
+            assert_eq!(doc[1].content_type, "application/octet-stream");
+            assert_eq!(doc[1].size, large.len());
+            assert_eq!(fs::read(&doc[1].tmp).unwrap(), large);
+        }
+    }
+
+    #[test]
+    fn multipart_limits() {
+        let raw = body(&[
+            ("Content-Disposition: form-data; name=\"short\"", b"abc"),
+            ("Content-Disposition: form-data; name=\"long\"", b"abcdef"),
+            ("Content-Disposition: form-data; name=\"a\"; filename=\"a.txt\"", b"aaa"),
+            ("Content-Disposition: form-data; name=\"b\"; filename=\"b.txt\"", b"bbbbbb"),
+            ("Content-Disposition: form-data; name=\"c\"; filename=\"c.txt\"", b"ccc"),
+            ("Content-Disposition: form-data; name=\"d\"; filename=\"d.txt\"", b"ddd"),
+        ]);
+        let ((post, file, skipped), text) = multipart(&raw, 0, upload(4, 3, 4));
+        assert_eq!(post, vec![("short".to_owned(), "abc".to_owned())]);
+        let mut names: Vec<&String> = file.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["a", "c"]);
+        assert_eq!(skipped, vec!["b", "d"]);
+        assert!(text.contains("1025 => "));
+        assert!(text.contains("1026 => "));
+        assert!(text.contains("1027 => The form data is larger than max_form, it is skipped: field=long"));
+    }
+
+    #[test]
+    fn multipart_broken() {
+        // No parts, the boundary is missing, the body is cut in the middle of the part
+        let ((post, file, _), _) = multipart(b"--XyZ--\r\n", 0, upload(0, 0, 0));
+        assert!(post.is_empty() && file.is_empty());
+        let ((post, _, _), _) = multipart(b"just text", 0, upload(0, 0, 0));
+        assert!(post.is_empty());
+        let raw = b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--XyZ\r\nContent-Disposition: form-data; name=\"b\"\r\n\r\n2";
+        let ((post, _, _), _) = multipart(raw, 0, upload(0, 0, 0));
+        assert_eq!(post, vec![("a".to_owned(), "1".to_owned())]);
+        // The headers without the empty line
+        let ((post, _, _), _) = multipart(b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"", 0, upload(0, 0, 0));
+        assert!(post.is_empty());
+        // The spaces after the boundary are allowed
+        let ((post, _, _), _) = multipart(b"--XyZ \t\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--XyZ--", 0, upload(0, 0, 0));
+        assert_eq!(post, vec![("a".to_owned(), "1".to_owned())]);
+    }
+
+    #[test]
+    fn header_params() {
+        let (value, params) = Action::get_header_params(" Form-Data; NAME=\"a;b\"; filename=\"C:\\dir\\x \\\"y\\\".txt\"; flag; size = 10 ");
+        assert_eq!(value, "form-data");
+        assert_eq!(params, vec![
+            ("name".to_owned(), "a;b".to_owned()),
+            ("filename".to_owned(), "C:\\dir\\x \"y\".txt".to_owned()),
+            ("size".to_owned(), "10".to_owned()),
+        ]);
+        let (value, params) = Action::get_header_params("multipart/form-data; boundary=----WebKitFormBoundary7MA4YWxk");
+        assert_eq!(value, "multipart/form-data");
+        assert_eq!(params, vec![("boundary".to_owned(), "----WebKitFormBoundary7MA4YWxk".to_owned())]);
+        assert_eq!(Action::get_header_params(""), (String::new(), vec![]));
+    }
+
+    #[test]
+    fn ext_value() {
+        assert_eq!(Action::get_ext_value("UTF-8''%D1%84%20a+b.txt").as_deref(), Some("ф a+b.txt"));
+        assert_eq!(Action::get_ext_value("utf-8'uk'%D1%84").as_deref(), Some("ф"));
+        assert_eq!(Action::get_ext_value("iso-8859-1'en'%E9t%E9").as_deref(), Some("été"));
+        assert_eq!(Action::get_ext_value("koi8-r''%C6"), None);
+        assert_eq!(Action::get_ext_value("utf-8'%D1%84"), None);
+        assert_eq!(Action::get_ext_value("report.txt"), None);
+    }
+
+    #[test]
+    fn part_header() {
+        assert_eq!(Action::get_part_header("Content-Disposition: form-data; name=\"f\"; filename=\"/home/me/a.png\"\r\nContent-Type: image/png"), ("f".to_owned(), Some("a.png".to_owned()), "image/png".to_owned()));
+        assert_eq!(Action::get_part_header("CONTENT-DISPOSITION: form-data; name=f"), ("f".to_owned(), None, "application/octet-stream".to_owned()));
+        assert_eq!(Action::get_part_header(""), (String::new(), None, "application/octet-stream".to_owned()));
+    }
+
+    #[test]
+    fn cookie() {
+        assert_eq!(Cookie::new("theme", "dark").to_header().unwrap(), "theme=dark; Path=/; HttpOnly; SameSite=Lax");
+        let cookie = Cookie {
+            name: "id".to_owned(),
+            value: "\"a1b2\"".to_owned(),
+            path: None,
+            domain: Some("example.com".to_owned()),
+            expires: Some(Utc.with_ymd_and_hms(2030, 1, 2, 3, 4, 5).unwrap()),
+            max_age: Some(0),
+            http_only: false,
+            secure: true,
+            same_site: Some(SameSite::None),
+        };
+        assert_eq!(cookie.to_header().unwrap(), "id=\"a1b2\"; Expires=Wed, 02 Jan 2030 03:04:05 GMT; Max-Age=0; Domain=example.com; Secure; SameSite=None");
+        assert_eq!(Cookie::new("empty", "").to_header().unwrap(), "empty=; Path=/; HttpOnly; SameSite=Lax");
+    }
+
+    #[test]
+    fn cookie_injection() {
+        for name in ["", "a b", "a=b", "a;b", "a,b", "a\"b", "a\r\nb", "ф"] {
+            assert!(Cookie::new(name, "1").to_header().is_none(), "name {:?}", name);
+        }
+        for value in ["1; Domain=evil.com", "a b", "a,b", "a\\b", "\"a\"b\"", "a\r\nSet-Cookie: x=1", "a\tb", "ф"] {
+            assert!(Cookie::new("id", value).to_header().is_none(), "value {:?}", value);
+        }
+        let mut cookie = Cookie::new("id", "1");
+        cookie.path = Some("/; Domain=evil.com".to_owned());
+        assert!(cookie.to_header().is_none());
+        let mut cookie = Cookie::new("id", "1");
+        cookie.domain = Some("example.com\r\nX-Evil: 1".to_owned());
+        assert!(cookie.to_header().is_none());
+        // The value may have '=' and the path may have spaces
+        let mut cookie = Cookie::new("id", "a=b=");
+        cookie.path = Some("/my docs".to_owned());
+        assert_eq!(cookie.to_header().unwrap(), "id=a=b=; Path=/my docs; HttpOnly; SameSite=Lax");
+    }
+}
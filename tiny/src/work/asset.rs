@@ -0,0 +1,360 @@
+use std::{collections::HashMap, fs::{self, File}, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::SystemTime};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use crate::sys::log::Log;
+
+use super::action::Action;
+
+// Smaller files are sent in one answer, larger ones are streamed by parts
+const ASSET_MEMORY_LEN: u64 = 1048576;
+const ASSET_BUFFER_LEN: usize = 65536;
+
+// Static files from the DOCUMENT_ROOT, when tiny runs without nginx. Only the built-in HTTP server uses it,
+// behind the FastCGI web server the static files are its job.
+pub struct Asset {}
+
+impl Asset {
+
+    // Answer with the static file. None - the url isn't a file, so it goes to the routing.
+    pub fn run(param: &HashMap<String, String>, dir: &str, id: &str, stream: &mut dyn Write, log: Arc<Mutex<Log>>) -> Option<Vec<u8>> {
+        let method = param.get("REQUEST_METHOD").map(|m| m.as_str()).unwrap_or("GET");
+        if method != "GET" && method != "HEAD" {
+            return None;
+        }
+        let root = match param.get("DOCUMENT_ROOT") {
+            Some(r) => PathBuf::from(r),
+            None => Path::new(dir).join("www"),
+        };
+        let url = param.get("REDIRECT_URL")?.split('?').next()?;
+        let path = Asset::resolve(&root, url)?;
+        let meta = fs::metadata(&path).ok()?;
+        if !meta.is_file() {
+            return None;
+        }
+        let size = meta.len();
+        let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        let mtime = modified.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let etag = format!("\"{:x}-{:x}\"", mtime, size);
+        let last_modified = DateTime::<Utc>::from(modified).format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+
+        let mut header: Vec<String> = Vec::with_capacity(8);
+        header.push(format!("ETag: {}\r\n", etag));
+        header.push(format!("Last-Modified: {}\r\n", last_modified));
+        header.push("Accept-Ranges: bytes\r\n".to_owned());
+
+        // Conditional request
+        let not_modified = match param.get("HTTP_IF_NONE_MATCH") {
+            Some(tags) => tags.split(',').any(|t| { let t = t.trim(); t == "*" || t.trim_start_matches("W/") == etag }),
+            None => match param.get("HTTP_IF_MODIFIED_SINCE") {
+                Some(since) => match NaiveDateTime::parse_from_str(since, "%a, %d %b %Y %H:%M:%S GMT") {
+                    Ok(since) => since.and_utc().timestamp() >= mtime as i64,
+                    Err(_) => false,
+                },
+                None => false,
+            },
+        };
+        if not_modified {
+            return Some(Asset::header(304, id, header, None));
+        }
+
+        // Range request. The range is ignored if If-Range doesn't match the file.
+        let range = match param.get("HTTP_RANGE") {
+            Some(r) => match param.get("HTTP_IF_RANGE") {
+                Some(i) if *i != etag && *i != last_modified => None,
+                _ => Some(r),
+            },
+            None => None,
+        };
+        let (code, start, len) = match range {
+            Some(r) => match Asset::range(r, size) {
+                Some(Some((start, end))) => {
+                    header.push(format!("Content-Range: bytes {}-{}/{}\r\n", start, end, size));
+                    (206, start, end - start + 1)
+                },
+                Some(None) => {
+                    header.push(format!("Content-Range: bytes */{}\r\n", size));
+                    return Some(Asset::header(416, id, header, Some(0)));
+                },
+                None => (200, 0, size),
+            },
+            None => (200, 0, size),
+        };
+        header.push(format!("Content-Type: {}\r\n", Asset::mime(&path)));
+
+        let mut file = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                Log::push_warning(log, 1300, Some(format!("filename={}. Error={}", path.display(), e)));
+                return Some(Asset::header(500, id, Vec::new(), Some(0)));
+            },
+        };
+        if start > 0 {
+            if let Err(e) = file.seek(SeekFrom::Start(start)) {
+                Log::push_warning(log, 1300, Some(format!("filename={}. Error={}", path.display(), e)));
+                return Some(Asset::header(500, id, Vec::new(), Some(0)));
+            }
+        }
+        let mut answer = Asset::header(code, id, header, Some(len));
+        if method == "HEAD" {
+            return Some(answer);
+        }
+        let mut file = file.take(len);
+        if len <= ASSET_MEMORY_LEN {
+            if let Err(e) = file.read_to_end(&mut answer) {
+                Log::push_warning(log, 1300, Some(format!("filename={}. Error={}", path.display(), e)));
+                return Some(Asset::header(500, id, Vec::new(), Some(0)));
+            }
+            return Some(answer);
+        }
+        // The large file is streamed, the header is sent with the first part
+        let mut buf = vec![0; ASSET_BUFFER_LEN];
+        loop {
+            let size = match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(s) => s,
+                Err(e) => {
+                    Log::push_warning(log, 1300, Some(format!("filename={}. Error={}", path.display(), e)));
+                    break;
+                },
+            };
+            answer.extend_from_slice(&buf[..size]);
+            if stream.write_all(&answer).is_err() {
+                break;
+            }
+            answer.clear();
+        }
+        Some(Vec::new())
+    }
+
+    // Path of the file inside the root. The path outside the root is never returned.
+    fn resolve(root: &Path, url: &str) -> Option<PathBuf> {
+        let url = Asset::decode(url)?;
+        let mut path = root.to_path_buf();
+        for part in url.split('/') {
+            match part {
+                "" | "." => {},
+                ".." => return None,
+                p if p.contains('\\') || p.contains('\0') => return None,
+                p => path.push(p),
+            }
+        }
+        let root = fs::canonicalize(root).ok()?;
+        let path = fs::canonicalize(path).ok()?;
+        if path.starts_with(&root) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    // Percent-decoding of the url path
+    fn decode(url: &str) -> Option<String> {
+        let bytes = url.as_bytes();
+        let mut vec = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' {
+                let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+                vec.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            } else {
+                vec.push(bytes[i]);
+                i += 1;
+            }
+        }
+        String::from_utf8(vec).ok()
+    }
+
+    // Single range "bytes=start-end", "bytes=start-" or "bytes=-suffix".
+    // None - the range is ignored and the whole file is sent, Some(None) - the range is not satisfiable.
+    fn range(range: &str, size: u64) -> Option<Option<(u64, u64)>> {
+        let range = range.trim().strip_prefix("bytes=")?;
+        if range.contains(',') {
+            return None;
+        }
+        let (start, end) = range.split_once('-')?;
+        let (start, end) = match (start.trim(), end.trim()) {
+            ("", "") => return None,
+            ("", suffix) => {
+                let suffix = suffix.parse::<u64>().ok()?;
+                if suffix == 0 || size == 0 {
+                    return Some(None);
+                }
+                (size.saturating_sub(suffix), size - 1)
+            },
+            (start, "") => (start.parse::<u64>().ok()?, size.saturating_sub(1)),
+            (start, end) => {
+                let start = start.parse::<u64>().ok()?;
+                let end = end.parse::<u64>().ok()?;
+                if end < start {
+                    return None;
+                }
+                (start, end.min(size.saturating_sub(1)))
+            },
+        };
+        if start >= size {
+            return Some(None);
+        }
+        Some(Some((start, end)))
+    }
+
+    fn header(code: u16, id: &str, header: Vec<String>, len: Option<u64>) -> Vec<u8> {
+        let mut answer = format!("HTTP/1.1 {}\r\nX-Request-Id: {}\r\n", Action::http_code_get(code), id);
+        for h in header {
+            answer.push_str(&h);
+        }
+        if let Some(len) = len {
+            answer.push_str(&format!("Content-Length: {}\r\n", len));
+        }
+        answer.push_str("\r\n");
+        answer.into_bytes()
+    }
+
+    fn mime(path: &Path) -> &'static str {
+        let ext = match path.extension().and_then(|e| e.to_str()) {
+            Some(e) => e.to_lowercase(),
+            None => return "application/octet-stream",
+        };
+        match ext.as_str() {
+            "html" | "htm" => "text/html; charset=utf-8",
+            "css" => "text/css; charset=utf-8",
+            "js" | "mjs" => "text/javascript; charset=utf-8",
+            "json" | "map" => "application/json",
+            "txt" => "text/plain; charset=utf-8",
+            "xml" => "application/xml",
+            "csv" => "text/csv; charset=utf-8",
+            "svg" => "image/svg+xml",
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "avif" => "image/avif",
+            "ico" => "image/x-icon",
+            "bmp" => "image/bmp",
+            "woff" => "font/woff",
+            "woff2" => "font/woff2",
+            "ttf" => "font/ttf",
+            "otf" => "font/otf",
+            "eot" => "application/vnd.ms-fontobject",
+            "pdf" => "application/pdf",
+            "zip" => "application/zip",
+            "wasm" => "application/wasm",
+            "mp3" => "audio/mpeg",
+            "ogg" => "audio/ogg",
+            "wav" => "audio/wav",
+            "mp4" => "video/mp4",
+            "webm" => "video/webm",
+            _ => "application/octet-stream",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, io::sink, os::unix::fs::symlink, path::Path};
+
+    use crate::sys::log::Log;
+
+    use super::Asset;
+
+    // Document root "www" with one file and the secret file next to it
+    fn root() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("www/css")).unwrap();
+        fs::write(dir.path().join("www/css/site.css"), "body { color: red; }").unwrap();
+        fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        dir
+    }
+
+    fn get(root: &Path, method: &str, url: &str, header: &[(&str, &str)]) -> Option<String> {
+        let mut param = HashMap::new();
+        param.insert("REQUEST_METHOD".to_owned(), method.to_owned());
+        param.insert("REDIRECT_URL".to_owned(), url.to_owned());
+        param.insert("DOCUMENT_ROOT".to_owned(), root.join("www").display().to_string());
+        for (name, value) in header {
+            param.insert((*name).to_owned(), (*value).to_owned());
+        }
+        Asset::run(&param, "", "rid-1", &mut sink(), Log::new()).map(|a| String::from_utf8(a).unwrap())
+    }
+
+    fn etag(answer: &str) -> &str {
+        answer.lines().find_map(|l| l.strip_prefix("ETag: ")).unwrap()
+    }
+
+    #[test]
+    fn range() {
+        assert_eq!(Asset::range("bytes=0-4", 10), Some(Some((0, 4))));
+        assert_eq!(Asset::range("bytes=5-", 10), Some(Some((5, 9))));
+        assert_eq!(Asset::range("bytes=8-100", 10), Some(Some((8, 9))));
+        assert_eq!(Asset::range("bytes=-3", 10), Some(Some((7, 9))));
+        assert_eq!(Asset::range("bytes=-20", 10), Some(Some((0, 9))));
+        assert_eq!(Asset::range("bytes=10-", 10), Some(None));
+        assert_eq!(Asset::range("bytes=-0", 10), Some(None));
+        assert_eq!(Asset::range("bytes=0-", 0), Some(None));
+        // The whole file is sent
+        assert_eq!(Asset::range("bytes=5-2", 10), None);
+        assert_eq!(Asset::range("bytes=0-1,3-4", 10), None);
+        assert_eq!(Asset::range("bytes=-", 10), None);
+        assert_eq!(Asset::range("bytes=a-", 10), None);
+        assert_eq!(Asset::range("items=0-4", 10), None);
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(Asset::decode("/a%20b/%D1%84.css").as_deref(), Some("/a b/ф.css"));
+        assert_eq!(Asset::decode("/a%2"), None);
+        assert_eq!(Asset::decode("/a%zz"), None);
+        assert_eq!(Asset::decode("/a%ff"), None);
+    }
+
+    #[test]
+    fn resolve() {
+        let dir = root();
+        let www = dir.path().join("www");
+        assert_eq!(Asset::resolve(&www, "/css/./site.css"), Some(fs::canonicalize(www.join("css/site.css")).unwrap()));
+        assert_eq!(Asset::resolve(&www, "/../secret.txt"), None);
+        assert_eq!(Asset::resolve(&www, "/css/%2e%2e/%2E%2E/secret.txt"), None);
+        assert_eq!(Asset::resolve(&www, "/css%2f..%2f..%2fsecret.txt"), None);
+        assert_eq!(Asset::resolve(&www, "/..%5csecret.txt"), None);
+        assert_eq!(Asset::resolve(&www, "/missing.css"), None);
+        // The link inside the root to the file outside it
+        symlink(dir.path().join("secret.txt"), www.join("link.txt")).unwrap();
+        assert_eq!(Asset::resolve(&www, "/link.txt"), None);
+    }
+
+    #[test]
+    fn answer() {
+        let dir = root();
+        let answer = get(dir.path(), "GET", "/css/site.css?v=1", &[]).unwrap();
+        assert!(answer.starts_with("HTTP/1.1 200 OK\r\nX-Request-Id: rid-1\r\n"));
+        assert!(answer.contains("Content-Type: text/css; charset=utf-8\r\n"));
+        assert!(answer.ends_with("Content-Length: 20\r\n\r\nbody { color: red; }"));
+        let etag = etag(&answer).to_owned();
+
+        let head = get(dir.path(), "HEAD", "/css/site.css", &[]).unwrap();
+        assert!(head.ends_with("Content-Length: 20\r\n\r\n"));
+
+        let cached = get(dir.path(), "GET", "/css/site.css", &[("HTTP_IF_NONE_MATCH", &format!("\"x\", W/{}", etag))]).unwrap();
+        assert!(cached.starts_with("HTTP/1.1 304 "));
+        assert!(!cached.contains("Content-Length"));
+
+        let part = get(dir.path(), "GET", "/css/site.css", &[("HTTP_RANGE", "bytes=0-3"), ("HTTP_IF_RANGE", &etag)]).unwrap();
+        assert!(part.starts_with("HTTP/1.1 206 "));
+        assert!(part.contains("Content-Range: bytes 0-3/20\r\n"));
+        assert!(part.ends_with("\r\n\r\nbody"));
+
+        // The file has changed since the client got the part
+        let whole = get(dir.path(), "GET", "/css/site.css", &[("HTTP_RANGE", "bytes=0-3"), ("HTTP_IF_RANGE", "\"old\"")]).unwrap();
+        assert!(whole.starts_with("HTTP/1.1 200 "));
+
+        let wrong = get(dir.path(), "GET", "/css/site.css", &[("HTTP_RANGE", "bytes=50-")]).unwrap();
+        assert!(wrong.starts_with("HTTP/1.1 416 "));
+        assert!(wrong.contains("Content-Range: bytes */20\r\n"));
+
+        // Not a file, so it goes to the routing
+        assert_eq!(get(dir.path(), "POST", "/css/site.css", &[]), None);
+        assert_eq!(get(dir.path(), "GET", "/css", &[]), None);
+        assert_eq!(get(dir.path(), "GET", "/../secret.txt", &[]), None);
+    }
+}
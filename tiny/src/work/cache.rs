@@ -37,7 +37,18 @@ impl Cache {
 
     pub fn del(cache: Arc<Mutex<Cache>>, key: &str, log: Arc<Mutex<Log>>) {
         match Mutex::lock(&cache) {
-            Ok(mut c) => c.data.retain(|k, _| k.starts_with(key)),
+            Ok(mut c) => c.data.retain(|k, _| !k.starts_with(key)),
+            Err(e) => Log::error(log, e.to_string()),
+        }
+    }
+
+    // The keys with these prefixes are replaced under one lock, so the worker never sees them half loaded
+    pub fn replace(cache: Arc<Mutex<Cache>>, prefixes: &[&str], list: Vec<(String, Data)>, log: Arc<Mutex<Log>>) {
+        match Mutex::lock(&cache) {
+            Ok(mut c) => {
+                c.data.retain(|k, _| !prefixes.iter().any(|p| k.starts_with(p)));
+                c.data.extend(list);
+            },
             Err(e) => Log::error(log, e.to_string()),
         }
     }
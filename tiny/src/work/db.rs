@@ -1,13 +1,100 @@
-use std::{sync::{Arc, Mutex}, fmt};
+use std::{sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, fmt, thread, time::Duration};
 
 use native_tls::Protocol;
-use postgres::{Client, Row, types::{ToSql, Type}, Statement, ToStatement};
+use postgres::{Client, Row, types::{ToSql, Type}, Statement, ToStatement, Error, fallible_iterator::FallibleIterator};
+#[cfg(not(feature = "async"))]
+pub use postgres::CancelToken;
 use postgres_native_tls::MakeTlsConnector;
+#[cfg(feature = "async")]
+use tokio::runtime::Handle;
 
 use crate::sys::log::Log;
 
 use super::{cache::Cache, action::Data};
 
+// The lost connection of the "tiny_reload" listener is restored after this time
+const DB_LISTEN_RETRY: Duration = Duration::from_secs(30);
+
+// Statements prepared for every connection, DB::query_fast takes them by the index
+const PREPARE: [(&str, &[Type]); 5] = [
+    // 0 Get / Insert session
+    ("
+        WITH 
+        new_q AS (
+            SELECT 0::int8 user_id, $1::text session, '\\x'::bytea data, now() created, now() last, $2 ip, $3 user_agent, $4 lang_id
+        ),
+        ins_q AS (
+            INSERT INTO session (user_id, session, data, created, last, ip, user_agent, lang_id) 
+            SELECT n.user_id, n.session, n.data, n.created, n.last, n.ip, n.user_agent, n.lang_id
+            FROM 
+            new_q n
+            LEFT JOIN session s ON s.session=n.session
+            WHERE s.session_id IS NULL
+            RETURNING session_id, data, user_id, lang_id
+        ),
+        res AS (
+            SELECT session_id, data, user_id, lang_id FROM ins_q
+            UNION 
+            SELECT session_id, data, user_id, lang_id FROM session WHERE session=$5
+        )
+        SELECT r.session_id, r.user_id, u.role_id, r.data, r.lang_id FROM res r INNER JOIN \"user\" u ON u.user_id=r.user_id
+    ", &[Type::TEXT, Type::TEXT, Type::TEXT, Type::INT8, Type::TEXT]),
+    // 1 Update session
+    ("
+        UPDATE session
+        SET 
+            user_id=$1,
+            data=$2,
+            last=now(),
+            ip=$3,
+            user_agent=$4,
+            lang_id=$5
+        WHERE
+            session_id=$6
+    ", &[Type::INT8, Type::BYTEA, Type::TEXT, Type::TEXT, Type::INT8, Type::INT8]),
+    // 2 Update session
+    ("
+        UPDATE session 
+        SET 
+            last = now()
+        WHERE
+            session_id=$1
+    ", &[Type::INT8]),
+    // 3 Get auth permissions
+    ("
+        SELECT COALESCE(MAX(a.access::int), 0)::bool AS access
+        FROM 
+            access a
+            INNER JOIN \"user\" u ON u.role_id=a.role_id
+            INNER JOIN controller c ON a.controller_id=c.controller_id
+        WHERE 
+            a.access AND u.user_id=$1 AND (
+                (c.module='' AND c.class='' AND c.action='')
+                OR (c.module=$2 AND c.class='' AND c.action='')
+                OR (c.module=$3 AND c.class=$5 AND c.action='')
+                OR (c.module=$4 AND c.class=$6 AND c.action=$7)
+            )
+    ", &[Type::INT8, Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT]),
+    // 4 Get url by route map
+    ("
+        SELECT r.url
+        FROM 
+            route r
+            INNER JOIN controller c ON c.controller_id=r.controller_id
+        WHERE c.module=$1 AND c.class=$2 AND c.action=$3 AND COALESCE(r.params, '')=$4 AND COALESCE(r.lang_id, -1)=$5
+    ", &[Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT, Type::INT8]),
+];
+
+// Routes and redirects as the keys of the cache
+const CACHE_REDIRECT: &str = "
+    SELECT url, redirect, permanently FROM redirect
+";
+const CACHE_ROUTE: &str = "
+    SELECT r.url, c.module, c.class, c.action, r.params, r.lang_id
+    FROM route r INNER JOIN controller c ON r.controller_id=c.controller_id
+    WHERE LENGTH(c.module)>0 AND LENGTH(c.class)>0 AND LENGTH(c.action)>0
+";
+
 #[derive(Debug, Clone)]
 pub struct DBConfig {
     pub host: String,
@@ -23,14 +110,76 @@ enum DBResult {
     ErrConnect(String),
 }
 
+// Connection of the controller. In the async mode it's the async client driven by the runtime,
+// the controller runs in the blocking pool and waits there for its queries.
+#[cfg(not(feature = "async"))]
+type Connection = Client;
+
+#[cfg(feature = "async")]
+struct Connection {
+    sql: tokio_postgres::Client,
+    rt: Handle,
+}
+
+#[cfg(feature = "async")]
+impl Connection {
+    fn is_closed(&self) -> bool {
+        self.sql.is_closed()
+    }
+
+    fn prepare(&mut self, query: &str) -> Result<Statement, Error> {
+        self.rt.block_on(self.sql.prepare(query))
+    }
+
+    fn execute(&mut self, statement: &Statement, params: &[&(dyn ToSql + Sync)]) -> Result<u64, Error> {
+        self.rt.block_on(self.sql.execute(statement, params))
+    }
+
+    fn cancel_token(&self) -> CancelToken {
+        CancelToken { token: self.sql.cancel_token(), rt: self.rt.clone() }
+    }
+}
+
+// Token to cancel the running query, the cancel request is sent by the task of the runtime
+#[cfg(feature = "async")]
+pub struct CancelToken {
+    token: tokio_postgres::CancelToken,
+    rt: Handle,
+}
+
+// Queries of the controller and of the one-time connections
+trait Query {
+    fn run<T: ?Sized + ToStatement>(&mut self, query: &T, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, Error>;
+}
+
+impl Query for Client {
+    fn run<T: ?Sized + ToStatement>(&mut self, query: &T, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, Error> {
+        self.query(query, params)
+    }
+}
+
+#[cfg(feature = "async")]
+impl Query for Connection {
+    fn run<T: ?Sized + ToStatement>(&mut self, query: &T, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, Error> {
+        self.rt.block_on(self.sql.query(query, params))
+    }
+}
+
+// The open connection with its prepared statements
+type Connected = Result<(Connection, Vec<(Statement, &'static str)>), String>;
+
 pub struct DB {
-    sql: Option<Client>,
+    sql: Option<Connection>,
     config: DBConfig,
     pub error: Option<String>,
     log: Arc<Mutex<Log>>,
     timezone: String,
     pub prepare: Vec<(Statement, &'static str)>,
     cache: Arc<Mutex<Cache>>,
+    abort: Arc<AtomicBool>,                 // The client has aborted the current request
+    request: Option<String>,                // Id of the request, it's set to the connection before the first query
+    applied: Option<String>,                // Id, which the connection has now
+    set_request: Option<Statement>,         // Prepared once for the connection, so setting the id is one round trip
 }
 
 impl fmt::Debug for DB {
@@ -55,6 +204,67 @@ impl fmt::Debug for DB {
 
 impl DB {
     pub fn one_time_query(config: &DBConfig, log: Arc<Mutex<Log>>, query: &str) -> Option<Vec<Row>> {
+        let mut sql = DB::one_time_connect(config, log)?;
+        match DB::exec(&mut sql, query, &[]) {
+            DBResult::Ok(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    // Load routes and redirects into the cache again
+    pub fn reload(config: &DBConfig, log: Arc<Mutex<Log>>, cache: Arc<Mutex<Cache>>) -> bool {
+        let mut sql = match DB::one_time_connect(config, Arc::clone(&log)) {
+            Some(s) => s,
+            None => return false,
+        };
+        DB::load_db_cache(&mut sql, log, cache)
+    }
+
+    // Clear the whole cache and load routes and redirects into it under one lock, so the worker never sees it empty.
+    // If the query fails, the cache is kept.
+    pub fn reset(config: &DBConfig, log: Arc<Mutex<Log>>, cache: Arc<Mutex<Cache>>) -> bool {
+        let mut sql = match DB::one_time_connect(config, Arc::clone(&log)) {
+            Some(s) => s,
+            None => return false,
+        };
+        match DB::read_db_cache(&mut sql, Arc::clone(&log)) {
+            Some(list) => {
+                // Every key starts with the empty prefix
+                Cache::replace(cache, &[""], list, log);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Routes and redirects are loaded again, when the database notifies on the "tiny_reload" channel.
+    // The connection is restored after DB_LISTEN_RETRY, the changes made meanwhile are loaded at once.
+    pub fn listen(config: DBConfig, log: Arc<Mutex<Log>>, cache: Arc<Mutex<Cache>>) {
+        thread::spawn(move || loop {
+            if let Some(mut sql) = DB::one_time_connect(&config, Arc::clone(&log)) {
+                match sql.batch_execute("LISTEN tiny_reload") {
+                    Ok(()) => {
+                        Log::push_info(Arc::clone(&log), 606, None);
+                        DB::load_db_cache(&mut sql, Arc::clone(&log), Arc::clone(&cache));
+                        loop {
+                            let table = match sql.notifications().blocking_iter().next() {
+                                Ok(Some(n)) => n.payload().to_owned(),
+                                _ => break,
+                            };
+                            // One statement on several tables sends a few notifications, they are loaded once
+                            let _ = sql.notifications().iter().count();
+                            Log::push_info(Arc::clone(&log), 607, Some(table));
+                            DB::load_db_cache(&mut sql, Arc::clone(&log), Arc::clone(&cache));
+                        }
+                    },
+                    Err(e) => Log::push_warning(Arc::clone(&log), 608, Some(e.to_string())),
+                };
+            }
+            thread::sleep(DB_LISTEN_RETRY);
+        });
+    }
+
+    fn one_time_connect(config: &DBConfig, log: Arc<Mutex<Log>>) -> Option<Client> {
         let connector = match native_tls::TlsConnector::builder().danger_accept_invalid_certs(true).min_protocol_version(Some(Protocol::Tlsv12)).build() {
             Ok(c) => c,
             Err(e) => {
@@ -63,23 +273,31 @@ impl DB {
             },
         };
         let builder = MakeTlsConnector::new(connector);
-        let conn_str = format!("host='{}' port='{}' dbname='{}' user='{}' password='{}' sslmode=require connect_timeout=2 application_name='{} {}' options='--client_encoding=UTF8'", config.host, config.port, config.name, config.user, config.pwd, &env!("CARGO_PKG_NAME"), &env!("CARGO_PKG_VERSION"));
+        let conn_str = DB::conn_str(config);
 
-        let mut sql = match Client::connect(&conn_str, builder) {
-            Ok(sql) => sql,
+        match Client::connect(&conn_str, builder) {
+            Ok(sql) => Some(sql),
             Err(e) => {
                 Log::push_warning(log, 601, Some(e.to_string()));
-                return None;
+                None
             },
-        };
-        match DB::exec(&mut sql, query, &[]) {
-            DBResult::Ok(r) => Some(r),
-            _ => None,
         }
     }
 
-    pub fn new(config: DBConfig, log: Arc<Mutex<Log>>, timezone: String, cache: Arc<Mutex<Cache>>) -> DB {
-        match DB::connect(&config, Arc::clone(&log), &timezone, Arc::clone(&cache)) {
+    pub fn new(config: DBConfig, log: Arc<Mutex<Log>>, timezone: String, cache: Arc<Mutex<Cache>>, abort: Arc<AtomicBool>) -> DB {
+        let connect = DB::connect(&config, Arc::clone(&log), &timezone, Arc::clone(&cache));
+        DB::init(config, log, timezone, cache, abort, connect)
+    }
+
+    // Connection of the controller, which is opened by the task of the runtime
+    #[cfg(feature = "async")]
+    pub async fn open(config: DBConfig, log: Arc<Mutex<Log>>, timezone: String, cache: Arc<Mutex<Cache>>, abort: Arc<AtomicBool>) -> DB {
+        let connect = DB::connect_async(&config, Arc::clone(&log), &timezone, Arc::clone(&cache)).await;
+        DB::init(config, log, timezone, cache, abort, connect)
+    }
+
+    fn init(config: DBConfig, log: Arc<Mutex<Log>>, timezone: String, cache: Arc<Mutex<Cache>>, abort: Arc<AtomicBool>, connect: Connected) -> DB {
+        match connect {
             Ok((db, prepare)) => {
                 DB {
                     sql: Some(db),
@@ -89,6 +307,10 @@ impl DB {
                     timezone,
                     prepare,
                     cache,
+                    abort,
+                    request: None,
+                    applied: None,
+                    set_request: None,
                 }
             },
             Err(e) => {
@@ -101,12 +323,21 @@ impl DB {
                     timezone,
                     prepare: Vec::new(),
                     cache,
+                    abort,
+                    request: None,
+                    applied: None,
+                    set_request: None,
                 }
             },
         }
     }
 
-    fn connect(config: &DBConfig, log: Arc<Mutex<Log>>, timezone: &str, cache: Arc<Mutex<Cache>>) -> Result<(Client, Vec<(Statement, &'static str)>), String> {
+    fn conn_str(config: &DBConfig) -> String {
+        format!("host='{}' port='{}' dbname='{}' user='{}' password='{}' sslmode=require connect_timeout=2 application_name='{} {}' options='--client_encoding=UTF8'", config.host, config.port, config.name, config.user, config.pwd, &env!("CARGO_PKG_NAME"), &env!("CARGO_PKG_VERSION"))
+    }
+
+    #[cfg(not(feature = "async"))]
+    fn connect(config: &DBConfig, log: Arc<Mutex<Log>>, timezone: &str, cache: Arc<Mutex<Cache>>) -> Connected {
         let connector = match native_tls::TlsConnector::builder().danger_accept_invalid_certs(true).min_protocol_version(Some(Protocol::Tlsv12)).build() {
             Ok(c) => c,
             Err(e) => {
@@ -115,7 +346,7 @@ impl DB {
             },
         };
         let builder = MakeTlsConnector::new(connector);
-        let conn_str = format!("host='{}' port='{}' dbname='{}' user='{}' password='{}' sslmode=require connect_timeout=2 application_name='{} {}' options='--client_encoding=UTF8'", config.host, config.port, config.name, config.user, config.pwd, &env!("CARGO_PKG_NAME"), &env!("CARGO_PKG_VERSION"));
+        let conn_str = DB::conn_str(config);
 
         let mut sql = match Client::connect(&conn_str, builder) {
             Ok(sql) => sql,
@@ -134,6 +365,96 @@ impl DB {
         Ok((sql, prepare))
     }
 
+    // The connection is restored by the controller, which runs in the blocking pool of the runtime
+    #[cfg(feature = "async")]
+    fn connect(config: &DBConfig, log: Arc<Mutex<Log>>, timezone: &str, cache: Arc<Mutex<Cache>>) -> Connected {
+        match Handle::try_current() {
+            Ok(rt) => rt.block_on(DB::connect_async(config, log, timezone, cache)),
+            Err(e) => {
+                Log::push_warning(log, 601, Some(e.to_string()));
+                Err(e.to_string())
+            },
+        }
+    }
+
+    #[cfg(feature = "async")]
+    async fn connect_async(config: &DBConfig, log: Arc<Mutex<Log>>, timezone: &str, cache: Arc<Mutex<Cache>>) -> Connected {
+        let connector = match native_tls::TlsConnector::builder().danger_accept_invalid_certs(true).min_protocol_version(Some(Protocol::Tlsv12)).build() {
+            Ok(c) => c,
+            Err(e) => {
+                Log::push_warning(log, 600, Some(e.to_string()));
+                return Err(e.to_string());
+            },
+        };
+        let (sql, connection) = match tokio_postgres::connect(&DB::conn_str(config), MakeTlsConnector::new(connector)).await {
+            Ok(c) => c,
+            Err(e) => {
+                Log::push_warning(log, 601, Some(e.to_string()));
+                return Err(e.to_string());
+            },
+        };
+        // The connection ends, when the client is dropped
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+
+        let query = format!("SET timezone TO '{}';", timezone);
+        if let Err(e) = sql.query(&query, &[]).await {
+            Log::push_warning(log, 602, Some(format!("{} error={} {}", query, e, timezone)));
+            return Err(e.to_string());
+        };
+        let mut prepare = Vec::with_capacity(PREPARE.len());
+        for (query, types) in PREPARE {
+            match sql.prepare_typed(query, types).await {
+                Ok(s) => prepare.push((s, query)),
+                Err(e) => Log::push_error(Arc::clone(&log), 604, Some(e.to_string())),
+            };
+        }
+        match (sql.query(CACHE_REDIRECT, &[]).await, sql.query(CACHE_ROUTE, &[]).await) {
+            (Ok(redirect), Ok(route)) => Cache::replace(cache, &["redirect:", "route:", "404", "auth:"], DB::cache_list(redirect, route), log),
+            _ => Log::push_warning(log, 609, None),
+        };
+        Ok((Connection { sql, rt: Handle::current() }, prepare))
+    }
+
+    // Token to cancel the running query from the other thread
+    pub fn cancel_token(&self) -> Option<CancelToken> {
+        self.sql.as_ref().map(|c| c.cancel_token())
+    }
+
+    // Cancel the running query of the stuck request
+    #[cfg(not(feature = "async"))]
+    pub fn cancel(token: CancelToken, log: Arc<Mutex<Log>>) {
+        let connector = match native_tls::TlsConnector::builder().danger_accept_invalid_certs(true).min_protocol_version(Some(Protocol::Tlsv12)).build() {
+            Ok(c) => c,
+            Err(e) => {
+                Log::push_warning(log, 600, Some(e.to_string()));
+                return;
+            },
+        };
+        if let Err(e) = token.cancel_query(MakeTlsConnector::new(connector)) {
+            Log::push_warning(log, 605, Some(e.to_string()));
+        }
+    }
+
+    // Cancel the running query of the stuck request, the caller doesn't wait for it
+    #[cfg(feature = "async")]
+    pub fn cancel(token: CancelToken, log: Arc<Mutex<Log>>) {
+        let connector = match native_tls::TlsConnector::builder().danger_accept_invalid_certs(true).min_protocol_version(Some(Protocol::Tlsv12)).build() {
+            Ok(c) => c,
+            Err(e) => {
+                Log::push_warning(log, 600, Some(e.to_string()));
+                return;
+            },
+        };
+        let CancelToken { token, rt } = token;
+        rt.spawn(async move {
+            if let Err(e) = token.cancel_query(MakeTlsConnector::new(connector)).await {
+                Log::push_warning(log, 605, Some(e.to_string()));
+            }
+        });
+    }
+
     pub fn is_not_empty(&self) -> bool {
         match self.sql {
             Some(_) => true,
@@ -141,6 +462,49 @@ impl DB {
         }
     }
 
+    // Queries of the aborted request are not executed
+    fn is_aborted(&mut self) -> bool {
+        if self.abort.load(Ordering::Acquire) {
+            self.error = Some("Request aborted".to_owned());
+            return true;
+        }
+        false
+    }
+
+    // The id is sent with the first query of the request, so the request without queries costs nothing
+    pub fn set_request(&mut self, id: &str) {
+        self.request = Some(id.to_owned());
+    }
+
+    // application_name shows the request in pg_stat_activity, tiny.request_id is for the triggers and functions.
+    // The idle connection keeps the id of its last request, so the same id isn't sent again.
+    fn apply_request(&mut self) {
+        let id = match self.request.take() {
+            Some(id) if self.applied.as_ref() != Some(&id) => id,
+            _ => return,
+        };
+        let c = match &mut self.sql {
+            Some(c) => c,
+            None => return,
+        };
+        if self.set_request.is_none() {
+            match c.prepare("SELECT set_config('application_name', $1, false), set_config('tiny.request_id', $2, false)") {
+                Ok(s) => self.set_request = Some(s),
+                Err(e) => {
+                    Log::push_warning(Arc::clone(&self.log), 610, Some(e.to_string()));
+                    return;
+                },
+            };
+        }
+        if let Some(statement) = &self.set_request {
+            let name = format!("{} {}", env!("CARGO_PKG_NAME"), id);
+            match c.execute(statement, &[&name, &id]) {
+                Ok(_) => self.applied = Some(id),
+                Err(e) => Log::push_warning(Arc::clone(&self.log), 610, Some(e.to_string())),
+            };
+        }
+    }
+
     pub fn check(&mut self) {
         let close = match &self.sql {
             Some(c) => c.is_closed(),
@@ -151,6 +515,8 @@ impl DB {
                 Ok((db, prepare)) => {
                     self.sql = Some(db);
                     self.prepare = prepare;
+                    self.applied = None;
+                    self.set_request = None;
                     self.error = None;
                 },
                 Err(e) => {
@@ -163,6 +529,7 @@ impl DB {
     }
 
     pub fn query_fast(&mut self, index: usize, params: &[&(dyn ToSql + Sync)]) -> Option<Vec<Row>> {
+        self.apply_request();
         match &mut self.sql {
             Some(c) => {
                 let (statement, source)= match self.prepare.get(index) {
@@ -192,6 +559,10 @@ impl DB {
     }
 
     pub fn query_params(&mut self, query: &str, params: &[&(dyn ToSql + Sync)]) -> Option<Vec<Row>> {
+        if self.is_aborted() {
+            return None;
+        }
+        self.apply_request();
         match &mut self.sql {
             Some(c) => match DB::exec(c, query, params) {
                 DBResult::Ok(r) => {
@@ -215,6 +586,10 @@ impl DB {
     }
     
     pub fn query(&mut self, query: &str) -> Option<Vec<Row>> {
+        if self.is_aborted() {
+            return None;
+        }
+        self.apply_request();
         match &mut self.sql {
             Some(c) => match DB::exec(c, query, &[]) {
                 DBResult::Ok(r) => {
@@ -237,11 +612,12 @@ impl DB {
         }
     }
 
-    fn exec<T>(sql: &mut Client, query: &T, params: &[&(dyn ToSql + Sync)]) -> DBResult 
+    fn exec<C, T>(sql: &mut C, query: &T, params: &[&(dyn ToSql + Sync)]) -> DBResult 
     where
+        C: Query,
         T: ?Sized + ToStatement,
     {
-        match sql.query(query, params) {
+        match sql.run(query, params) {
             Ok(res) => DBResult::Ok(res),
             Err(e) => if e.is_closed() {
                 DBResult::ErrConnect(e.to_string())
@@ -251,185 +627,99 @@ impl DB {
         }
     }
     
+    #[cfg(not(feature = "async"))]
     fn prepare(db: &mut Client, log: Arc<Mutex<Log>>, cache: Arc<Mutex<Cache>>) -> Vec<(Statement, &'static str)> {
-        let mut vec = Vec::with_capacity(64);
-        // 0 Get / Insert session
-        let sql = "
-            WITH 
-            new_q AS (
-                SELECT 0::int8 user_id, $1::text session, '\\x'::bytea data, now() created, now() last, $2 ip, $3 user_agent, $4 lang_id
-            ),
-            ins_q AS (
-                INSERT INTO session (user_id, session, data, created, last, ip, user_agent, lang_id) 
-                SELECT n.user_id, n.session, n.data, n.created, n.last, n.ip, n.user_agent, n.lang_id
-                FROM 
-                new_q n
-                LEFT JOIN session s ON s.session=n.session
-                WHERE s.session_id IS NULL
-                RETURNING session_id, data, user_id, lang_id
-            ),
-            res AS (
-                SELECT session_id, data, user_id, lang_id FROM ins_q
-                UNION 
-                SELECT session_id, data, user_id, lang_id FROM session WHERE session=$5
-            )
-            SELECT r.session_id, r.user_id, u.role_id, r.data, r.lang_id FROM res r INNER JOIN \"user\" u ON u.user_id=r.user_id
-        ";
-        match db.prepare_typed(sql, &[Type::TEXT, Type::TEXT, Type::TEXT, Type::INT8, Type::TEXT]) {
-            Ok(s) => {
-                vec.push((s, sql));
-            },
-            Err(e) => Log::push_error(log, 604, Some(e.to_string())),
-        };
-        
-        // 1 Update session
-        let sql = "
-            UPDATE session
-            SET 
-                user_id=$1,
-                data=$2,
-                last=now(),
-                ip=$3,
-                user_agent=$4,
-                lang_id=$5
-            WHERE
-                session_id=$6
-        ";
-        match db.prepare_typed(sql, &[Type::INT8, Type::BYTEA, Type::TEXT, Type::TEXT, Type::INT8, Type::INT8]) {
-            Ok(s) => {
-                vec.push((s, sql));
-            },
-            Err(e) => Log::push_error(log, 604, Some(e.to_string())),
-        };
-
-        // 2 Update session
-        let sql = "
-            UPDATE session 
-            SET 
-                last = now()
-            WHERE
-                session_id=$1
-        ";
-        match db.prepare_typed(sql, &[Type::INT8]) {
-            Ok(s) => {
-                vec.push((s, sql));
-            },
-            Err(e) => Log::push_error(log, 604, Some(e.to_string())),
-        };
+        let mut vec = Vec::with_capacity(PREPARE.len());
+        for (sql, types) in PREPARE {
+            match db.prepare_typed(sql, types) {
+                Ok(s) => vec.push((s, sql)),
+                Err(e) => Log::push_error(Arc::clone(&log), 604, Some(e.to_string())),
+            };
+        }
+        DB::load_db_cache(db, log, cache);
+        vec
+    }
 
-        // 3 Get auth permissions
-        let sql = "
-            SELECT COALESCE(MAX(a.access::int), 0)::bool AS access
-            FROM 
-                access a
-                INNER JOIN \"user\" u ON u.role_id=a.role_id
-                INNER JOIN controller c ON a.controller_id=c.controller_id
-            WHERE 
-                a.access AND u.user_id=$1 AND (
-                    (c.module='' AND c.class='' AND c.action='')
-                    OR (c.module=$2 AND c.class='' AND c.action='')
-                    OR (c.module=$3 AND c.class=$5 AND c.action='')
-                    OR (c.module=$4 AND c.class=$6 AND c.action=$7)
-                )
-        ";
-        match db.prepare_typed(sql, &[Type::INT8, Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT]) {
-            Ok(s) => {
-                vec.push((s, sql));
+    // Routes and redirects are loaded into the cache at once, the permissions are cached again on demand.
+    // If the query fails, the old keys are kept.
+    fn load_db_cache(db: &mut Client, log: Arc<Mutex<Log>>, cache: Arc<Mutex<Cache>>) -> bool {
+        match DB::read_db_cache(db, Arc::clone(&log)) {
+            Some(list) => {
+                Cache::replace(cache, &["redirect:", "route:", "404", "auth:"], list, log);
+                true
             },
-            Err(e) => Log::push_error(log, 604, Some(e.to_string())),
-        };
+            None => false,
+        }
+    }
 
-        // 4 Get url by route map
-        let sql = "
-            SELECT r.url
-            FROM 
-                route r
-                INNER JOIN controller c ON c.controller_id=r.controller_id
-            WHERE c.module=$1 AND c.class=$2 AND c.action=$3 AND COALESCE(r.params, '')=$4 AND COALESCE(r.lang_id, -1)=$5
-        ";
-        match db.prepare_typed(sql, &[Type::TEXT, Type::TEXT, Type::TEXT, Type::TEXT, Type::INT8]) {
-            Ok(s) => {
-                vec.push((s, sql));
+    // Routes and redirects as the keys of the cache
+    fn read_db_cache(db: &mut Client, log: Arc<Mutex<Log>>) -> Option<Vec<(String, Data)>> {
+        match (DB::exec(db, CACHE_REDIRECT, &[]), DB::exec(db, CACHE_ROUTE, &[])) {
+            (DBResult::Ok(redirect), DBResult::Ok(route)) => Some(DB::cache_list(redirect, route)),
+            _ => {
+                Log::push_warning(log, 609, None);
+                None
             },
-            Err(e) => Log::push_error(log, 604, Some(e.to_string())),
-        };
-
-        DB::load_db_cache(db, log, cache);
-        vec
+        }
     }
 
-    fn load_db_cache(db: &mut Client, log: Arc<Mutex<Log>>, cache: Arc<Mutex<Cache>>) {
-        let sql = "
-            SELECT url, redirect, permanently FROM redirect
-        ";
-        Cache::del(Arc::clone(&cache), "redirect", Arc::clone(&log));
-        if let DBResult::Ok(res) = DB::exec(db, sql, &[]) {
-            let mut url: String;
-            let mut key: String;
-            let mut redirect: String;
-            let mut permanently: bool;
-            let mut value: String;
-            for row in res {
-                url = row.get(0);
-                key = format!("redirect:{}", &url);
-                redirect = row.get(1);
-                permanently = row.get(2);
-                value = if permanently {
-                    format!("1{}", &redirect)
-                } else {
-                    format!("0{}", &redirect)
-                };
-                Cache::set(Arc::clone(&cache), key, Data::String(value), Arc::clone(&log));
-            }
-        };
+    fn cache_list(redirects: Vec<Row>, routes: Vec<Row>) -> Vec<(String, Data)> {
+        let mut list = Vec::new();
+        let mut url: String;
+        let mut key: String;
+        let mut redirect: String;
+        let mut permanently: bool;
+        let mut value: String;
+        for row in redirects {
+            url = row.get(0);
+            key = format!("redirect:{}", &url);
+            redirect = row.get(1);
+            permanently = row.get(2);
+            value = if permanently {
+                format!("1{}", &redirect)
+            } else {
+                format!("0{}", &redirect)
+            };
+            list.push((key, Data::String(value)));
+        }
 
-        let sql = "
-            SELECT r.url, c.module, c.class, c.action, r.params, r.lang_id
-            FROM route r INNER JOIN controller c ON r.controller_id=c.controller_id
-            WHERE LENGTH(c.module)>0 AND LENGTH(c.class)>0 AND LENGTH(c.action)>0
-        ";
-        Cache::del(Arc::clone(&cache), "route", Arc::clone(&log));
-        Cache::del(Arc::clone(&cache), "404", Arc::clone(&log));
-        if let DBResult::Ok(res) = DB::exec(db, sql, &[]) {
-            let mut url: String;
-            let mut key: String;
-            let mut module: String;
-            let mut class: String;
-            let mut action: String;
-            let mut param: Option<String>;
-            let mut lang_id: Option<i64>;
-            let mut data = Vec::with_capacity(5);
-
-            for row in res {
-                url = row.get(0);
-                key = format!("route:{}", &url);
-                module = row.get(1);
-                class = row.get(2);
-                action = row.get(3);
-                param = row.get(4);
-                lang_id = row.get(5);
-                data.clear();
-                if &module == "index" && &class == "index" && action == "not_found" {
-                    match &lang_id {
-                        Some(i) => Cache::set(Arc::clone(&cache), format!("404:{}", *i), Data::String(url), Arc::clone(&log)),
-                        None => Cache::set(Arc::clone(&cache), "404".to_owned(), Data::String(url), Arc::clone(&log)),
-                    };
-                }
-                data.push(Data::String(module));
-                data.push(Data::String(class));
-                data.push(Data::String(action));
-                match &param {
-                    Some(s) => data.push(Data::String(s.clone())),
-                    None => data.push(Data::None),
-                };
+        let mut module: String;
+        let mut class: String;
+        let mut action: String;
+        let mut param: Option<String>;
+        let mut lang_id: Option<i64>;
+        let mut data = Vec::with_capacity(5);
+
+        for row in routes {
+            url = row.get(0);
+            key = format!("route:{}", &url);
+            module = row.get(1);
+            class = row.get(2);
+            action = row.get(3);
+            param = row.get(4);
+            lang_id = row.get(5);
+            data.clear();
+            if &module == "index" && &class == "index" && action == "not_found" {
                 match &lang_id {
-                    Some(i) => data.push(Data::U64(*i as u64)),
-                    None => data.push(Data::None),
+                    Some(i) => list.push((format!("404:{}", *i), Data::String(url))),
+                    None => list.push(("404".to_owned(), Data::String(url))),
                 };
-
-                Cache::set(Arc::clone(&cache), key, Data::Vec(data.clone()), Arc::clone(&log));
             }
+            data.push(Data::String(module));
+            data.push(Data::String(class));
+            data.push(Data::String(action));
+            match &param {
+                Some(s) => data.push(Data::String(s.clone())),
+                None => data.push(Data::None),
+            };
+            match &lang_id {
+                Some(i) => data.push(Data::U64(*i as u64)),
+                None => data.push(Data::None),
+            };
+
+            list.push((key, Data::Vec(data.clone())));
         }
+        list
     }
 
 }
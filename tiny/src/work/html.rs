@@ -179,6 +179,17 @@ impl Html {
     }
 
     pub fn new(root: &str, log: Arc<Mutex<Log>>) -> Option<Html> {
+        Html::load(root, log, None, &mut Vec::new())
+    }
+
+    // Load the templates again. The template with an error keeps its old version, it's returned in the list of rejected.
+    pub fn reload(&self, root: &str, log: Arc<Mutex<Log>>) -> Option<(Html, Vec<String>)> {
+        let mut rejected = Vec::new();
+        Html::load(root, log, Some(self), &mut rejected).map(|h| (h, rejected))
+    }
+
+    // Without the old templates any error stops the loading
+    fn load(root: &str, log: Arc<Mutex<Log>>, old: Option<&Html>, rejected: &mut Vec<String>) -> Option<Html> {
         let path = format!("{}/app/", root);
         let list = match read_dir(path) {
             Ok(r) => {
@@ -211,6 +222,12 @@ impl Html {
                                                                                             let mut vec = Vec::new();
                                                                                             if let ConditionNode::End = Html::get_view(&str, &mut vec, Arc::clone(&log)) {
                                                                                                 l.insert(view.to_owned(), vec);
+                                                                                            } else if let Some(old) = old {
+                                                                                                Log::push_warning(Arc::clone(&log), 1101, Some(path.display().to_string()));
+                                                                                                rejected.push(format!("{}/{}/{}", module, class, view));
+                                                                                                if let Some(v) = old.get(module, class).and_then(|l| l.get(view)) {
+                                                                                                    l.insert(view.to_owned(), v.clone());
+                                                                                                }
                                                                                             } else {
                                                                                                 return None;
                                                                                             }
@@ -242,6 +259,10 @@ impl Html {
             },
             Err(e) => {
                 Log::push_warning(log, 1100, Some(e.to_string()));
+                // On reload the old templates are kept
+                if old.is_some() {
+                    return None;
+                }
                 HashMap::new()
             },
         };
@@ -0,0 +1,335 @@
+use std::{collections::HashMap, fmt, mem, str::FromStr};
+
+// Deeper keys like a[b][c][d]... are not split, the whole key is the name
+const PARAM_MAX_DEPTH: usize = 8;
+
+// Value of the query or form parameter
+#[derive(Debug, Clone, PartialEq)]
+pub enum Param {
+    Value(String),
+    List(Vec<Param>),                   // Repeated key: tag=a&tag=b or ids[]=1&ids[]=2
+    Map(HashMap<String, Param>),        // Nested key: user[name]=a&user[city]=b
+}
+
+impl Param {
+
+    // The value, or the first value of the list
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Param::Value(v) => Some(v),
+            Param::List(l) => l.first().and_then(|p| p.as_str()),
+            Param::Map(_) => None,
+        }
+    }
+
+    // The item of the nested map
+    pub fn get(&self, key: &str) -> Option<&Param> {
+        match self {
+            Param::Map(m) => m.get(key),
+            _ => None,
+        }
+    }
+
+    // All values of the list, the single value is the list of one
+    pub fn values(&self) -> Vec<&str> {
+        match self {
+            Param::Value(v) => vec![v],
+            Param::List(l) => l.iter().filter_map(|p| p.as_str()).collect(),
+            Param::Map(_) => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamError {
+    Missing(String),                    // Name of the parameter
+    Invalid(String, String),            // Name of the parameter and the parse error
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamError::Missing(name) => write!(f, "The parameter \"{}\" is missing", name),
+            ParamError::Invalid(name, e) => write!(f, "The parameter \"{}\" is invalid: {}", name, e),
+        }
+    }
+}
+
+// Decoded parameters of the query string or the form
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Params {
+    list: HashMap<String, Param>,
+}
+
+impl Params {
+
+    // Parse "a=1&b=%20x+y&ids[]=1&user[name]=a"
+    pub fn parse(text: &str) -> Params {
+        let mut params = Params::default();
+        for pair in text.split('&') {
+            if pair.is_empty() {
+                continue;
+            }
+            let (name, value) = match pair.split_once('=') {
+                Some((n, v)) => (n, v),
+                None => (pair, ""),
+            };
+            params.insert(&Params::decode(name, true), Params::decode(value, true));
+        }
+        params
+    }
+
+    // Add the decoded value, the brackets in the name make a list or a map
+    pub fn insert(&mut self, name: &str, value: String) {
+        let (key, path) = Params::split_name(name);
+        Params::put(&mut self.list, key, &path, value);
+    }
+
+    // Percent-decoding. In the form and the query string '+' is a space. The wrong UTF-8 is replaced with U+FFFD.
+    pub fn decode(text: &str, form: bool) -> String {
+        match String::from_utf8(Params::decode_bytes(text, form)) {
+            Ok(s) => s,
+            Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+        }
+    }
+
+    // Percent-decoding to the bytes, the charset is up to the caller
+    pub fn decode_bytes(text: &str, form: bool) -> Vec<u8> {
+        let bytes = text.as_bytes();
+        let mut buf = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                // The wrong escape is kept as it is
+                b'%' => match (bytes.get(i + 1).and_then(|b| Params::hex(*b)), bytes.get(i + 2).and_then(|b| Params::hex(*b))) {
+                    (Some(h), Some(l)) => {
+                        buf.push(h * 16 + l);
+                        i += 3;
+                        continue;
+                    },
+                    _ => buf.push(b'%'),
+                },
+                b'+' if form => buf.push(b' '),
+                b => buf.push(b),
+            };
+            i += 1;
+        }
+        buf
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Param)> {
+        self.list.iter()
+    }
+
+    pub fn param(&self, name: &str) -> Option<&Param> {
+        self.list.get(name)
+    }
+
+    // The value, or the first value of the repeated key
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.list.get(name).and_then(|p| p.as_str())
+    }
+
+    // All values of the repeated key
+    pub fn values(&self, name: &str) -> Vec<&str> {
+        match self.list.get(name) {
+            Some(p) => p.values(),
+            None => Vec::new(),
+        }
+    }
+
+    // The typed value: this.get::<u64>("page")
+    pub fn get<T: FromStr>(&self, name: &str) -> Result<T, ParamError> where T::Err: fmt::Display {
+        match self.value(name) {
+            Some(v) => v.parse::<T>().map_err(|e| ParamError::Invalid(name.to_owned(), e.to_string())),
+            None => Err(ParamError::Missing(name.to_owned())),
+        }
+    }
+
+    // All typed values of the repeated key, the missing key is the empty list
+    pub fn get_list<T: FromStr>(&self, name: &str) -> Result<Vec<T>, ParamError> where T::Err: fmt::Display {
+        self.values(name).into_iter().map(|v| v.parse::<T>().map_err(|e| ParamError::Invalid(name.to_owned(), e.to_string()))).collect()
+    }
+
+    // "a[b][]" is ("a", ["b", ""]). The name without the closed brackets is taken as it is.
+    fn split_name(name: &str) -> (&str, Vec<&str>) {
+        let start = match name.find('[') {
+            Some(i) if i > 0 => i,
+            _ => return (name, Vec::new()),
+        };
+        let mut path = Vec::new();
+        let mut rest = &name[start..];
+        while let Some(r) = rest.strip_prefix('[') {
+            match r.find(']') {
+                Some(end) => {
+                    path.push(&r[..end]);
+                    rest = &r[end + 1..];
+                },
+                None => return (name, Vec::new()),
+            }
+        }
+        if !rest.is_empty() || path.len() > PARAM_MAX_DEPTH {
+            return (name, Vec::new());
+        }
+        (&name[..start], path)
+    }
+
+    fn put(list: &mut HashMap<String, Param>, key: &str, path: &[&str], value: String) {
+        match path.split_first() {
+            // The repeated key turns into the list
+            None => match list.get_mut(key) {
+                Some(Param::List(l)) => l.push(Param::Value(value)),
+                Some(p) => {
+                    let old = mem::replace(p, Param::Value(String::new()));
+                    *p = Param::List(vec![old, Param::Value(value)]);
+                },
+                None => {
+                    list.insert(key.to_owned(), Param::Value(value));
+                },
+            },
+            // key[] adds the item to the list
+            Some((&"", rest)) => {
+                let item = if rest.is_empty() {
+                    Param::Value(value)
+                } else {
+                    let mut map = HashMap::new();
+                    Params::put(&mut map, rest[0], &rest[1..], value);
+                    Param::Map(map)
+                };
+                match list.get_mut(key) {
+                    Some(Param::List(l)) => l.push(item),
+                    Some(p) => {
+                        let old = mem::replace(p, Param::Value(String::new()));
+                        *p = Param::List(vec![old, item]);
+                    },
+                    None => {
+                        list.insert(key.to_owned(), Param::List(vec![item]));
+                    },
+                }
+            },
+            // key[name] is the item of the map, the value with the same key is replaced by the map
+            Some((next, rest)) => {
+                let entry = list.entry(key.to_owned()).or_insert_with(|| Param::Map(HashMap::new()));
+                if !matches!(entry, Param::Map(_)) {
+                    *entry = Param::Map(HashMap::new());
+                }
+                if let Param::Map(map) = entry {
+                    Params::put(map, next, rest, value);
+                }
+            },
+        }
+    }
+
+    fn hex(b: u8) -> Option<u8> {
+        match b {
+            b'0'..=b'9' => Some(b - b'0'),
+            b'a'..=b'f' => Some(b - b'a' + 10),
+            b'A'..=b'F' => Some(b - b'A' + 10),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{Param, ParamError, Params};
+
+    fn value(v: &str) -> Param {
+        Param::Value(v.to_owned())
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(Params::decode("a+b%20c%2Bd", true), "a b c+d");
+        assert_eq!(Params::decode("a+b", false), "a+b");
+        assert_eq!(Params::decode("%D1%84%d1%84", true), "фф");
+        // The wrong escapes are kept, the wrong UTF-8 is replaced
+        assert_eq!(Params::decode("100%", true), "100%");
+        assert_eq!(Params::decode("%zz%4", true), "%zz%4");
+        assert_eq!(Params::decode("a%FFb", true), "a\u{FFFD}b");
+        assert_eq!(Params::decode_bytes("a%FFb", true), b"a\xFFb");
+    }
+
+    #[test]
+    fn split_name() {
+        assert_eq!(Params::split_name("page"), ("page", vec![]));
+        assert_eq!(Params::split_name("ids[]"), ("ids", vec![""]));
+        assert_eq!(Params::split_name("user[name]"), ("user", vec!["name"]));
+        assert_eq!(Params::split_name("a[b][][c]"), ("a", vec!["b", "", "c"]));
+        // The name is taken as it is
+        assert_eq!(Params::split_name("[a]"), ("[a]", vec![]));
+        assert_eq!(Params::split_name("a[b"), ("a[b", vec![]));
+        assert_eq!(Params::split_name("a[b]c"), ("a[b]c", vec![]));
+        assert_eq!(Params::split_name("a]b"), ("a]b", vec![]));
+        let deep = format!("a{}", "[x]".repeat(8));
+        assert_eq!(Params::split_name(&deep).1.len(), 8);
+        let deeper = format!("a{}", "[x]".repeat(9));
+        assert_eq!(Params::split_name(&deeper), (deeper.as_str(), vec![]));
+    }
+
+    #[test]
+    fn parse() {
+        let params = Params::parse("page=2&q=a+b%26c&&flag&empty=&tag=x&tag=y&tag=z&ids[]=1&ids[]=2&user[name]=Ann&user[city]=Kyiv&a%5Bb%5D=1");
+        assert_eq!(params.value("page"), Some("2"));
+        assert_eq!(params.value("q"), Some("a b&c"));
+        assert_eq!(params.value("flag"), Some(""));
+        assert_eq!(params.value("empty"), Some(""));
+        assert_eq!(params.values("tag"), vec!["x", "y", "z"]);
+        assert_eq!(params.value("tag"), Some("x"));
+        assert_eq!(params.values("ids"), vec!["1", "2"]);
+        assert_eq!(params.values("page"), vec!["2"]);
+        assert_eq!(params.values("missing"), Vec::<&str>::new());
+        let user = params.param("user").unwrap();
+        assert_eq!(user.get("name").and_then(|p| p.as_str()), Some("Ann"));
+        assert_eq!(user.get("city").and_then(|p| p.as_str()), Some("Kyiv"));
+        assert_eq!(user.as_str(), None);
+        // The encoded brackets are the brackets too
+        assert_eq!(params.param("a").and_then(|p| p.get("b")), Some(&value("1")));
+        assert_eq!(params.len(), 8);
+        assert!(Params::parse("").is_empty());
+        assert!(Params::parse("&&").is_empty());
+    }
+
+    #[test]
+    fn put() {
+        let params = Params::parse("list[][id]=1&list[][id]=2&m[a][b]=1&m[a][c]=2");
+        let list = match params.param("list") {
+            Some(Param::List(l)) => l,
+            p => panic!("{:?}", p),
+        };
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[1].get("id"), Some(&value("2")));
+        let mut map = HashMap::new();
+        map.insert("b".to_owned(), value("1"));
+        map.insert("c".to_owned(), value("2"));
+        assert_eq!(params.param("m").and_then(|p| p.get("a")), Some(&Param::Map(map)));
+
+        // The value and the list are joined, the map replaces the value
+        let params = Params::parse("x=1&x[]=2&y=1&y[k]=2");
+        assert_eq!(params.values("x"), vec!["1", "2"]);
+        assert_eq!(params.param("y").and_then(|p| p.get("k")), Some(&value("2")));
+        assert_eq!(params.value("y"), None);
+    }
+
+    #[test]
+    fn get() {
+        let params = Params::parse("page=2&ids=1&ids=2&bad=x&list=1&list=x");
+        assert_eq!(params.get::<u64>("page"), Ok(2));
+        assert_eq!(params.get::<u64>("none"), Err(ParamError::Missing("none".to_owned())));
+        assert!(matches!(params.get::<u64>("bad"), Err(ParamError::Invalid(name, _)) if name == "bad"));
+        assert_eq!(params.get_list::<u8>("ids"), Ok(vec![1, 2]));
+        assert_eq!(params.get_list::<u8>("none"), Ok(vec![]));
+        assert!(params.get_list::<u8>("list").is_err());
+        assert_eq!(ParamError::Missing("page".to_owned()).to_string(), "The parameter \"page\" is missing");
+    }
+}
@@ -0,0 +1,193 @@
+use std::{io::{self, ErrorKind, Write}, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread, time::{Duration, Instant}};
+
+use super::db::CancelToken;
+
+// The worker is replaced, if it hasn't finished the expired request after this time
+pub const WATCH_REPLACE_DELAY: Duration = Duration::from_secs(5);
+
+// The request that is processed by the controller now
+struct Job {
+    start: Instant,
+    timeout: Option<Box<dyn FnOnce() + Send>>,     // Answers 504 to the client, None - the request has expired
+    cancel: Option<CancelToken>,                    // Cancels the running database query
+    abort: Arc<AtomicBool>,                         // Queries of the expired request are not executed
+}
+
+struct State {
+    job: Option<Job>,
+    abandoned: bool,                                // The stuck worker is replaced, so it stops after the request
+}
+
+pub enum WatchStatus {
+    Idle,
+    Busy,
+    Expired(Option<CancelToken>),                   // The deadline has passed now, 504 is sent
+    Stuck,                                          // The expired request is still running, the worker must be replaced
+}
+
+// Deadline of the request for the watchdog
+pub struct Watch {
+    timeout: Duration,                              // 0 - the request is unlimited
+    state: Mutex<State>,
+}
+
+impl Watch {
+
+    pub fn new(timeout: Duration) -> Watch {
+        Watch {
+            timeout,
+            state: Mutex::new(State { job: None, abandoned: false }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.timeout.is_zero()
+    }
+
+    // The controller starts the request
+    pub fn start(&self, timeout: Box<dyn FnOnce() + Send>, cancel: Option<CancelToken>, abort: Arc<AtomicBool>) {
+        if !self.is_enabled() {
+            return;
+        }
+        if let Ok(mut state) = Mutex::lock(&self.state) {
+            state.job = Some(Job {
+                start: Instant::now(),
+                timeout: Some(timeout),
+                cancel,
+                abort,
+            });
+        }
+    }
+
+    // The controller has finished the request. Returns true, when 504 has already been sent instead of the answer.
+    pub fn finish(&self) -> bool {
+        match Mutex::lock(&self.state) {
+            Ok(mut state) => match state.job.take() {
+                Some(job) => job.timeout.is_none(),
+                None => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    pub fn is_abandoned(&self) -> bool {
+        match Mutex::lock(&self.state) {
+            Ok(state) => state.abandoned,
+            Err(_) => true,
+        }
+    }
+
+    // Check the deadline of the running request
+    pub fn check(&self) -> WatchStatus {
+        let (timeout, cancel) = {
+            let mut state = match Mutex::lock(&self.state) {
+                Ok(s) => s,
+                Err(_) => return WatchStatus::Idle,
+            };
+            let abandoned = state.abandoned;
+            let job = match state.job.as_mut() {
+                Some(j) => j,
+                None => return WatchStatus::Idle,
+            };
+            let elapsed = job.start.elapsed();
+            if job.timeout.is_some() {
+                if elapsed < self.timeout {
+                    return WatchStatus::Busy;
+                }
+                job.abort.store(true, Ordering::Release);
+                (job.timeout.take(), job.cancel.take())
+            } else {
+                if abandoned || elapsed < self.timeout + WATCH_REPLACE_DELAY {
+                    return WatchStatus::Busy;
+                }
+                state.abandoned = true;
+                return WatchStatus::Stuck;
+            }
+        };
+        // The answer waits for the controller's write in progress, so it's sent from its own thread, not the dispatcher
+        if let Some(f) = timeout {
+            thread::spawn(f);
+        }
+        WatchStatus::Expired(cancel)
+    }
+}
+
+// Connection, which the controller, the reader and the watchdog write to. Every write goes out whole under the lock,
+// so the answer of the watchdog never gets into the middle of the controller's data.
+pub struct Output<W: Write> {
+    state: Arc<Mutex<OutputState<W>>>,
+    controller: bool,                               // Writer of the controller, it fails after the watchdog has answered
+}
+
+struct OutputState<W> {
+    tcp: W,
+    request: u64,                                   // Number of the request, which the controller runs now
+    expired: bool,                                  // The watchdog has answered the request instead of the controller
+    written: bool,                                  // The controller has streamed a part of the answer
+}
+
+impl<W: Write> Clone for Output<W> {
+    fn clone(&self) -> Self {
+        Output { state: Arc::clone(&self.state), controller: self.controller }
+    }
+}
+
+impl<W: Write> Output<W> {
+
+    pub fn new(tcp: W) -> Output<W> {
+        Output {
+            state: Arc::new(Mutex::new(OutputState { tcp, request: 0, expired: false, written: false })),
+            controller: false,
+        }
+    }
+
+    // The controller starts the next request, the number is given to the watchdog
+    pub fn start(&self) -> (u64, Output<W>) {
+        let request = match Mutex::lock(&self.state) {
+            Ok(mut state) => {
+                state.request += 1;
+                state.expired = false;
+                state.written = false;
+                state.request
+            },
+            Err(_) => 0,
+        };
+        (request, Output { state: Arc::clone(&self.state), controller: true })
+    }
+
+    // The watchdog answers instead of the controller, f gets true, when a part of the answer has already been sent.
+    // The late call for the finished request does nothing.
+    pub fn expire(&self, request: u64, f: impl FnOnce(&mut W, bool)) {
+        if let Ok(mut state) = Mutex::lock(&self.state) {
+            if state.request == request && !state.expired {
+                state.expired = true;
+                let written = state.written;
+                f(&mut state.tcp, written);
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = match Mutex::lock(&self.state) {
+            Ok(s) => s,
+            Err(e) => return Err(io::Error::other(e.to_string())),
+        };
+        if self.controller {
+            if state.expired {
+                return Err(io::Error::from(ErrorKind::TimedOut));
+            }
+            state.written = true;
+        }
+        state.tcp.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Mutex::lock(&self.state) {
+            Ok(mut state) => state.tcp.flush(),
+            Err(e) => Err(io::Error::other(e.to_string())),
+        }
+    }
+}
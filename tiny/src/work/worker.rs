@@ -1,50 +1,95 @@
-use std::{thread::{self, JoinHandle}, net::TcpStream, sync::{Arc, Mutex, mpsc::{Receiver, Sender}, RwLock}, any::Any, collections::HashMap, fs::remove_file};
+use std::{thread::{self, JoinHandle}, net::TcpStream, sync::{Arc, Mutex, mpsc::{Receiver, Sender}, RwLock, atomic::{AtomicBool, AtomicU64, Ordering}}, any::Any, collections::HashMap, io::Write, panic::{self, AssertUnwindSafe}, cell::RefCell, time::{SystemTime, UNIX_EPOCH}};
 
 use chrono::{Utc, Duration};
 
-use crate::sys::{log::Log, fastcgi::FastCGI};
+use crate::sys::{log::Log, fastcgi::FastCGI, http::Http, socket::Socket};
 
-use super::{cache::Cache, action::{DataRun, Action, Answer, ActMap}, db::{DB, DBConfig}, html::Html, lang::Lang};
+use super::{watch::Watch, cache::Cache, action::{DataRun, Action, Answer, ActMap, Body, Upload, View}, db::{DB, DBConfig}, html::Html, lang::Lang, asset::Asset};
 
 pub enum MessageWork {
     Terminate,
-    Job(TcpStream),
+    Job(Socket),
+    Http(TcpStream),
 }
 
 const ON_YEAR: i64 = 31622400;
 
+// Number of the requests, it makes the request id unique
+static REQUEST_COUNT: AtomicU64 = AtomicU64::new(0);
+// The longer X-Request-Id of the client is replaced with the generated one
+const REQUEST_ID_MAX_LEN: usize = 128;
+
+thread_local! {
+    // The controller runs in catch_unwind, so the panic message is kept for the log of the request
+    static PANIC: RefCell<Option<Option<String>>> = const { RefCell::new(None) };
+}
+
 pub struct Worker {
     thread: JoinHandle<()>,
 }
 
+// Settings of the controllers, they are the same for all workers
+#[derive(Clone)]
+pub struct WorkerConf {
+    pub cache: Arc<Mutex<Cache>>,
+    pub html: Arc<RwLock<Arc<Html>>>,
+    pub lang: Arc<RwLock<Arc<Lang>>>,
+    pub log: Arc<Mutex<Log>>,
+    pub salt: String,
+    pub path: String,
+    pub db: DBConfig,
+    pub timezone: String,
+    pub lang_id: u64,
+    pub engine: ActMap,
+    pub max_body: usize,
+    pub spool_body: usize,
+    pub upload: Upload,
+}
+
+impl WorkerConf {
+    // Own database connection of the worker
+    pub fn connect(&self, abort: Arc<AtomicBool>) -> DB {
+        DB::new(self.db.clone(), Arc::clone(&self.log), self.timezone.clone(), Arc::clone(&self.cache), abort)
+    }
+
+    // Database connection of the controller in the async mode, it's opened by the task
+    #[cfg(feature = "async")]
+    pub async fn open(&self, abort: Arc<AtomicBool>) -> DB {
+        DB::open(self.db.clone(), Arc::clone(&self.log), self.timezone.clone(), Arc::clone(&self.cache), abort).await
+    }
+
+    // Data of the controller for the connection
+    pub fn data<'a>(&'a self, db: &'a mut DB, abort: &Arc<AtomicBool>, watch: &Arc<Watch>) -> DataRun<'a> {
+        DataRun {
+            cache: Arc::clone(&self.cache),
+            html: Arc::clone(&self.html),
+            lang: Arc::clone(&self.lang),
+            salt: &self.salt,
+            lang_id: self.lang_id,
+            path: &self.path,
+            db,
+            engine: &self.engine,
+            abort: Arc::clone(abort),
+            max_body: self.max_body,
+            spool_body: self.spool_body,
+            upload: self.upload,
+            watch: Arc::clone(watch),
+        }
+    }
+}
+
 impl Worker {
-    pub fn new(
-        id: u8, 
-        receiver: Receiver<MessageWork>, 
-        sender_ready: Arc<Mutex<Sender<u8>>>, 
-        cache: Arc<Mutex<Cache>>, 
-        html: Arc<RwLock<Html>>, 
-        lang: Arc<RwLock<Lang>>, 
-        log: Arc<Mutex<Log>>, 
-        salt: String, 
-        path: String, 
-        db: DBConfig, 
-        timezone: String, 
-        lang_id: u64, 
-        engine: ActMap
-    ) -> Worker {
+    pub fn new(id: u8, receiver: Receiver<MessageWork>, sender_ready: Arc<Mutex<Sender<u8>>>, conf: WorkerConf, watch: Arc<Watch>) -> Worker {
+        let log = Arc::clone(&conf.log);
         let tlog = Arc::clone(&log);
         let thread = thread::spawn(move || {
-            let mut db = DB::new(db.clone(), Arc::clone(&log), timezone.clone(), Arc::clone(&cache));
-            let html = match RwLock::read(&html) {
-                Ok(h) => h,
-                Err(e) => Log::error(log, e.to_string()),
-            };
-            let lang = match RwLock::read(&lang) {
-                Ok(h) => h,
-                Err(e) => Log::error(log, e.to_string()),
-            };
+            let abort = Arc::new(AtomicBool::new(false));
+            let mut db = conf.connect(Arc::clone(&abort));
             loop {
+                // The stuck worker has been replaced, so it stops after the request
+                if watch.is_abandoned() {
+                    break;
+                }
                 match Mutex::lock(&sender_ready) {
                     Ok(s) => if let Err(e) = s.send(id) {
                         Log::push_error(log, 703, Some(e.to_string()));
@@ -54,18 +99,13 @@ impl Worker {
                 match receiver.recv() {
                     Ok(e) => match e {
                         MessageWork::Terminate => break,
-                        MessageWork::Job(tcp) => {
-                            let data = DataRun {
-                                cache: Arc::clone(&cache),
-                                html: &html,
-                                lang: &lang,
-                                salt: &salt,
-                                lang_id,
-                                path: &path,
-                                db: &mut db,
-                                engine: &engine,
-                            };
-                            FastCGI::run(&Worker::run, tcp, data, Arc::clone(&log))
+                        job => {
+                            let data = conf.data(&mut db, &abort, &watch);
+                            match job {
+                                MessageWork::Job(tcp) => FastCGI::run(&Worker::run, tcp, data, Arc::clone(&log)),
+                                MessageWork::Http(tcp) => Http::run(&Worker::run_http, tcp, data, Arc::clone(&log)),
+                                MessageWork::Terminate => {},
+                            }
                         },
                     },
                     Err(e) => Log::push_error(tlog, 700, Some(e.to_string())),
@@ -78,17 +118,149 @@ impl Worker {
         }
     }
 
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
     pub fn join(self) -> Result<(), Box<dyn Any + Send>> {
         self.thread.join()
     }
 
-    fn run(param: HashMap<String, String>, stdin: Option<Vec<u8>>, data: DataRun, log: Arc<Mutex<Log>>) -> Vec<u8> {
-        let mut action = Action::new(&param, &stdin, data, Arc::clone(&log));
-        let mut result = match action.run() {
-            Answer::Raw(answer) => answer,
-            Answer::String(answer) => answer.into_bytes(),
-            Answer::None => Vec::new(),
+    pub fn run(param: HashMap<String, String>, stdin: Body, data: &mut DataRun, stream: &mut dyn Write, log: Arc<Mutex<Log>>) -> Vec<u8> {
+        Worker::start(param, stdin, data, stream, false, log)
+    }
+
+    // The built-in HTTP server has no web server before it, so it answers with the static files too
+    pub fn run_http(param: HashMap<String, String>, stdin: Body, data: &mut DataRun, stream: &mut dyn Write, log: Arc<Mutex<Log>>) -> Vec<u8> {
+        Worker::start(param, stdin, data, stream, true, log)
+    }
+
+    fn start(param: HashMap<String, String>, stdin: Body, data: &mut DataRun, stream: &mut dyn Write, http: bool, log: Arc<Mutex<Log>>) -> Vec<u8> {
+        // All log messages of the request and its database connection get the id
+        let id = match param.get("HTTP_X_REQUEST_ID") {
+            Some(id) if Worker::is_request_id(id) => id.to_owned(),
+            _ => Worker::request_id(),
+        };
+        Log::set_request(Some(id.clone()));
+        let asset = if http { Asset::run(&param, data.path, &id, stream, Arc::clone(&log)) } else { None };
+        let answer = match asset {
+            Some(answer) => answer,
+            None => {
+                data.db.set_request(&id);
+                Worker::serve(param, stdin, data, stream, id, Arc::clone(&log))
+            },
+        };
+        Log::set_request(None);
+        answer
+    }
+
+    fn serve(param: HashMap<String, String>, stdin: Body, data: &mut DataRun, stream: &mut dyn Write, id: String, log: Arc<Mutex<Log>>) -> Vec<u8> {
+        // Templates and translations are taken for the request, so the reload doesn't wait for it
+        let html = match RwLock::read(&data.html) {
+            Ok(h) => Arc::clone(&h),
+            Err(e) => Log::error(log, e.to_string()),
+        };
+        let lang = match RwLock::read(&data.lang) {
+            Ok(l) => Arc::clone(&l),
+            Err(e) => Log::error(log, e.to_string()),
         };
+        // The panic in the controller turns into 500, so the server and the worker keep running
+        let mut action = match Worker::catch(|| Action::new(&param, &stdin, data, View { html: &html, lang: &lang }, stream, id.clone(), Arc::clone(&log))) {
+            Ok(action) => action,
+            Err(e) => return Worker::panic(e, false, &id, Arc::clone(&log)),
+        };
+        let result = Worker::catch(|| action.run());
+
+        // The files are removed with their paths, even if the controller has panicked.
+        // When Action::new panics, the paths are dropped on unwinding and remove the files without the log.
+        if let Some(list) = action.request.input.file.take() {
+            for (_, val) in list {
+                for f in val {
+                    let name = f.tmp.display().to_string();
+                    if let Err(e) = f.tmp.close() {
+                        Log::push_warning(Arc::clone(&log), 1020, Some(format!("filename={}. Error={}", name, e)));
+                    };
+                }
+            }
+        }
+        let mut result = match result {
+            Ok(Answer::Raw(answer)) => answer,
+            Ok(Answer::String(answer)) => answer.into_bytes(),
+            Ok(Answer::None) => Vec::new(),
+            Ok(Answer::Json(answer)) => {
+                if action.response.content_type.is_none() {
+                    action.response.set_content_type("application/json; charset=utf-8");
+                }
+                answer.to_string().into_bytes()
+            },
+            // The session of the failed request is not saved
+            Err(e) => return Worker::panic(e, action.is_streamed(), &action.request.id, log),
+        };
+        // The header of the streamed answer has already been sent
+        let answer = if action.is_streamed() {
+            result
+        } else {
+            let mut answer = Worker::header(&action, Some(result.len()));
+            answer.append(&mut result);
+            answer
+        };
+        action.stop(); 
+        answer
+    }
+
+    // Log the panic messages of the controllers instead of stderr
+    pub fn set_panic_hook(log: Arc<Mutex<Log>>) {
+        panic::set_hook(Box::new(move |info| {
+            let caught = PANIC.with(|p| match p.borrow_mut().as_mut() {
+                Some(message) => {
+                    *message = Some(info.to_string().replace('\n', " "));
+                    true
+                },
+                None => false,
+            });
+            if !caught {
+                Log::push_warning(Arc::clone(&log), 1021, Some(info.to_string().replace('\n', " ")));
+            }
+        }));
+    }
+
+    // Run the controller code, Err contains the panic message
+    fn catch<T>(f: impl FnOnce() -> T) -> Result<T, Option<String>> {
+        PANIC.with(|p| *p.borrow_mut() = Some(None));
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        let message = PANIC.with(|p| p.borrow_mut().take()).flatten();
+        result.map_err(|_| message)
+    }
+
+    // Answer 500 to the request, which controller has panicked
+    fn panic(message: Option<String>, streamed: bool, id: &str, log: Arc<Mutex<Log>>) -> Vec<u8> {
+        Log::push_warning(log, 1022, message);
+        // The header of the streamed answer has already been sent, so the answer is just cut
+        if streamed {
+            return Vec::new();
+        }
+        let body = format!("Internal Server Error. Request id: {}", id);
+        format!("HTTP/1.1 {}\r\nX-Request-Id: {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}", Action::http_code_get(500), id, body.len(), body).into_bytes()
+    }
+
+    // Unique id of the request, when the client hasn't sent X-Request-Id
+    fn request_id() -> String {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        format!("{:08x}{:06x}", time, REQUEST_COUNT.fetch_add(1, Ordering::Relaxed) & 0xffffff)
+    }
+
+    // The id of the client goes to the log, the header and application_name, so only the printable ASCII is taken
+    fn is_request_id(id: &str) -> bool {
+        !id.is_empty() && id.len() <= REQUEST_ID_MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+    }
+
+    // The line break in the value would start the next header
+    fn is_header_value(value: &str) -> bool {
+        !value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0)
+    }
+
+    // HTTP header of the answer. The length of the streamed answer is unknown.
+    pub fn header(action: &Action, len: Option<usize>) -> Vec<u8> {
         let mut answer: Vec<String> = Vec::with_capacity(16);
         answer.push("HTTP/1.1 ".to_owned());
         if let Some(redirect) = action.response.redirect.as_ref() {
@@ -111,23 +283,30 @@ impl Worker {
             ""
         };
         answer.push(format!("Set-Cookie: {}={}; Expires={}; Max-Age={}; path=/; domain={}; {}SameSite=none\r\n", action.session.key, action.session.session, date, ON_YEAR, action.request.host, secure));
-        answer.push("Connection: keep-alive\r\n".to_owned());
-        answer.push("Content-Type: text/html; charset=utf-8\r\n".to_owned());
-        answer.push(format!("Content-Length: {}\r\n", result.len()));
-        answer.push("\r\n".to_owned());
-        let mut answer = answer.join("").into_bytes();
-        answer.append(&mut result);
-
-        if let Some(list) = &action.request.input.file {
-            for (_, val) in list {
-                for f in val {
-                    if let Err(e) = remove_file(&f.tmp) {
-                        Log::push_warning(Arc::clone(&log), 1020, Some(format!("filename={}. Error={}", &f.tmp.display(), e.to_string())));
-                    };
-                }
+        for cookie in &action.response.cookies {
+            match cookie.to_header() {
+                Some(header) => answer.push(format!("Set-Cookie: {}\r\n", header)),
+                None => Log::push_warning(Arc::clone(&action.log), 1028, Some(format!("{:?}", cookie.name))),
+            };
+        }
+        answer.push(format!("X-Request-Id: {}\r\n", action.request.id));
+        match &action.response.content_type {
+            Some(content_type) if Worker::is_header_value(content_type) => answer.push(format!("Content-Type: {}\r\n", content_type)),
+            _ => answer.push("Content-Type: text/html; charset=utf-8\r\n".to_owned()),
+        };
+        for (name, value) in &action.response.headers {
+            // The length and the request id are set by the server, the connection by its transport
+            if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Connection") || name.eq_ignore_ascii_case("Content-Type") || name.eq_ignore_ascii_case("X-Request-Id") {
+                continue;
+            }
+            if !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && b != b':') && Worker::is_header_value(value) {
+                answer.push(format!("{}: {}\r\n", name, value));
             }
         }
-        action.stop(); 
-        answer
+        if let Some(len) = len {
+            answer.push(format!("Content-Length: {}\r\n", len));
+        }
+        answer.push("\r\n".to_owned());
+        answer.join("").into_bytes()
     }
-}
\ No newline at end of file
+}